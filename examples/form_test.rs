@@ -0,0 +1,84 @@
+#[macro_use] extern crate log;
+
+extern crate env_logger;
+extern crate futures;
+extern crate hyper;
+extern crate hyper_util;
+extern crate multipart_async as multipart;
+extern crate tokio;
+
+use futures::future;
+use futures::TryStreamExt;
+
+use hyper::server::conn::http1;
+use hyper::{Response, StatusCode};
+
+use hyper_util::rt::TokioIo;
+
+use multipart::server::{IncomingBody, LimitExceeded, Limits, MinusBody, Multipart, MultipartService};
+
+use std::convert::Infallible;
+use std::io;
+use std::net::SocketAddr;
+
+use tokio::net::TcpListener;
+use tokio::task::{self, LocalSet};
+
+const FORM: &str = include_str!("test_form.html");
+
+// `Multipart` is not `Send` unless the `use_arc` feature is enabled,
+// so connections are served on a single-threaded `LocalSet`
+#[tokio::main(flavor = "current_thread")]
+async fn main() {
+    env_logger::init().unwrap();
+
+    let addr: SocketAddr = "127.0.0.1:8080".parse().expect("invalid socket address");
+    let listener = TcpListener::bind(addr).await.expect("failed to bind socket");
+
+    LocalSet::new().run_until(async move {
+        loop {
+            let (stream, _) = listener.accept().await.expect("error accepting connection");
+
+            let service = MultipartService {
+                multipart: |multi| async move {
+                    Ok::<_, Infallible>(match handle_multipart(multi).await {
+                        Ok(()) => Response::new("success".to_string()),
+                        Err(e) => {
+                            let mut resp = Response::new(e.to_string());
+
+                            if LimitExceeded::from_io_error(&e).is_some() {
+                                *resp.status_mut() = StatusCode::PAYLOAD_TOO_LARGE;
+                            }
+
+                            resp
+                        },
+                    })
+                },
+                normal: |_| future::ok::<_, Infallible>(Response::new(FORM.to_string())),
+            };
+
+            task::spawn_local(async move {
+                if let Err(e) = http1::Builder::new()
+                    .serve_connection(TokioIo::new(stream), service).await {
+                    error!("error serving connection: {}", e);
+                }
+            });
+        }
+    }).await
+}
+
+async fn handle_multipart((mut multi, _rest): (Multipart<IncomingBody>, MinusBody)) -> io::Result<()> {
+    multi.set_limits(Limits::default().fields(16).total_size(64 * 1024 * 1024));
+
+    while let Some(field) = multi.try_next().await? {
+        if field.headers.is_text() {
+            let field = field.data.read_text().await?;
+            info!("got text field: {:?}", field);
+        } else {
+            info!("got file field: {:?}", field.headers);
+            field.data.try_for_each(|_| future::ok(())).await?;
+        }
+    }
+
+    Ok(())
+}
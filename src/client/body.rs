@@ -0,0 +1,109 @@
+// Copyright 2017 `multipart-async` Crate Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+//! The data sources for client-side fields.
+use bytes::Bytes;
+
+use futures::io::AsyncRead;
+
+use std::fs::File;
+use std::io::{self, Read};
+
+use crate::helpers::*;
+
+const READ_BUF_LEN: usize = 64 * 1024;
+
+pub type BoxStream<'a> = Pin<Box<dyn Stream<Item = io::Result<Bytes>> + Send + 'a>>;
+
+pub enum FieldBody<'a> {
+    Text(Option<Bytes>),
+    File(FileStream),
+    Stream(BoxStream<'a>),
+}
+
+impl FieldBody<'_> {
+    pub fn poll_chunk(&mut self, cx: &mut Context) -> PollOpt<Bytes, io::Error> {
+        match *self {
+            FieldBody::Text(ref mut text) => Poll::Ready(text.take().map(Ok)),
+            FieldBody::File(ref mut file) => Poll::Ready(file.read_chunk().transpose()),
+            FieldBody::Stream(ref mut stream) => stream.as_mut().poll_next(cx),
+        }
+    }
+}
+
+/// Reads a file with blocking I/O.
+pub struct FileStream {
+    file: File,
+}
+
+impl FileStream {
+    pub fn new(file: File) -> Self {
+        FileStream { file }
+    }
+
+    fn read_chunk(&mut self) -> io::Result<Option<Bytes>> {
+        let mut buf = vec![0u8; READ_BUF_LEN];
+
+        let read = loop {
+            match self.file.read(&mut buf) {
+                Ok(read) => break read,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        };
+
+        if read == 0 {
+            return Ok(None);
+        }
+
+        buf.truncate(read);
+        Ok(Some(buf.into()))
+    }
+}
+
+/// Adapts an `AsyncRead` into a stream of chunks.
+pub struct ReadStream<R> {
+    read: Pin<Box<R>>,
+    buf: Vec<u8>,
+    done: bool,
+}
+
+impl<R: AsyncRead> ReadStream<R> {
+    pub fn new(read: R) -> Self {
+        ReadStream { read: Box::pin(read), buf: Vec::new(), done: false }
+    }
+}
+
+impl<R: AsyncRead> Stream for ReadStream<R> {
+    type Item = io::Result<Bytes>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+
+        if this.done {
+            return Poll::Ready(None);
+        }
+
+        // keep the buffer around if the read isn't ready
+        if this.buf.is_empty() {
+            this.buf = vec![0u8; READ_BUF_LEN];
+        }
+
+        let read = match ready!(this.read.as_mut().poll_read(cx, &mut this.buf)) {
+            Ok(read) => read,
+            Err(e) => return Poll::Ready(Some(Err(e))),
+        };
+
+        if read == 0 {
+            this.done = true;
+            return Poll::Ready(None);
+        }
+
+        let mut chunk = replace_default(&mut this.buf);
+        chunk.truncate(read);
+        ready_some(chunk.into())
+    }
+}
@@ -8,456 +8,334 @@
 //! default).
 //!
 //! Use this when sending POST requests with files to a server.
+//!
+//! See the `Multipart` struct for more info.
+use bytes::Bytes;
+
+use futures::io::AsyncRead;
+
 use mime::Mime;
-use buf_redux::{self, BufReader, copy_buf};
 
-use std::borrow::Cow;
-use std::fmt::Write as FmtWrite;
+use std::collections::VecDeque;
+use std::fmt::Write;
 use std::fs::File;
-use std::io;
-use std::io::Cursor;
-use std::io::prelude::*;
-
 use std::path::Path;
+use std::{fmt, io};
 
-use self::RequestStatus::*;
-
-#[cfg(feature = "hyper")]
-pub mod hyper;
-
-#[macro_export]
-macro_rules! field (
-    ($name:expr => text($val:expr)) => (
-        $crate::client::TextField::new($name, $val)
-    );
-    ($name:expr => file($val:expr)) => (
-        $crate::client::StreamField::open_file($name, $val)
-            .expect("Could not open file for reading")
-    );
-    ($name:expr => stream($val:expr)) => (
-        $crate::client::StreamField::buffer(
-            $name, $val, None, None
-        )
-    );
-    ($name:expr => stream($val:expr, $content_type:expr)) => (
-        $crate::client::StreamField::buffer(
-            $name, $val, Some(&$content_type), None
-        )
-    );
-    ($name:expr => buffered($val:expr)) => (
-        $crate::client::StreamField::new(
-            $name, $val, None, None
-        )
-    );
-    ($name:expr => buffered($val:expr, $cont_type:expr)) => (
-        $crate::client::StreamField::new(
-            $name, $val, Some(&$cont_type), None
-        )
-    );
-);
-
-pub mod chained;
-
-pub mod dyn;
-
-//pub mod lazy;
-
-//mod sized;
-
-//pub use self::sized::SizedRequest;
-
-const BOUNDARY_LEN: usize = 16;
+use crate::helpers::*;
 
+mod body;
 
-macro_rules! wrap_const {
-    ($($name:ident = $val:expr),+,) => (
-        $(wrap_const! { $name = $val })+
-    );
-    ($name:ident = $val:expr) => (
-        struct $name;
+use self::body::{FieldBody, FileStream, ReadStream};
 
-        impl AsRef<[u8]> for $name {
-            fn as_ref(&self) -> &[u8] {
-                $val.as_bytes()
-            }
-        }
-    )
-}
+const BOUNDARY_LEN: usize = 16;
 
 /// The entry point of the client-side multipart API.
 ///
-/// Though they perform I/O, the `.write_*()` methods do not return `io::Result<_>` in order to
-/// facilitate method chaining. Upon the first error, all subsequent API calls will be no-ops until
-/// `.send()` is called, at which point the error will be reported.
-pub struct Multipart<B> {
-    boundary: Boundary,
-    end: Cursor<End>,
-    state: BodyState,
-    body: B,
+/// Fields are added with the `add_*()` methods, then the request body is produced by the
+/// `Stream` implementation, which yields the encoded request in chunks as it is polled. Field
+/// data is only read when the next chunk is requested, so the request is never buffered
+/// in its entirety and a slow connection naturally applies backpressure to the sources.
+///
+/// Set the `Content-Type` header of the request to the value of `content_type()`:
+///
+/// ```rust,ignore
+/// let mut multipart = Multipart::new();
+/// multipart.add_text("name", "value")
+///     .add_file("upload", "/path/to/file.txt")?;
+///
+/// let content_type = multipart.content_type();
+/// // pass `multipart` as the streaming body of the request
+/// ```
+pub struct Multipart<'a> {
+    boundary: String,
+    fields: VecDeque<Field<'a>>,
+    current: Option<FieldBody<'a>>,
+    started: bool,
+    finished: bool,
 }
 
-impl<B> Multipart<B> {
-    
-    /// Create a new instance with the given body.
-    ///
-    /// ##Note
-    /// You might prefer to call `.build()` on the body for cleaner chaining.
-    pub fn with_body(body: B) -> Self {
-        let mut boundary = Boundary::gen();
-
-        boundary.skip_pre_newline();
-    
-        Multipart {
-            boundary: boundary,
-            end: Cursor::new(End),
-            state: BodyState::WriteBoundary,
-            body: body,
-        }
+impl<'a> Multipart<'a> {
+    /// Create a new request with a random boundary.
+    pub fn new() -> Self {
+        Self::with_boundary(crate::random_alphanumeric(BOUNDARY_LEN))
     }
 
-    /// Create a new `Multipart` to wrap a request.
+    /// Create a new request with the given boundary.
     ///
-    /// ## Returns Error
-    /// If `req.open_stream()` returns an error.
-    pub fn on_request<R: Request>(&self, req: &mut R) {
-        req.set_method();
-        req.set_boundary(self.boundary.get());
-    }
-}
-
-impl<B: Body> Multipart<B> {
-    pub fn on_writable<W: Write>(&mut self, out: &mut W) -> io::Result<RequestStatus> {
-        use self::BodyState::*;
-
-        loop {
-            match self.state {
-                NextField => if !self.body.finished() {
-                    self.state.go_next();
-                } else if try!(self.end.try_write_all(out)) {
-                    return Ok(NullRead);
-                } else {
-                    return Ok(MoreData);
-                },
-                WriteBoundary => {
-                    if let MoreData = try!(self.boundary.write_to(out)) {
-                        debug!("More data!");
-                        return Ok(MoreData);
-                    }
-                    
-                    if self.boundary.written() {
-                        self.boundary.reset();
-                        self.state.go_next();
-                    }
-                },
-                WriteField => if let NullRead = try!(self.body.write_field(out)) {
-                    self.state.go_next();
-                    return Ok(MoreData);
-                },
-                FieldNulled => if let NullRead = try!(self.body.write_field(out)) {
-                    self.body.pop_field();
-                    self.state.go_next();
-                } else {
-                    self.state = WriteField;
-                },
-            }
+    /// The boundary must not appear anywhere in the field data; the randomly generated boundary
+    /// of `new()` is recommended unless the data is known beforehand.
+    pub fn with_boundary<B: Into<String>>(boundary: B) -> Self {
+        Multipart {
+            boundary: boundary.into(),
+            fields: VecDeque::new(),
+            current: None,
+            started: false,
+            finished: false,
         }
     }
-}
 
-impl<B: Body> From<B> for Multipart<B> {
-    fn from(body: B) -> Self {
-        Self::with_body(body)
+    /// The boundary of this request, without the leading `--`.
+    pub fn boundary(&self) -> &str {
+        &self.boundary
     }
-}
-
-#[derive(PartialEq, Eq, Copy, Clone)]
-pub enum RequestStatus {
-    MoreData,
-    NullRead,
-}
-
-pub type FieldStatus = RequestStatus;
 
-#[derive(PartialEq, Eq, Copy, Clone)]
-enum BodyState {
-    NextField,
-    WriteField,
-    FieldNulled,
-    WriteBoundary,
-}
+    /// The value for the `Content-Type` header of the request:
+    /// `multipart/form-data; boundary={boundary}`.
+    pub fn content_type(&self) -> String {
+        format!("multipart/form-data; boundary={}", self.boundary)
+    }
 
-impl BodyState {
-    fn go_next(&mut self) {
-        use self::BodyState::*;
+    /// Add a text field to this request.
+    pub fn add_text<N: Into<String>, T: Into<String>>(&mut self, name: N, text: T) -> &mut Self {
+        let text = text.into();
+        let len = text.len() as u64;
 
-        *self = match *self {
-            NextField => WriteField,
-            WriteField => FieldNulled,
-            FieldNulled => WriteBoundary,
-            WriteBoundary => NextField,
-        }
+        self.push_field(name.into(), None, None, FieldBody::Text(Some(text.into())), Some(len))
     }
-}
 
-pub struct FieldHeader(Cursor<String>);
+    /// Open the file at `path` and add it as a file field to this request.
+    ///
+    /// The filename is taken from `path` and the `Content-Type` is guessed from its extension.
+    ///
+    /// ### Note: Blocking
+    /// The file is read with blocking I/O as the request is polled, in chunks of 64 KiB. If this
+    /// is not acceptable for your executor, use `add_stream()` with your runtime's file stream.
+    pub fn add_file<N: Into<String>, P: AsRef<Path>>(&mut self, name: N, path: P) -> io::Result<&mut Self> {
+        let path = path.as_ref();
+        let file = File::open(path)?;
+        let len = file.metadata()?.len();
 
-impl FieldHeader {
-    fn text(name: &str) -> Self {
-        Self::new(name, None, None)
-    }
+        let filename = path.file_name().and_then(|name| name.to_str()).map(Into::into);
+        let content_type = ::mime_guess::from_path(path).first_or_octet_stream();
 
-    fn file(name: &str, path: &Path) -> Self {
-        let (mime, filename) = mime_filename(path);
-        Self::new(name, Some(&mime), filename)
+        Ok(self.push_field(name.into(), filename, Some(content_type),
+                           FieldBody::File(FileStream::new(file)), Some(len)))
     }
 
-    fn stream(name: &str, content_type: Option<&Mime>, filename: Option<&str>) -> Self {
-        if let Some(content_type) = content_type {
-            Self::new(name, Some(&content_type), filename)
-        } else {
-            Self::new(name, Some(&::mime_guess::octet_stream()), None)
-        }
-    }
+    /// Add a stream of bytes as a file field to this request.
+    ///
+    /// If `content_type` is `None`, `application/octet-stream` is sent.
+    pub fn add_stream<N, S>(&mut self, name: N, stream: S, filename: Option<&str>,
+                            content_type: Option<Mime>) -> &mut Self
+    where N: Into<String>, S: TryStream + Send + 'a, S::Ok: Into<Bytes>, S::Error: Into<io::Error> {
+        use futures::TryStreamExt;
 
-    fn new(name: &str, content_type: Option<&Mime>, filename: Option<&str>) -> Self {
-        let mut header = format!("\r\nContent-Disposition: form-data; name=\"{}\"", name);
-        filename.map(|filename| write!(header, "; filename=\"{}\"", filename));
-        content_type.map(|content_type| write!(header, "\r\nContent-Type: {}", content_type));
-        header.push_str("\r\n\r\n");
+        let stream = stream.map_ok(Into::into).map_err(Into::into);
+        let content_type = content_type.unwrap_or(mime::APPLICATION_OCTET_STREAM);
 
-        FieldHeader(Cursor::new(header))
+        self.push_field(name.into(), filename.map(Into::into), Some(content_type),
+                        FieldBody::Stream(Box::pin(stream)), None)
     }
 
-    fn at_end(&self) -> bool { self.0.at_end() }
-}
-
-pub trait Field {
-    fn write_out<W: Write>(&mut self, w: &mut W) -> io::Result<FieldStatus>;
-}
+    /// Add the contents of an `AsyncRead` as a file field to this request.
+    ///
+    /// If `content_type` is `None`, `application/octet-stream` is sent.
+    pub fn add_async_read<N, R>(&mut self, name: N, read: R, filename: Option<&str>,
+                                content_type: Option<Mime>) -> &mut Self
+    where N: Into<String>, R: AsyncRead + Send + 'a {
+        self.add_stream(name, ReadStream::new(read), filename, content_type)
+    }
 
-pub struct TextField<'a> {
-    header: FieldHeader,
-    text: Cursor<CowStr<'a>>,
-}
+    /// The total length of the request body in bytes, if all fields have a known length.
+    ///
+    /// This will be `None` if any fields were added with `add_stream()` or `add_async_read()`,
+    /// in which case the request should be sent with `Transfer-Encoding: chunked`.
+    pub fn content_len(&self) -> Option<u64> {
+        let mut len = 0;
 
-impl<'a> TextField<'a> {
-    pub fn new<T: Into<Cow<'a, str>> + 'a>(name: &str, text: T) -> Self {
-        TextField {
-            header: FieldHeader::text(name),
-            text: Cursor::new(CowStr::from(text)),
+        for (idx, field) in self.fields.iter().enumerate() {
+            len += field.header(&self.boundary, idx == 0).len() as u64 + field.len?;
         }
-    }
-}
 
-impl<'a> Field for TextField<'a> {
-    fn write_out<W: Write>(&mut self, wrt: &mut W) -> io::Result<FieldStatus> {
-        try!(buf_redux::copy_buf(&mut self.header.0, wrt));
-        try!(buf_redux::copy_buf(&mut self.text, wrt));
+        Some(len + self.end().len() as u64)
+    }
 
-        Ok(
-            if self.text.at_end() {
-                RequestStatus::NullRead
-            } else {
-                RequestStatus::MoreData
-            }
-        )
+    fn push_field(&mut self, name: String, filename: Option<String>, content_type: Option<Mime>,
+                  body: FieldBody<'a>, len: Option<u64>) -> &mut Self {
+        self.fields.push_back(Field { name, filename, content_type, body, len });
+        self
     }
-}
 
-// Adapter to make Cow<'a, str> impl AsRef<[u8]>
-struct CowStr<'a>(Cow<'a, str>);
+    fn end(&self) -> Bytes {
+        let end = if self.started || !self.fields.is_empty() {
+            format!("\r\n--{}--\r\n", self.boundary)
+        } else {
+            format!("--{}--\r\n", self.boundary)
+        };
 
-impl<'a> AsRef<[u8]> for CowStr<'a> {
-    fn as_ref(&self) -> &[u8] {
-        (*self.0).as_ref()
+        end.into()
     }
 }
 
-impl<'a, S: Into<Cow<'a, str>>> From<S> for CowStr<'a> {
-    fn from(cow: S) -> Self {
-        CowStr(cow.into())
+impl Default for Multipart<'_> {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
-pub type FileField = StreamField<BufReader<File>>;
+impl Stream for Multipart<'_> {
+    type Item = io::Result<Bytes>;
 
-pub struct StreamField<R> {
-    header: FieldHeader,
-    stream: R,
-}
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
 
-impl<R: Read> StreamField<BufReader<R>> {
-    pub fn new(name: &str, stream: R, content_type: Option<&Mime>, filename: Option<&str>) -> Self {
-        StreamField::buffered(name, BufReader::new(stream), content_type, filename)
-    }
+        loop {
+            if let Some(ref mut body) = this.current {
+                match ready!(body.poll_chunk(cx)?) {
+                    // don't yield empty chunks as some clients take them for the end of the body
+                    Some(chunk) => if !chunk.is_empty() { return ready_some(chunk); },
+                    None => this.current = None,
+                }
+
+                continue;
+            }
 
-    pub fn boxed<'a>(self) -> StreamField<BufReader<Box<Read + 'a>>> where R: 'a {
-        StreamField {
-            header: self.header,
-            stream: self.stream.boxed(),
-        }
-    }
-}
+            if let Some(field) = this.fields.pop_front() {
+                let header = field.header(&this.boundary, !this.started);
+                this.started = true;
+                this.current = Some(field.body);
+                return ready_some(header);
+            }
 
-impl<R: BufRead> StreamField<R> {
-    pub fn buffered(name: &str, stream: R, content_type: Option<&Mime>, filename: Option<&str>) -> Self {
-        StreamField {
-            header: FieldHeader::stream(name, content_type, filename),
-            stream: stream,
-        }
-    }
+            if !this.finished {
+                this.finished = true;
+                return ready_some(this.end());
+            }
 
-    pub fn boxed_buffered<'a>(self) -> StreamField<Box<BufRead + 'a>> where R: 'a {
-        let stream: Box<BufRead + 'a> = Box::new(self.stream);
-        
-        StreamField {
-            header: self.header,
-            stream: stream,
+            return Poll::Ready(None);
         }
     }
 }
 
-impl FileField {
-    pub fn open_file<P: AsRef<Path>>(name: &str, path: P) -> io::Result<Self> {
-        let (mime, filename) = mime_filename(path.as_ref());
-        let file = try!(File::open(path.as_ref()));
-
-        Ok(Self::new(name, file, Some(&mime), filename))
+impl fmt::Debug for Multipart<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Multipart")
+            .field("boundary", &self.boundary)
+            .field("fields", &self.fields)
+            .field("finished", &self.finished)
+            .finish()
     }
 }
 
-impl<R: BufRead> Field for StreamField<R> {
-    fn write_out<W: Write>(&mut self, wrt: &mut W) -> io::Result<FieldStatus> {
-        try!(buf_redux::copy_buf(&mut self.header.0, wrt));
-
-        let read = try!(buf_redux::copy_buf(&mut self.stream, wrt));
-
-        Ok(
-            if self.header.at_end() && read == 0 {
-                RequestStatus::NullRead
-            } else {
-                RequestStatus::MoreData
-            }
-        )
-    }
+struct Field<'a> {
+    name: String,
+    filename: Option<String>,
+    content_type: Option<Mime>,
+    body: FieldBody<'a>,
+    len: Option<u64>,
 }
-pub trait Request {
-    fn set_method(&mut self);
 
-    fn set_boundary(&mut self, boundary: &str);
+impl Field<'_> {
+    /// The boundary line and headers of this field, terminated by the empty line before the data.
+    fn header(&self, boundary: &str, first: bool) -> Bytes {
+        let mut header = String::new();
 
-    fn set_content_len(&mut self, content_len: u64);
-}
+        if !first {
+            header.push_str("\r\n");
+        }
 
-pub trait Body {
-    fn write_field<W: Write>(&mut self, wrt: &mut W) -> io::Result<FieldStatus>;
+        // `fmt::Write` for `String` never fails
+        let _ = write!(header, "--{}\r\nContent-Disposition: form-data; name=\"{}\"",
+                       boundary, escape_param(&self.name));
 
-    fn pop_field(&mut self);
+        if let Some(ref filename) = self.filename {
+            let _ = write!(header, "; filename=\"{}\"", escape_param(filename));
+        }
 
-    fn finished(&self) -> bool;
+        if let Some(ref content_type) = self.content_type {
+            let _ = write!(header, "\r\nContent-Type: {}", content_type);
+        }
 
-    fn build(self) -> Multipart<Self> where Self: Sized {
-        Multipart::with_body(self)
+        header.push_str("\r\n\r\n");
+        header.into()
     }
 }
 
-impl<'a, B: Body> Body for &'a mut B {
-    fn write_field<W: Write>(&mut self, wrt: &mut W) -> io::Result<FieldStatus> {
-        (**self).write_field(wrt)
-    }
-
-    fn pop_field(&mut self) {
-        (**self).pop_field();
-    }
-
-    fn finished(&self) -> bool {
-        (**self).finished()
+impl fmt::Debug for Field<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Field")
+            .field("name", &self.name)
+            .field("filename", &self.filename)
+            .field("content_type", &self.content_type)
+            .field("len", &self.len)
+            .finish()
     }
 }
 
-wrap_const! {
-    Prefix = "\r\n--",
-    End = "--",
-}
-
-struct Boundary {
-    prefix: Cursor<Prefix>,
-    boundary: Cursor<String>,
+/// Escape a `Content-Disposition` parameter value the way browsers do, as described by the
+/// [HTML5 spec](https://html.spec.whatwg.org/multipage/form-control-infrastructure.html#multipart-form-data).
+fn escape_param(val: &str) -> String {
+    val.replace('"', "%22").replace('\r', "%0D").replace('\n', "%0A")
 }
 
-impl Boundary {
-    fn gen() -> Self {
-        let boundary = ::random_alphanumeric(BOUNDARY_LEN);
+#[cfg(test)]
+mod test {
+    use futures::executor::block_on;
+    use futures::{stream, TryStreamExt};
 
-        Boundary {
-            prefix: Cursor::new(Prefix),
-            boundary: Cursor::new(boundary),
-        }
-    }
+    use std::io;
 
-    fn write_to<W: Write>(&mut self, w: &mut W) -> io::Result<RequestStatus> {
-        if !try!(self.prefix.try_write_all(w)) {
-            return Ok(MoreData);
-        }
+    use super::Multipart;
 
-        if !try!(self.boundary.try_write_all(w)) {
-            return Ok(MoreData);
-        }
-
-        Ok(NullRead)
+    fn collect(multipart: Multipart) -> String {
+        let bytes = block_on(multipart.map_ok(|b| b.to_vec()).try_concat()).unwrap();
+        String::from_utf8(bytes).unwrap()
     }
 
-    fn written(&self) -> bool {
-        self.prefix.at_end() &&
-            self.boundary.at_end()
+    #[test]
+    fn test_empty() {
+        let multipart = Multipart::with_boundary("boundary");
+        assert_eq!(multipart.content_len(), Some(14));
+        assert_eq!(collect(multipart), "--boundary--\r\n");
     }
 
-    fn skip_pre_newline(&mut self) {
-        self.prefix.set_position(2);
-    }
+    #[test]
+    fn test_fields() {
+        let mut multipart = Multipart::with_boundary("boundary");
+        multipart.add_text("text", "Hello, world!")
+            .add_text("quote\"d", "text 2");
 
-    fn reset(&mut self) {
-        self.prefix.reset();
-        self.boundary.reset();
-    }
+        let content_len = multipart.content_len();
 
-    fn get(&self) -> &str {
-        self.boundary.get_ref()
-    }
-}
+        let body = collect(multipart);
 
-fn mime_filename(path: &Path) -> (Mime, Option<&str>) {
-    let content_type = ::mime_guess::guess_mime_type(path);
-    let filename = opt_filename(path);
-    (content_type, filename)
-}
+        assert_eq!(body, "--boundary\r\n\
+                          Content-Disposition: form-data; name=\"text\"\r\n\r\n\
+                          Hello, world!\r\n\
+                          --boundary\r\n\
+                          Content-Disposition: form-data; name=\"quote%22d\"\r\n\r\n\
+                          text 2\r\n\
+                          --boundary--\r\n");
 
-fn opt_filename(path: &Path) -> Option<&str> {
-    path.file_name().and_then(|filename| filename.to_str())
-}
+        assert_eq!(content_len, Some(body.len() as u64));
+    }
 
-trait CursorExt {
-    fn at_end(&self) -> bool;
+    #[test]
+    fn test_stream() {
+        let chunks: Vec<io::Result<&'static [u8]>> = vec![Ok(b"file "), Ok(b""), Ok(b"data")];
 
-    fn reset(&mut self);
+        let mut multipart = Multipart::with_boundary("boundary");
+        multipart.add_stream("file", stream::iter(chunks), Some("file.bin"), None);
 
-    fn try_write_all<W: Write>(&mut self, w: &mut W) -> io::Result<bool>;
-}
+        assert_eq!(multipart.content_len(), None);
 
-impl<T: AsRef<[u8]>> CursorExt for Cursor<T> {
-    fn at_end(&self) -> bool {
-        let buf = self.get_ref().as_ref();
-        let pos = self.position();
-        pos == buf.len() as u64
+        assert_eq!(collect(multipart), "--boundary\r\n\
+                                         Content-Disposition: form-data; name=\"file\"; \
+                                         filename=\"file.bin\"\r\n\
+                                         Content-Type: application/octet-stream\r\n\r\n\
+                                         file data\r\n\
+                                         --boundary--\r\n");
     }
 
-    fn reset(&mut self) {
-        self.set_position(0);
-    }
+    #[test]
+    fn test_stream_error() {
+        let chunks = vec![Ok(&b"file "[..]), Err(io::Error::other("read error"))];
 
-    fn try_write_all<W: Write>(&mut self, w: &mut W) -> io::Result<bool> {
-        try!(copy_buf(self, w));
+        let mut multipart = Multipart::with_boundary("boundary");
+        multipart.add_stream("file", stream::iter(chunks), None, None);
 
-        Ok(self.at_end())
+        let err = block_on(multipart.map_ok(|b| b.to_vec()).try_concat()).unwrap_err();
+        assert_eq!(err.to_string(), "read error");
     }
 }
@@ -4,31 +4,26 @@
 // http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
 // http://opensource.org/licenses/MIT>, at your option. This file may not be
 // copied, modified, or distributed except according to those terms.
-use std::borrow::Cow;
 use std::mem;
 use std::str::Utf8Error;
 
-use StreamError;
+use crate::StreamError;
 
 pub use display_bytes::display_bytes as show_bytes;
 
-pub use futures::*;
+pub use futures::{ready, Future, Stream, TryStream};
 
-pub type PollOpt<T, E> = Poll<Option<T>, E>;
+pub use std::pin::Pin;
+pub use std::task::{Context, Poll, Waker};
 
-pub fn ready<R, E, T: Into<R>>(val: T) -> Poll<R, E> {
-    Ok(Async::Ready(val.into()))
-}
+pub type PollOpt<T, E> = Poll<Option<Result<T, E>>>;
 
-pub fn not_ready<T, E>() -> Poll<T, E> {
-    Ok(Async::NotReady)
+pub fn ready_ok<T, E>(val: T) -> Poll<Result<T, E>> {
+    Poll::Ready(Ok(val))
 }
 
-pub fn error<T, E: Into<Cow<'static, str>>, E_: StreamError>(e: E) -> Result<T, E_> {
-    Err(match e.into() {
-        Cow::Owned(string) => E_::from_string(string),
-        Cow::Borrowed(str) => E_::from_str(str),
-    })
+pub fn ready_some<T, E>(val: T) -> PollOpt<T, E> {
+    Poll::Ready(Some(Ok(val)))
 }
 
 pub fn utf8_err<T, E: StreamError>(e: Utf8Error) -> Result<T, E> {
@@ -36,5 +31,10 @@ pub fn utf8_err<T, E: StreamError>(e: Utf8Error) -> Result<T, E> {
 }
 
 pub fn replace_default<T: Default>(dest: &mut T) -> T {
-    mem::replace(dest, T::default())
+    mem::take(dest)
+}
+
+/// Poll the next item from an `Unpin` stream with the error type of `TryStream`.
+pub fn poll_next<S: TryStream + Unpin>(stream: &mut S, cx: &mut Context) -> PollOpt<S::Ok, S::Error> {
+    Pin::new(stream).try_poll_next(cx)
 }
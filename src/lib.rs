@@ -9,58 +9,75 @@
 //!
 //! Features: 
 //! 
-//! * `client` (default): Enable the client-side abstractions for multipart requests. If the
-//! `hyper` feature is also set, enables integration with the Hyper HTTP client API.
+//! * `client` (default): Enable the client-side abstractions for multipart requests.
 //!
 //! * `server` (default): Enable the server-side abstractions for multipart requests. If the
-//! `hyper` feature is also set, enables integration with the Hyper HTTP server API.
+//!   `hyper` feature is also set, enables integration with the Hyper HTTP server API.
 //!
-//! * `hyper` (default): Enable integration with the [Hyper](https://github.com/hyperium/hyper) HTTP library 
-//! for client and/or server depending on which other feature flags are set.
+//! * `hyper` (default): Enable integration with the [Hyper](https://github.com/hyperium/hyper) 1.x
+//!   HTTP library: `RequestExt` for `hyper::Request<Incoming>` and `server::MultipartService`.
+//!
+//! * `futures01`: Enable compatibility shims for the futures 0.1 `Stream` and `Future` APIs
+//!   to ease migration; see `Multipart::with_body01()` and `Multipart::compat()`.
+//!
+//! * `digest`: Compute SHA-256, MD5 and CRC-32 digests of field data as it is read, and check
+//!   `Content-MD5` headers; see `server::FieldData::hashed()`.
+//!
+//! * `encoding_rs`: Decode text fields in character sets other than UTF-8 with
+//!   [`encoding_rs`](https://docs.rs/encoding_rs); see `server::FieldData::read_text()`.
+//!
+//! * `serde`: Deserialize a whole form into a type implementing `serde::Deserialize`;
+//!   see `server::Multipart::deserialize()`.
+//!
+//! * `use_arc`: Share server-side parser state with `Arc` and `Mutex` instead of `Rc` and
+//!   `RefCell`, so `server::Multipart` and its fields can be sent to other threads.
 #![deny(missing_docs)]
+#[cfg_attr(not(feature = "server"), allow(unused_imports))]
 #[macro_use] extern crate log;
-//extern crate env_logger;
 
-extern crate bytes;
-extern crate display_bytes;
-
-#[macro_use]
-extern crate futures;
-
-//extern crate mime_guess;
-extern crate rand;
+#[doc(hidden)]
+pub extern crate futures;
 
-//extern crate tempdir;
+#[cfg(feature = "futures01")]
+pub extern crate futures01;
 
 #[cfg(feature = "hyper")]
 pub extern crate hyper;
 
+#[cfg(feature = "encoding_rs")]
+pub extern crate encoding_rs;
+
+#[cfg(feature = "serde")]
+pub extern crate serde;
+
 pub extern crate mime;
 
 pub extern crate http;
 
-use rand::Rng;
-
 use std::borrow::Cow;
 use std::str::Utf8Error;
-use std::{io, ops};
+use std::io;
 
-// FIXME: after server prototype is working
-//#[cfg(feature = "client")]
-//pub mod client;
+#[cfg(feature = "client")]
+pub mod client;
 
 #[cfg(feature = "server")]
 pub mod server;
 
+// some helpers are only used by the server
+#[cfg_attr(not(feature = "server"), allow(dead_code, unused_imports))]
 mod helpers;
 
 #[doc(hidden)]
 pub mod mock;
 
-/*#[cfg(all(test, feature = "client", feature = "server"))]
+#[cfg(all(test, feature = "client", feature = "server"))]
 mod local_test;
-*/
+
+#[cfg(any(feature = "client", feature = "server"))]
 fn random_alphanumeric(len: usize) -> String {
+    use rand::Rng;
+
     rand::thread_rng().gen_ascii_chars().take(len).collect()
 }
 
@@ -106,7 +123,7 @@ impl BodyChunk for Vec<u8> {
     fn into_vec(self) -> Vec<u8> { self }
 }
 
-impl<'a> BodyChunk for &'a [u8] {
+impl BodyChunk for &[u8] {
     fn split_at(self, idx: usize) -> (Self, Self) {
         self.split_at(idx)
     }
@@ -129,7 +146,7 @@ impl<'a> BodyChunk for Cow<'a, [u8]> {
     }
 
     fn as_slice(&self) -> &[u8] {
-        &**self
+        self
     }
 }
 
@@ -163,47 +180,33 @@ pub trait StreamError: From<io::Error> {
 
     /// Wrap a `std::str::Utf8Error` into this error type.
     ///
-    /// Goes through `io::Error` by default.
+    /// Goes through `from_multipart()` by default if the `server` feature is enabled,
+    /// otherwise `io::Error`.
     fn from_utf8(err: Utf8Error) -> Self {
-        io::Error::new(io::ErrorKind::InvalidData, err).into()
-    }
-}
+        #[cfg(feature = "server")]
+        return Self::from_multipart(server::MultipartError::Utf8(err));
 
-impl StreamError for io::Error {}
-
-#[derive(Debug, Eq, PartialEq)]
-struct StringError(String);
-
-impl StreamError for StringError {
-    fn from_str(str: &'static str) -> Self {
-        StringError(str.into())
-    }
-
-    fn from_string(string: String) -> Self {
-        StringError(string)
-    }
-}
-
-impl Into<String> for StringError {
-    fn into(self) -> String {
-        self.0
+        #[cfg(not(feature = "server"))]
+        io::Error::new(io::ErrorKind::InvalidData, err).into()
     }
-}
 
-impl From<io::Error> for StringError {
-    fn from(err: io::Error) -> Self {
-        StringError(err.to_string())
+    /// Wrap a `server::LimitExceeded` into this error type.
+    ///
+    /// Goes through `from_multipart()` by default; see `LimitExceeded::from_io_error()`.
+    #[cfg(feature = "server")]
+    fn from_limit(err: server::LimitExceeded) -> Self {
+        Self::from_multipart(server::MultipartError::LimitExceeded(err))
     }
-}
 
-impl PartialEq<str> for StringError {
-    fn eq(&self, other: &str) -> bool {
-        self.0 == other
+    /// Wrap a `server::MultipartError` into this error type.
+    ///
+    /// All errors found by the parser are created with this method, except those which go
+    /// through `from_utf8()` and `from_limit()`. Goes through `io::Error` by default;
+    /// see `MultipartError::from_io_error()`.
+    #[cfg(feature = "server")]
+    fn from_multipart(err: server::MultipartError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, err).into()
     }
 }
 
-impl<'a> PartialEq<&'a str> for StringError {
-    fn eq(&self, other: &&'a str) -> bool {
-        self.0 == *other
-    }
-}
+impl StreamError for io::Error {}
@@ -4,17 +4,14 @@
 // http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
 // http://opensource.org/licenses/MIT>, at your option. This file may not be
 // copied, modified, or distributed except according to those terms.
-use client::HttpRequest as ClientRequest;
-use client::HttpStream as ClientStream;
-
-use server::HttpRequest as ServerRequest;
+use futures::executor::block_on;
+use futures::{stream, TryStream, TryStreamExt};
 
 use rand::Rng;
 use rand::distributions::{Range, Sample};
 
 use std::collections::HashMap;
 use std::io;
-use std::io::prelude::*;
 
 #[derive(Debug)]
 struct TestFields {
@@ -24,14 +21,12 @@ struct TestFields {
 
 #[test]
 fn local_test() {
-    let _ = ::env_logger::init(); 
-
     let test_fields = gen_test_fields();
 
     let buf = test_client(&test_fields);
 
     trace!(
-        "\n--Test Buffer Begin--\n{}\n--Test Buffer End--", 
+        "\n--Test Buffer Begin--\n{}\n--Test Buffer End--",
         String::from_utf8_lossy(&buf.buf)
     );
 
@@ -77,162 +72,102 @@ fn gen_bytes() -> Vec<u8> {
 }
 
 fn test_client(test_fields: &TestFields) -> HttpBuffer {
-    use client::Multipart;
+    use crate::client::Multipart;
 
-    let request = MockClientRequest::default();
+    let mut multipart = Multipart::new();
 
     let mut test_files = test_fields.files.iter();
 
-    let mut multipart = Multipart::from_request(request).unwrap();
-   
     // Intersperse file fields amongst text fields
     for (name, text) in &test_fields.texts {
         if let Some((file_name, file)) = test_files.next() {
-            multipart.write_stream(file_name, &mut &**file, None, None).unwrap();
+            multipart.add_stream(&**file_name, file_stream(file), None, None);
         }
 
-        multipart.write_text(name, text).unwrap();    
+        multipart.add_text(&**name, &**text);
     }
 
     // Write remaining files
     for (file_name, file) in test_files {
-       multipart.write_stream(file_name, &mut &**file, None, None).unwrap();
+        multipart.add_stream(&**file_name, file_stream(file), None, None);
     }
 
+    let boundary = multipart.boundary().to_string();
+
+    let buf = block_on(multipart.map_ok(|chunk| chunk.to_vec()).try_concat()).unwrap();
+
+    HttpBuffer { buf, boundary }
+}
 
-    multipart.send().unwrap()
+fn file_stream(file: &[u8]) -> impl TryStream<Ok = Vec<u8>, Error = io::Error> + Send {
+    // split the file into two chunks to test the client's handling of streams
+    let (first, second) = file.split_at(file.len() / 2);
+    stream::iter(vec![Ok(first.to_vec()), Ok(second.to_vec())])
 }
 
 fn test_server(buf: HttpBuffer, mut fields: TestFields) {
-    use server::{Multipart, MultipartData};
+    use crate::server::Multipart;
 
-    let mut multipart = Multipart::from_request(buf.for_server())
-        .unwrap_or_else(|_| panic!("Buffer should be multipart!"));
+    let mut multipart = Multipart::with_body(buf.for_server(), buf.boundary);
 
     trace!("Fields for server test: {:?}", fields);
 
-    while let Ok(Some(mut field)) = multipart.read_entry() {
-        match field.data {
-            MultipartData::Text(text) => {
-                let test_text = fields.texts.remove(&field.name);
+    block_on(async {
+        while let Some(field) = multipart.try_next().await.unwrap() {
+            let name = field.headers.name.clone();
+
+            if field.headers.filename.is_none() && field.headers.content_type.is_none() {
+                let text = field.data.read_text().await.unwrap().text;
+
+                let test_text = fields.texts.remove(&name);
 
                 assert!(
                     test_text.is_some(),
                     "Got text field that wasn't in original dataset: {:?} : {:?} ",
-                    field.name, text
+                    name, text
                 );
 
                 let test_text = test_text.unwrap();
 
                 assert!(
-                    text == test_text, 
-                    "Unexpected data for field {:?}: Expected {:?}, got {:?}", 
-                    field.name, test_text, text
+                    text == test_text,
+                    "Unexpected data for field {:?}: Expected {:?}, got {:?}",
+                    name, test_text, text
                 );
+            } else {
+                let test_bytes = fields.files.remove(&name).unwrap();
 
-            },
-            MultipartData::File(ref mut file) => {
-                let test_bytes = fields.files.remove(&field.name).unwrap();
-
-                let mut bytes = Vec::with_capacity(test_bytes.len());
-                file.read_to_end(&mut bytes).unwrap();
+                let bytes: Vec<u8> = field.data.try_concat().await.unwrap();
 
-                assert!(bytes == test_bytes, "Unexpected data for file {:?}: Expected {:?}, Got {:?}", 
-                        field.name, String::from_utf8_lossy(&test_bytes), String::from_utf8_lossy(&bytes)
+                assert!(bytes == test_bytes, "Unexpected data for file {:?}: Expected {:?}, Got {:?}",
+                        name, String::from_utf8_lossy(&test_bytes), String::from_utf8_lossy(&bytes)
                 );
-            },
+            }
         }
-    }
+    });
 
     assert!(fields.texts.is_empty(), "Text fields were not exhausted! Text fields: {:?}", fields.texts);
     assert!(fields.files.is_empty(), "File fields were not exhausted! File fields: {:?}", fields.files);
 }
 
-#[derive(Default, Debug)]
-pub struct MockClientRequest {
-    boundary: Option<String>,
-    content_len: Option<u64>,
-}
-
-impl ClientRequest for MockClientRequest {
-    type Stream = HttpBuffer;
-    type Error = io::Error;
-    
-    fn apply_headers(&mut self, boundary: &str, content_len: Option<u64>) -> bool {
-        self.boundary = Some(boundary.into());
-        self.content_len = content_len;
-        true
-    }
-
-    fn open_stream(self) -> Result<HttpBuffer, io::Error> {
-        debug!("MockClientRequest::open_stream called! {:?}", self);
-        let boundary = self.boundary.expect("HttpRequest::set_headers() was not called!");
-        
-        Ok(HttpBuffer { buf: Vec::new(), boundary: boundary, content_len: self.content_len })
-    }
-}
-
 #[derive(Debug)]
 pub struct HttpBuffer {
     buf: Vec<u8>,
     boundary: String,
-    content_len: Option<u64>,
 }
 
 impl HttpBuffer {
-    pub fn for_server(&self) -> ServerBuffer {
-        ServerBuffer {
-            data: &self.buf,
-            boundary: &self.boundary,
-            content_len: self.content_len,
-        }
-    }
-}
-
-impl Write for HttpBuffer {
-    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
-        self.buf.write(data)
-    }
-
-    fn flush(&mut self) -> io::Result<()> {
-        self.buf.flush()
-    }
-}
-
-impl Read for HttpBuffer {
-    fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
-        unimplemented!()
-    }
-}
-
-impl ClientStream for HttpBuffer {
-    type Request = MockClientRequest;
-    type Response = HttpBuffer;
-    type Error = io::Error;
-
-    fn finish(self) -> Result<Self, io::Error> { Ok(self) }
-}
-
-#[derive(Debug)]
-pub struct ServerBuffer<'a> {
-    data: &'a [u8],
-    boundary: &'a str,
-    content_len: Option<u64>,
-}
-
-impl<'a> Read for ServerBuffer<'a> {
-    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
-        self.data.read(out)
-    }
-}
-
-impl<'a> ServerRequest for ServerBuffer<'a> {
-    type Body = Self;
-
-    fn multipart_boundary(&self) -> Option<&str> { Some(&self.boundary) }
-
-    fn body(self) -> Self::Body {
-        self
+    /// Split the request body into chunks of random sizes to exercise the server's handling of
+    /// boundaries and headers spanning multiple chunks.
+    pub fn for_server(&self) -> impl TryStream<Ok = Vec<u8>, Error = io::Error> + Unpin {
+        // chunks must be long enough to verify a boundary split across two of them
+        const MIN_CHUNK: usize = 32;
+        const MAX_CHUNK: usize = 64;
+
+        let chunks: Vec<io::Result<Vec<u8>>> = self.buf.chunks(gen_range(MIN_CHUNK, MAX_CHUNK))
+            .map(|chunk| Ok(chunk.to_vec()))
+            .collect();
+
+        stream::iter(chunks)
     }
 }
-
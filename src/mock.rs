@@ -1,22 +1,23 @@
 use std::borrow::Cow;
+use std::{error, fmt, io};
 
-use helpers::*;
+use crate::StreamError;
+use crate::helpers::*;
 
 #[macro_export]
 macro_rules! mock_stream {
     ($($args:tt)*) => ({
         struct MockStream(u32);
 
-        #[allow(unused_comparisons)]
         impl $crate::futures::Stream for MockStream {
-            type Item = $crate::std::borrow::Cow<'static, [u8]>;
-            type Error = $crate::mock::StringError;
+            type Item = Result<::std::borrow::Cow<'static, [u8]>, $crate::mock::StringError>;
 
-            fn poll(&mut self) -> $crate::futures::Poll<Option<Self::Item>, Self::Error> {
+            fn poll_next(self: ::std::pin::Pin<&mut Self>, _cx: &mut ::std::task::Context)
+                -> ::std::task::Poll<Option<Self::Item>> {
                 let state = self.0;
-                self.0 += 1;
+                self.get_mut().0 += 1;
 
-                stream_items!(@(state, 0) $($args)*);
+                $crate::stream_items!(@(state, 0) $($args)*);
 
                 panic!("MockStream::poll() called after returning `None`, state: {}", state);
             }
@@ -30,24 +31,24 @@ macro_rules! mock_stream {
 #[macro_export]
 macro_rules! stream_items (
     (@($state:ident, $val:expr) $ex:expr) => (
-        stream_items!(@($state, $val) $ex;)
+        $crate::stream_items!(@($state, $val) $ex;)
     );
     (@($state:ident, $val:expr) $ex:expr, $repeat:expr) => (
-        stream_items!(@($state, $val) $ex, $repeat;)
+        $crate::stream_items!(@($state, $val) $ex, $repeat;)
     );
     (@($state:ident, $val:expr) $ex:expr; $($rest:tt)*) => (
         if $state == $val { return $crate::mock::into_poll($ex) }
-        stream_items!(@($state, $val + 1) $($rest)*);
+        $crate::stream_items!(@($state, $val + 1) $($rest)*);
     );
     (@($state:ident, $val:expr) $ex:expr, $repeat:expr; $($rest:tt)*) => (
-        if $state >= $val && $state <= $val + $repeat {
+        if ($val ..= $val + $repeat).contains(&$state) {
             return $crate::mock::into_poll($ex)
         }
 
-        stream_items!(@($state, $val + $repeat + 1) $($rest)*);
+        $crate::stream_items!(@($state, $val + $repeat + 1) $($rest)*);
     );
     (@($state:ident, $val:expr)) => (
-        if $state == $val { return $crate::helpers::ready(None) }
+        if $state == $val { return ::std::task::Poll::Ready(None) }
     );
 );
 
@@ -55,6 +56,36 @@ macro_rules! stream_items (
 #[derive(Debug, Eq, PartialEq)]
 pub struct StringError(String);
 
+impl StreamError for StringError {
+    fn from_str(str: &'static str) -> Self {
+        StringError(str.into())
+    }
+
+    fn from_string(string: String) -> Self {
+        StringError(string)
+    }
+}
+
+impl fmt::Display for StringError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl error::Error for StringError {}
+
+impl From<io::Error> for StringError {
+    fn from(err: io::Error) -> Self {
+        StringError(err.to_string())
+    }
+}
+
+impl From<StringError> for String {
+    fn from(err: StringError) -> Self {
+        err.0
+    }
+}
+
 impl PartialEq<String> for StringError {
     fn eq(&self, other: &String) -> bool {
         *self == **other
@@ -67,100 +98,117 @@ impl PartialEq<str> for StringError {
     }
 }
 
+impl<'a> PartialEq<&'a str> for StringError {
+    fn eq(&self, other: &&'a str) -> bool {
+        self.0 == *other
+    }
+}
+
+/// The item type returned by `mock_stream!()`.
+#[doc(hidden)]
+pub type MockItem = Option<Result<Cow<'static, [u8]>, StringError>>;
+
 /// An adaptor trait to make `mock_stream!()` easier to use,
 /// shouldn't be necessary for users to be aware of it.
 #[doc(hidden)]
 pub trait IntoPoll {
-    fn into_poll(self) -> Poll<Option<Cow<'static, [u8]>>, StringError>;
+    fn into_poll(self) -> Poll<MockItem>;
 }
 
 impl<T: AsRef<[u8]> + ?Sized> IntoPoll for &'static T {
-    fn into_poll(self) -> PollOpt<Cow<'static, [u8]>, StringError> {
-        ready(Some(self.as_ref().into()))
+    fn into_poll(self) -> Poll<MockItem> {
+        ready_some(self.as_ref().into())
     }
 }
 
 impl IntoPoll for Vec<u8> {
-    fn into_poll(self) -> PollOpt<Cow<'static, [u8]>, StringError> {
-        ready(Some(self.into()))
+    fn into_poll(self) -> Poll<MockItem> {
+        ready_some(self.into())
     }
 }
 
 impl IntoPoll for Option<Cow<'static, [u8]>> {
-    fn into_poll(self) -> Poll<Option<Cow<'static, [u8]>>, StringError> {
-        ready(self)
+    fn into_poll(self) -> Poll<MockItem> {
+        Poll::Ready(self.map(Ok))
     }
 }
 
-impl<E: Into<String>> IntoPoll for PollOpt<Cow<'static, [u8]>, E> {
-    fn into_poll(self) -> Poll<Option<Cow<'static, [u8]>>, StringError> {
-        self.map_err(|s| StringError(s.into()))
+impl<E: Into<String>> IntoPoll for Poll<Option<Result<Cow<'static, [u8]>, E>>> {
+    fn into_poll(self) -> Poll<MockItem> {
+        self.map(|opt| opt.map(|res| res.map_err(|s| StringError(s.into()))))
     }
 }
 
 #[doc(hidden)]
-pub fn into_poll<T: IntoPoll>(from: T) -> Poll<Option<Cow<'static, [u8]>>, StringError> {
+pub fn into_poll<T: IntoPoll>(from: T) -> Poll<MockItem> {
     from.into_poll()
 }
 
+/// Poll a stream once with a no-op waker.
+#[doc(hidden)]
+pub fn poll_once<S: Stream + Unpin>(stream: &mut S) -> Poll<Option<S::Item>> {
+    let waker = futures::task::noop_waker();
+    Pin::new(stream).poll_next(&mut Context::from_waker(&waker))
+}
+
 #[cfg(test)]
 mod test {
     use std::borrow::Cow;
 
-    use helpers::*;
+    use crate::helpers::*;
 
-    use super::into_poll;
+    use super::{into_poll, poll_once};
 
     #[test]
     fn test_into_poll() {
         assert_eq!(
-            Ok(Async::Ready(Some(Cow::Borrowed(&b"Hello, world!"[..])))),
+            Poll::Ready(Some(Ok(Cow::Borrowed(&b"Hello, world!"[..])))),
             into_poll("Hello, world!")
         );
     }
 
     #[test]
     fn test_empty_mock() {
-        assert_eq!(mock_stream!().poll(), into_poll(None));
+        assert_eq!(poll_once(&mut mock_stream!()), into_poll(None));
     }
 
     #[test]
     #[should_panic]
     fn test_extra_poll() {
         let mut stream = mock_stream!();
-        let _ = stream.poll();
-        let _ = stream.poll();
+        let _ = poll_once(&mut stream);
+        let _ = poll_once(&mut stream);
     }
 
     #[test]
     fn test_yield_once() {
         let mut stream = mock_stream!("Hello, world!");
-        assert_eq!(stream.poll(), into_poll("Hello, world!"));
-        assert_eq!(stream.poll(), ready(None));
+        assert_eq!(poll_once(&mut stream), into_poll("Hello, world!"));
+        assert_eq!(poll_once(&mut stream), Poll::Ready(None));
     }
 
     #[test]
     fn test_repeat_once() {
         let mut stream = mock_stream!("Hello, world!", 1);
-        assert_eq!(stream.poll(), into_poll("Hello, world!"));
-        assert_eq!(stream.poll(), ready(Some(b"Hello, world!".as_ref().into())));
-        assert_eq!(stream.poll(), ready(None));
+        assert_eq!(poll_once(&mut stream), into_poll("Hello, world!"));
+        assert_eq!(poll_once(&mut stream), ready_some(b"Hello, world!".as_ref().into()));
+        assert_eq!(poll_once(&mut stream), Poll::Ready(None));
     }
 
     #[test]
     fn test_two_items() {
         let mut stream = mock_stream!("Hello, world!"; "Hello, also!");
-        assert_eq!(stream.poll(), into_poll("Hello, world!"));
-        assert_eq!(stream.poll(), into_poll("Hello, also!"));
-        assert_eq!(stream.poll(), ready(None));
+        assert_eq!(poll_once(&mut stream), into_poll("Hello, world!"));
+        assert_eq!(poll_once(&mut stream), into_poll("Hello, also!"));
+        assert_eq!(poll_once(&mut stream), Poll::Ready(None));
     }
 
     #[test]
     fn test_two_items_one_repeat() {
         let mut stream = mock_stream!("Hello, world!", 1; "Hello, also!");
-        assert_eq!(stream.poll(), into_poll("Hello, world!"));
-        assert_eq!(stream.poll(), into_poll("Hello, world!"));
-        assert_eq!(stream.poll(), into_poll("Hello, also!"));
-        assert_eq!(stream.poll(), ready(None));
+        assert_eq!(poll_once(&mut stream), into_poll("Hello, world!"));
+        assert_eq!(poll_once(&mut stream), into_poll("Hello, world!"));
+        assert_eq!(poll_once(&mut stream), into_poll("Hello, also!"));
+        assert_eq!(poll_once(&mut stream), Poll::Ready(None));
     }
 }
@@ -4,42 +4,97 @@
 // http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
 // http://opensource.org/licenses/MIT>, at your option. This file may not be
 // copied, modified, or distributed except according to those terms.
-extern crate twoway;
+use std::{fmt, mem};
 
-use futures::{Async, Poll, Stream};
+#[cfg(feature = "encoding_rs")]
+use encoding_rs::Encoding;
 
-use std::{fmt, mem};
+use crate::{BodyChunk, StreamError};
 
-use {BodyChunk, StreamError};
+use super::error::{context_err, MultipartError, ParseContext};
+use super::limits::{check_limit, LimitKind, Limits};
+use super::mode::ParseMode;
+use super::progress::{ProgressListener, ProgressState};
+use super::twoway;
 
 use self::State::*;
 
-use helpers::*;
+use crate::helpers::*;
+
+/// The name of the field giving the charset of the request, RFC 7578 Section 4.6
+#[cfg(feature = "encoding_rs")]
+const CHARSET_FIELD: &str = "_charset_";
 
-pub type PollOpt<T, E> = Poll<Option<T>, E>;
+/// No charset label is longer than this.
+#[cfg(feature = "encoding_rs")]
+const MAX_CHARSET_LEN: usize = 64;
 
 /// A struct implementing `Read` and `BufRead` that will yield bytes until it sees a given sequence.
-pub struct BoundaryFinder<S: Stream> {
+pub struct BoundaryFinder<S: TryStream> {
     stream: S,
-    state: State<S::Item>,
+    state: State<S::Ok>,
     boundary: Box<[u8]>,
-    chunk: Option<S::Item>,
+    chunk: Option<S::Ok>,
+    pub limits: Limits,
+    pub mode: ParseMode,
+    total_read: u64,
+    preamble_read: u64,
+    in_preamble: bool,
+    epilogue_read: u64,
+    /// Set when the closing boundary has been read, until the end of the stream.
+    in_epilogue: bool,
+    /// The rest of the chunk containing the closing boundary.
+    epilogue_rem: Option<S::Ok>,
+    progress: Option<ProgressState>,
+    /// The number of boundaries consumed which were followed by a field.
+    fields: u64,
+    /// The name of the current field, for `ParseContext`.
+    field_name: Option<String>,
+    /// The length of the line ending trimmed off before the boundary held in `state`,
+    /// for `position()`.
+    boundary_newline: usize,
+    /// Set while skipping the padding and line ending after a boundary in lenient mode.
+    padding: Option<Padding>,
+    /// The value of the current field if it is `_charset_`, collected whether it is read or
+    /// skipped.
+    #[cfg(feature = "encoding_rs")]
+    charset_buf: Option<Vec<u8>>,
+    /// The charset given by a `_charset_` field, if one has been read.
+    #[cfg(feature = "encoding_rs")]
+    pub charset: Option<&'static Encoding>,
 }
 
-impl<S: Stream> BoundaryFinder<S> {
-    pub fn new<B: Into<Vec<u8>>>(stream: S, boundary: B) -> BoundaryFinder<S> {
+impl<S: TryStream> BoundaryFinder<S> {
+    pub fn new<B: Into<Vec<u8>>>(stream: S, boundary: B, limits: Limits) -> BoundaryFinder<S> {
         BoundaryFinder {
-            stream: stream,
+            stream,
             state: State::Watching,
             boundary: boundary.into().into_boxed_slice(),
             chunk: Default::default(),
+            limits,
+            mode: ParseMode::default(),
+            total_read: 0,
+            preamble_read: 0,
+            in_preamble: true,
+            epilogue_read: 0,
+            in_epilogue: false,
+            epilogue_rem: None,
+            progress: None,
+            fields: 0,
+            field_name: None,
+            boundary_newline: 0,
+            padding: None,
+            #[cfg(feature = "encoding_rs")]
+            charset_buf: None,
+            #[cfg(feature = "encoding_rs")]
+            charset: None,
         }
     }
 }
 
-impl<S: Stream> BoundaryFinder<S> where S::Item: BodyChunk, S::Error: StreamError {
+impl<S: TryStream + Unpin> BoundaryFinder<S> where S::Ok: BodyChunk, S::Error: StreamError {
 
-    pub fn push_chunk(&mut self, chunk: S::Item) {
+    pub fn push_chunk(&mut self, chunk: S::Ok) {
         debug_assert!(twoway::find_bytes(chunk.as_slice(), &self.boundary).is_none(),
                       "Pushed chunk contains boundary: {}", show_bytes(chunk.as_slice()));
 
@@ -51,28 +106,85 @@ impl<S: Stream> BoundaryFinder<S> where S::Item: BodyChunk, S::Error: StreamErro
         self.chunk = Some(chunk);
     }
 
-    pub fn body_chunk(&mut self) -> PollOpt<S::Item, S::Error> {
+    pub fn set_progress_listener<L: ProgressListener + 'static>(&mut self, listener: L) {
+        self.progress = Some(ProgressState::new(listener, self.total_read));
+    }
+
+    /// Record the start of a field for `context()` and report it to the progress listener, if any.
+    pub fn start_field(&mut self, name: Option<&str>, filename: Option<&str>) {
+        self.field_name = name.map(Into::into);
+
+        #[cfg(feature = "encoding_rs")]
+        {
+            self.charset_buf = if name == Some(CHARSET_FIELD) { Some(Vec::new()) } else { None };
+        }
+
+        if let Some(ref mut progress) = self.progress {
+            progress.start_field(name, filename);
+        }
+    }
+
+    /// The offset in the request of the next byte to be yielded, i.e. the bytes read from the
+    /// stream less those held back.
+    pub fn position(&self) -> u64 {
+        let held = match self.state {
+            Partial(ref chunk, _) | Remainder(ref chunk) => chunk.len(),
+            Boundary(ref bnd) => bnd.len() + self.boundary_newline,
+            BoundarySplit(ref first, ref second) => first.len() + second.len() + self.boundary_newline,
+            Watching | End => 0,
+        };
+
+        let held = held + self.chunk.as_ref().map_or(0, BodyChunk::len)
+            + self.epilogue_rem.as_ref().map_or(0, BodyChunk::len);
+
+        self.total_read - held as u64
+    }
+
+    /// The context of an error found in `bytes`, which start at `offset` in the request.
+    pub fn context(&self, offset: u64, bytes: &[u8]) -> ParseContext {
+        let field_index = if self.in_epilogue { None } else { self.fields.checked_sub(1) };
+        ParseContext::new(offset, field_index, self.field_name.as_deref(), bytes)
+    }
+
+    /// Get the next chunk before the next boundary, reporting it to the progress listener.
+    ///
+    /// Chunks are counted towards the current field if one has been started, so header bytes
+    /// pushed back with `push_chunk()` are only counted once.
+    pub fn body_chunk(&mut self, cx: &mut Context) -> PollOpt<S::Ok, S::Error> {
+        let res = self.poll_body_chunk(cx);
+
+        if let (Some(progress), Poll::Ready(Some(Ok(chunk)))) = (self.progress.as_mut(), &res) {
+            progress.chunk(self.total_read, chunk.len());
+        }
+
+        #[cfg(feature = "encoding_rs")]
+        if let (Some(buf), Poll::Ready(Some(Ok(chunk)))) = (self.charset_buf.as_mut(), &res) {
+            buf.extend_from_slice(chunk.as_slice());
+
+            if buf.len() > MAX_CHARSET_LEN {
+                self.charset_buf = None;
+            }
+        }
+
+        res
+    }
+
+    fn poll_body_chunk(&mut self, cx: &mut Context) -> PollOpt<S::Ok, S::Error> {
         macro_rules! try_ready_opt(
             ($try:expr) => (
                 match $try {
-                    Ok(Async::Ready(Some(val))) => val,
-                    Ok(Async::Ready(None)) => {
-                        self.state = End;
-                        return ready(None);
-                    }
-                    other => return other.into(),
+                    Poll::Ready(Some(Ok(val))) => val,
+                    Poll::Ready(None) => return self.missing_closing_boundary(None),
+                    other => return other,
                 }
             );
-            ($try:expr; $restore:expr) => (
+            ($try:expr; $restore:expr; $rest:expr) => (
                 match $try {
-                    Ok(Async::Ready(Some(val))) => val,
-                    Ok(Async::Ready(None)) => {
-                        self.state = End;
-                        return ready(None);
-                    },
+                    Poll::Ready(Some(Ok(val))) => val,
+                    Poll::Ready(None) => return self.missing_closing_boundary($rest),
                     other => {
                         self.state = $restore;
-                        return other.into();
+                        return other;
                     }
                 }
             )
@@ -83,116 +195,223 @@ impl<S: Stream> BoundaryFinder<S> where S::Item: BodyChunk, S::Error: StreamErro
                    self.chunk.as_ref().map(BodyChunk::as_slice));
 
             if let Some(pushed) = self.chunk.take() {
-                return ready(pushed);
+                return ready_some(pushed);
             }
 
             match self.state {
-                Boundary(_) | BoundarySplit(_, _) | End => return ready(None),
+                Boundary(_) | BoundarySplit(_, _) | End => return Poll::Ready(None),
                 _ => ()
             }
 
             match mem::replace(&mut self.state, Watching) {
                 Watching => {
-                    let chunk = try_ready_opt!(self.stream.poll());
+                    let chunk = try_ready_opt!(self.poll_stream(cx));
 
                     // For sanity
-                    if chunk.is_empty() { return ready(chunk); }
+                    if chunk.is_empty() { return ready_some(chunk); }
+
+                    let chunk = match self.skip_padding_chunk(chunk) {
+                        Some(chunk) => chunk,
+                        None => continue,
+                    };
+
+                    if let Some(ret) = self.check_chunk(chunk) { return ret; }
+                },
+                Remainder(rem) => {
+                    let rem = match self.skip_padding_chunk(rem) {
+                        Some(rem) => rem,
+                        None => continue,
+                    };
 
-                    return self.check_chunk(chunk);
+                    if let Some(ret) = self.check_chunk(rem) { return ret; }
                 },
-                Remainder(rem) => return self.check_chunk(rem),
                 Partial(partial, res) => {
-                    let chunk = try_ready_opt!(self.stream.poll(); Partial(partial, res));
-                    let needed_len = (self.boundary_size(res.incl_crlf)).saturating_sub(partial.len());
+                    // the data before a partial boundary is kept if the request ends there
+                    let chunk = try_ready_opt!(self.poll_stream(cx); Partial(partial, res);
+                                               Some(partial.split_at(res.idx).0));
+                    if self.check_boundary_split(&partial.as_slice()[res.idx..], chunk.as_slice(),
+                                                 res.newline) {
+                        let needed_len = self.boundary_size(res.newline)
+                            .saturating_sub(partial.len() - res.idx);
+
+                        if needed_len > chunk.len() {
+                            // hopefully rare
+                            let bnd = &partial.as_slice()[res.idx..];
+                            let offset = self.total_read - (chunk.len() + bnd.len()) as u64;
+                            let context = self.context(offset, &[bnd, chunk.as_slice()].concat());
+                            let err = MultipartError::Boundary("chunk too short to verify multipart boundary".into());
+                            return Poll::Ready(Some(context_err(err, context)));
+                        }
 
-                    if needed_len > chunk.len() {
-                        // hopefully rare
-                        return error("chunk too short to verify multipart boundary");
-                    }
+                        let (ret, first) = partial.split_at(res.idx);
 
-                    if self.check_boundary_split(&partial.as_slice()[res.boundary_start()..], chunk.as_slice()) {
-                        let (ret, first) = partial.split_at(res.boundary_start());
-                        self.state = BoundarySplit(first, chunk);
+                        let (first, second) = trim_newline(first, chunk, res.newline.len());
+
+                        self.state = BoundarySplit(first, second);
+                        self.boundary_newline = res.newline.len();
 
                         if !ret.is_empty() {
-                            return ready(ret);
+                            return ready_some(ret);
                         } else {
                             // Don't return an empty chunk at the end
-                            return ready(None);
+                            return Poll::Ready(None);
                         }
                     }
 
                     self.state = Remainder(chunk);
-                    return ready(partial);
+                    return ready_some(partial);
                 },
                 state => unreachable!("invalid state: {:?}", state),
             }
         }
     }
 
-    fn check_chunk(&mut self, chunk: S::Item) -> PollOpt<S::Item, S::Error> {
+    /// Poll the underlying stream, counting the bytes read against `Limits::total_size`.
+    fn poll_stream(&mut self, cx: &mut Context) -> PollOpt<S::Ok, S::Error> {
+        let res = poll_next(&mut self.stream, cx);
+
+        if let Poll::Ready(Some(Ok(ref chunk))) = res {
+            self.total_read += chunk.len() as u64;
+            check_limit(LimitKind::TotalSize, self.total_read, self.limits.total_size)?;
+        }
+
+        res
+    }
+
+    /// The stream ended before the closing boundary, which is an error unless in lenient mode;
+    /// then `rest` is yielded as the end of the last field, if not empty.
+    fn missing_closing_boundary(&mut self, rest: Option<S::Ok>) -> PollOpt<S::Ok, S::Error> {
+        self.state = End;
+        self.padding = None;
+
+        if self.mode == ParseMode::Strict {
+            let context = self.context(self.total_read, &[]);
+            let err = MultipartError::UnexpectedEof("before the closing boundary".into());
+            return Poll::Ready(Some(context_err(err, context)));
+        }
+
+        warn!("accepting a request without a closing boundary after {} bytes", self.total_read);
+
+        match rest {
+            Some(rest) if !rest.is_empty() => ready_some(rest),
+            _ => Poll::Ready(None),
+        }
+    }
+
+    /// Skip the padding and line ending after a boundary at the start of `chunk`, returning
+    /// `None` if that was all of it.
+    fn skip_padding_chunk(&mut self, chunk: S::Ok) -> Option<S::Ok> {
+        let skip = self.skip_padding(chunk.as_slice());
+
+        if skip < chunk.len() {
+            Some(chunk.split_at(skip).1)
+        } else {
+            None
+        }
+    }
+
+    /// Get the number of bytes of padding and line ending after a boundary at the start of
+    /// `bytes`, if they are being skipped.
+    fn skip_padding(&mut self, bytes: &[u8]) -> usize {
+        let mut padding = match self.padding.take() {
+            Some(padding) => padding,
+            None => return 0,
+        };
+
+        for (idx, &byte) in bytes.iter().enumerate() {
+            match byte {
+                b' ' | b'\t' => {
+                    if !padding.padded {
+                        warn!("accepting transport padding after a boundary");
+                        padding.padded = true;
+                    }
+
+                    padding.cr = false;
+                },
+                b'\r' => padding.cr = true,
+                b'\n' => {
+                    if !padding.cr {
+                        warn!("accepting a bare LF after a boundary");
+                    }
+
+                    return idx + 1;
+                },
+                _ => {
+                    warn!("accepting a boundary without a line ending before: {}", show_bytes(&bytes[idx..]));
+                    return idx;
+                },
+            }
+        }
+
+        self.padding = Some(padding);
+        bytes.len()
+    }
+
+    /// Returns `None` if the chunk ended in a partial boundary and another chunk is needed.
+    fn check_chunk(&mut self, chunk: S::Ok) -> Option<PollOpt<S::Ok, S::Error>> {
         trace!("check chunk: {}", show_bytes(chunk.as_slice()));
 
         if let Some(res) = self.find_boundary(&chunk) {
             debug!("boundary found: {:?}", res);
 
-            let len = self.boundary_size(res.incl_crlf);
+            let len = self.boundary_size(res.newline);
 
             if chunk.len() < res.idx + len {
                 // Either partial boundary, or boundary but not the two bytes after it
                 self.state = Partial(chunk, res);
                 trace!("partial boundary: {:?}", self.state);
+                None
             } else {
                 let (ret, bnd) = chunk.split_at(res.idx);
 
-                let bnd = if res.incl_crlf {
-                    // cut off the preceding CRLF
-                    bnd.split_at(2).1
-                } else {
-                    bnd
-                };
+                // cut off the preceding line ending
+                let bnd = bnd.split_at(res.newline.len()).1;
 
                 self.state = Boundary(bnd);
+                self.boundary_newline = res.newline.len();
 
                 trace!("boundary located: {:?} returning chunk: {}", self.state, show_bytes(ret.as_slice()));
 
                 if !ret.is_empty() {
-                    return ready(ret);
+                    Some(ready_some(ret))
                 } else {
-                    return ready(None);
+                    Some(Poll::Ready(None))
                 }
-            };
-
-            return not_ready();
+            }
         } else {
-            ready(chunk)
+            Some(ready_some(chunk))
         }
     }
 
-    fn find_boundary(&self, chunk: &S::Item) -> Option<SearchResult> {
+    fn find_boundary(&self, chunk: &S::Ok) -> Option<SearchResult> {
         twoway::find_bytes(chunk.as_slice(), &self.boundary)
-            .map(|idx| check_crlf(chunk.as_slice(), idx))
+            .map(|idx| self.check_newline(chunk.as_slice(), idx))
             .or_else(|| self.partial_find_boundary(chunk))
     }
 
-    fn partial_find_boundary(&self, chunk: &S::Item) -> Option<SearchResult> {
+    fn partial_find_boundary(&self, chunk: &S::Ok) -> Option<SearchResult> {
         let chunk = chunk.as_slice();
         let len = chunk.len();
 
         partial_rmatch(chunk, &self.boundary)
-            .map(|idx| check_crlf(chunk, idx))
+            .map(|idx| self.check_newline(chunk, idx))
             .or_else(||
                 // EDGE CASE: the bytes of the newline before the boundary are at the end
                 // of the chunk
-                if len > 2 && &chunk[len - 2 ..] == &*b"\r\n" {
+                if len >= 2 && chunk[len - 2 ..] == *CRLF {
                     Some(SearchResult {
                         idx: len - 2,
-                        incl_crlf: true,
+                        newline: CRLF,
+                    })
+                } else if len >= 1 && chunk[len - 1] == b'\r' {
+                    Some(SearchResult {
+                        idx: len - 1,
+                        newline: CRLF,
                     })
-                } else if len > 1 && chunk[len - 1] == b'\r' {
+                } else if len >= 1 && chunk[len - 1] == b'\n' && self.mode == ParseMode::Lenient {
                     Some(SearchResult {
                         idx: len - 1,
-                        incl_crlf: true
+                        newline: LF,
                     })
                 } else {
                     None
@@ -200,40 +419,133 @@ impl<S: Stream> BoundaryFinder<S> where S::Item: BodyChunk, S::Error: StreamErro
             )
     }
 
+    /// If there's a newline before the boundary, we want to back up to make sure we don't yield
+    /// a newline that the client doesn't expect; a bare LF is only taken in lenient mode.
+    fn check_newline(&self, chunk: &[u8], idx: usize) -> SearchResult {
+        let newline = if chunk[..idx].ends_with(CRLF) {
+            CRLF
+        } else if chunk[..idx].ends_with(LF) && self.mode == ParseMode::Lenient {
+            warn!("accepting a bare LF before a boundary");
+            LF
+        } else {
+            b""
+        };
+
+        SearchResult {
+            idx: idx - newline.len(),
+            newline,
+        }
+    }
+
     fn check_boundary(&self, bytes: &[u8]) -> bool {
         (bytes.len() >= 2 && bytes[2..].starts_with(&self.boundary))
             || bytes.starts_with(&self.boundary)
     }
 
-    fn check_boundary_split(&self, first: &[u8], second: &[u8]) -> bool {
-        let check_len = self.boundary.len() - first.len();
+    /// Check if the boundary, preceded by `newline`, starts in `first` and continues
+    /// into `second`, or if they're too short to contain the whole boundary, that they match
+    /// its beginning.
+    fn check_boundary_split(&self, first: &[u8], second: &[u8], newline: &[u8]) -> bool {
+        first.iter().chain(second).zip(newline.iter().chain(&*self.boundary)).all(|(l, r)| l == r)
+    }
+
+    /// Get the next chunk of the preamble, or `None` if the first boundary has been reached.
+    pub fn preamble_chunk(&mut self, cx: &mut Context) -> PollOpt<S::Ok, S::Error> {
+        if !self.in_preamble {
+            return Poll::Ready(None);
+        }
+
+        let chunk = ready!(self.body_chunk(cx)?);
+
+        if let Some(ref chunk) = chunk {
+            self.count_preamble(chunk)?;
+        }
+
+        Poll::Ready(chunk.map(Ok))
+    }
+
+    fn count_preamble(&mut self, chunk: &S::Ok) -> Result<(), S::Error> {
+        if self.in_preamble {
+            self.preamble_read += chunk.len() as u64;
+            check_limit(LimitKind::PreambleSize, self.preamble_read, self.limits.preamble_size)?;
+        }
 
-        second.len() >= check_len && first.iter().chain(&second[..check_len])
-            .eq(self.boundary.iter())
+        Ok(())
+    }
+
+    /// Get the next chunk of the epilogue, or `None` if the closing boundary hasn't been read
+    /// or the stream has ended.
+    pub fn epilogue_chunk(&mut self, cx: &mut Context) -> PollOpt<S::Ok, S::Error> {
+        if !self.in_epilogue {
+            return Poll::Ready(None);
+        }
+
+        let chunk = match self.epilogue_rem.take() {
+            Some(chunk) => chunk,
+            None => match ready!(self.poll_stream(cx)?) {
+                Some(chunk) => chunk,
+                None => {
+                    self.in_epilogue = false;
+                    return Poll::Ready(None);
+                },
+            },
+        };
+
+        self.epilogue_read += chunk.len() as u64;
+        check_limit(LimitKind::EpilogueSize, self.epilogue_read, self.limits.epilogue_size)?;
+
+        ready_some(chunk)
     }
 
     /// Returns `true` if another field should follow this boundary, `false` if the stream
     /// is at a logical end
-    pub fn consume_boundary(&mut self) -> Poll<bool, S::Error> {
+    pub fn consume_boundary(&mut self, cx: &mut Context) -> Poll<Result<bool, S::Error>> {
         debug!("consuming boundary");
 
-        while try_ready!(self.body_chunk()).is_some() {}
+        while let Some(chunk) = ready!(self.body_chunk(cx)?) {
+            self.count_preamble(&chunk)?;
+        }
 
-        match mem::replace(&mut self.state, Watching) {
+        self.in_preamble = false;
+
+        if let Some(ref mut progress) = self.progress {
+            progress.end_field();
+        }
+
+        // the rest of a `_charset_` field has been read or skipped by now
+        #[cfg(feature = "encoding_rs")]
+        if let Some(buf) = self.charset_buf.take() {
+            self.charset = Encoding::for_label(&buf);
+            debug!("request charset: {:?}", self.charset.map(Encoding::name));
+        }
+
+        let res = match mem::replace(&mut self.state, Watching) {
             Boundary(bnd) => self.confirm_boundary(bnd),
             BoundarySplit(first, second) => self.confirm_boundary_split(first, second),
-            End => ready(false),
+            End => ready_ok(false),
             state => unreachable!("invalid state: {:?}", state),
+        };
+
+        if let Poll::Ready(Ok(another)) = res {
+            self.fields += another as u64;
+            self.field_name = None;
         }
+
+        res
     }
 
-    fn confirm_boundary(&mut self, boundary: S::Item) -> Poll<bool, S::Error> {
-        if boundary.len() < self.boundary_size(false) {
-            return error(format!("boundary sequence too short: {}",
-                                 show_bytes(boundary.as_slice())));
+    fn confirm_boundary(&mut self, boundary: S::Ok) -> Poll<Result<bool, S::Error>> {
+        if boundary.len() < self.boundary_size(b"") {
+            let context = self.context(self.total_read - boundary.len() as u64, boundary.as_slice());
+            return Poll::Ready(context_err(MultipartError::Boundary("boundary sequence too short".into()),
+                                           context));
         }
 
-        let (boundary, rem) = boundary.split_at(self.boundary_size(false));
+        let is_end = self.check_last_two(&boundary.as_slice()[self.boundary.len()..self.boundary_size(b"")]);
+
+        debug!("is_end: {:?}", is_end);
+
+        let (boundary, rem) = boundary.split_at(self.boundary_len(is_end));
         let boundary = boundary.as_slice();
 
         trace!("confirming boundary: {}", show_bytes(boundary));
@@ -246,66 +558,104 @@ impl<S: Stream> BoundaryFinder<S> where S::Item: BodyChunk, S::Error: StreamErro
                       "invalid boundary previous confirmed as valid: {}",
                       show_bytes(boundary));
 
-        self.state = if !rem.is_empty() { Remainder(rem) } else { Watching };
-
         trace!("boundary found: {}", show_bytes(boundary));
 
-        let is_end = check_last_two(boundary);
+        self.set_remainder(rem, is_end);
 
-        debug!("is_end: {:?}", is_end);
-
-        if is_end { self.state = End; }
-
-        ready(!is_end)
+        ready_ok(!is_end)
     }
 
-    fn confirm_boundary_split(&mut self, first: S::Item, second: S::Item) -> Poll<bool, S::Error> {
+    fn confirm_boundary_split(&mut self, first: S::Ok, second: S::Ok) -> Poll<Result<bool, S::Error>> {
         let first = first.as_slice();
-        let check_len = self.boundary_size(false) - first.len();
+        let check_len = self.boundary_size(b"") - first.len();
 
         if second.len() < check_len {
-            return error(format!("split boundary sequence too short: ({}, {})",
-                                 show_bytes(first),
-                                 show_bytes(second.as_slice())));
+            let offset = self.total_read - (first.len() + second.len()) as u64;
+            let context = self.context(offset, &[first, second.as_slice()].concat());
+            return Poll::Ready(context_err(MultipartError::Boundary("split boundary sequence too short".into()),
+                                           context));
         }
 
-        let (second, rem) = second.split_at(check_len);
-        let second = second.as_slice();
-
-        self.state = Remainder(rem);
-
         debug_assert!(!first.starts_with(b"\r\n"),
                       "leading CRLF should have been trimmed from first boundary section: {}",
                       show_bytes(first));
 
-        debug_assert!(self.check_boundary_split(first, second),
+        debug_assert!(self.check_boundary_split(first, &second.as_slice()[..check_len], b""),
                       "invalid split boundary previous confirmed as valid: ({}, {})",
-                      show_bytes(first), show_bytes(second));
+                      show_bytes(first), show_bytes(&second.as_slice()[..check_len]));
 
-        let is_end = check_last_two(second);
+        // the last two bytes may also be split
+        let mut last_two = [0u8; 2];
 
-        if is_end { self.state = End; }
+        for (dest, &byte) in last_two.iter_mut().zip(first.iter().chain(second.as_slice()).skip(self.boundary.len())) {
+            *dest = byte;
+        }
+
+        let is_end = self.check_last_two(&last_two);
+
+        // in lenient mode, a byte after the boundary may be in `first` and so is skipped as
+        // padding before `second`
+        let boundary_len = self.boundary_len(is_end);
+        let first_rem = first.get(boundary_len..).unwrap_or_default();
+        let skip = self.skip_padding(first_rem);
+
+        if skip < first_rem.len() {
+            warn!("dropping a byte after the boundary: {}", show_bytes(first_rem));
+        }
 
-        ready(!is_end)
+        let rem = second.split_at(boundary_len.saturating_sub(first.len())).1;
+
+        self.set_remainder(rem, is_end);
+
+        ready_ok(!is_end)
     }
 
-    /// The necessary size to verify a boundary, including the potential CRLF before, and the
-    /// CRLF / "--" afterward
-    fn boundary_size(&self, incl_crlf: bool) -> usize {
-        self.boundary.len() + if incl_crlf { 4 } else { 2 }
+    /// The length of the boundary and what follows it on its line which `set_remainder()`
+    /// doesn't skip: all of it in strict mode, or just the boundary (and `--` at the end) in
+    /// lenient mode, where the padding and line ending after a boundary are skipped as they
+    /// are read.
+    fn boundary_len(&mut self, is_end: bool) -> usize {
+        if self.mode == ParseMode::Lenient && !is_end {
+            self.padding = Some(Padding::default());
+            self.boundary.len()
+        } else {
+            self.boundary_size(b"")
+        }
+    }
+
+    /// Check the two bytes after a boundary, returning `true` if it is the closing boundary.
+    fn check_last_two(&self, last_two: &[u8]) -> bool {
+        let is_end = last_two == b"--";
+
+        if !is_end && last_two != CRLF && self.mode == ParseMode::Strict {
+            warn!("unexpected bytes after boundary: {:?} ('--': {:?}, '\\r\\n': {:?})",
+                  last_two, b"--", CRLF);
+        }
+
+        is_end
     }
-}
 
-impl<S: Stream> Stream for BoundaryFinder<S> where S::Item: BodyChunk, S::Error: StreamError {
-    type Item = S::Item;
-    type Error = S::Error;
+    /// Keep the rest of the chunk after a boundary, as the epilogue if it's the closing one.
+    fn set_remainder(&mut self, rem: S::Ok, is_end: bool) {
+        let rem = if !rem.is_empty() { Some(rem) } else { None };
 
-    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
-        self.body_chunk()
+        if is_end {
+            self.state = End;
+            self.in_epilogue = true;
+            self.epilogue_rem = rem;
+        } else {
+            self.state = rem.map_or(Watching, Remainder);
+        }
+    }
+
+    /// The necessary size to verify a boundary, including the `newline` before, and the
+    /// CRLF / "--" afterward
+    fn boundary_size(&self, newline: &[u8]) -> usize {
+        newline.len() + self.boundary.len() + 2
     }
 }
 
-impl<S: Stream + fmt::Debug> fmt::Debug for BoundaryFinder<S> where S::Item: BodyChunk + fmt::Debug {
+impl<S: TryStream + fmt::Debug> fmt::Debug for BoundaryFinder<S> where S::Ok: BodyChunk + fmt::Debug {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         f.debug_struct("BoundaryFinder")
             .field("stream", &self.stream)
@@ -348,41 +698,31 @@ impl<B: BodyChunk> fmt::Debug for State<B> {
 #[derive(Copy, Clone, Debug)]
 struct SearchResult {
     idx: usize,
-    incl_crlf: bool,
+    /// The line ending before the boundary: CRLF, LF in lenient mode, or none.
+    newline: &'static [u8],
 }
 
-impl SearchResult {
-    fn boundary_start(&self) -> usize {
-        if self.incl_crlf { self.idx + 2 } else { self.idx }
-    }
-}
-
-/// If there's a CRLF before the boundary, we want to back up to make sure we don't yield a newline
-/// that the client doesn't expect
-fn check_crlf(chunk: &[u8], mut idx: usize) -> SearchResult {
-    let mut incl_crlf = false;
-    if idx > 2 && chunk[idx - 2 .. idx] == *b"\r\n" {
-        incl_crlf = true;
-        idx -= 2;
-    }
+const CRLF: &[u8] = b"\r\n";
+const LF: &[u8] = b"\n";
 
-    SearchResult {
-        idx: idx,
-        incl_crlf: incl_crlf
+/// Trim the `len` bytes of line ending preceding a boundary which may itself be split across
+/// two chunks.
+fn trim_newline<B: BodyChunk>(first: B, second: B, len: usize) -> (B, B) {
+    if first.len() >= len {
+        (first.split_at(len).1, second)
+    } else {
+        let first_len = first.len();
+        (first.split_at(first_len).1, second.split_at(len - first_len).1)
     }
 }
 
-fn check_last_two(boundary: &[u8]) -> bool {
-    let len = boundary.len();
-
-    let is_end = boundary.ends_with(b"--");
-
-    if !is_end && !boundary.ends_with(b"\r\n") && boundary.len() > 2 {
-        warn!("unexpected bytes after boundary: {:?} ('--': {:?}, '\\r\\n': {:?})",
-              &boundary[len - 2 ..], b"--", b"\r\n");
-    }
-
-    is_end
+/// The state of skipping the padding and line ending after a boundary in lenient mode.
+#[derive(Copy, Clone, Debug, Default)]
+struct Padding {
+    /// Set once a space or tab has been skipped, so it is only logged once.
+    padded: bool,
+    /// Set if the last byte skipped was a CR.
+    cr: bool,
 }
 
 /// Check if `needle` is cut off at the end of `haystack`, and if so, its index
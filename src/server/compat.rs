@@ -0,0 +1,38 @@
+// Copyright 2017 `multipart-async` Crate Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+//! Compatibility with the futures 0.1 `Stream` and `Future` APIs, to ease migration.
+//! Enabled with the `futures01` feature.
+//!
+//! `FieldData` and `ReadTextField` can be adapted with `TryStreamExt::compat()` and
+//! `TryFutureExt::compat()` from `futures::compat`, respectively.
+use futures::TryStream;
+use futures::compat::{Compat, Compat01As03};
+
+use crate::{BodyChunk, StreamError};
+
+use super::Multipart;
+
+/// A futures 0.1 body stream adapted to the futures 0.3 `Stream` API.
+pub type Compat01Body<S> = Compat01As03<S>;
+
+impl<S: futures01::Stream> Multipart<Compat01Body<S>> where S::Item: BodyChunk, S::Error: StreamError {
+    /// Construct a new `Multipart` with a futures 0.1 body stream and the given boundary.
+    ///
+    /// See `with_body()` for details.
+    pub fn with_body01<B: Into<String>>(stream: S, boundary: B) -> Self {
+        Multipart::with_body(Compat01As03::new(stream), boundary)
+    }
+}
+
+impl<S: TryStream + Unpin> Multipart<S> where S::Ok: BodyChunk, S::Error: StreamError {
+    /// Adapt this `Multipart` into a futures 0.1 `Stream` of `Field`s.
+    ///
+    /// The returned stream must be polled from within a futures 0.1 task.
+    pub fn compat(self) -> Compat<Self> {
+        Compat::new(self)
+    }
+}
@@ -0,0 +1,504 @@
+// Copyright 2017 `multipart-async` Crate Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+//! Deserializing a whole request into a type implementing `serde::Deserialize`.
+use serde::de::value::SeqDeserializer;
+use serde::de::{self, Deserialize, DeserializeOwned, DeserializeSeed, IntoDeserializer, MapAccess,
+                Visitor};
+use serde::forward_to_deserialize_any;
+
+use std::cell::RefCell;
+use std::marker::PhantomData;
+use std::str::FromStr;
+use std::{error, fmt, vec};
+
+use crate::{BodyChunk, StreamError};
+
+use super::form::{CollectForm, Form};
+use super::{MultipartError, SavedField};
+
+use crate::helpers::*;
+
+/// The name `SavedField` passes to `deserialize_newtype_struct()`, so `ValueDeserializer`
+/// knows to hand over the field.
+const SAVED_FIELD: &str = "$multipart_async::SavedField";
+
+thread_local! {
+    // `Visitor` can only receive values from the serde data model, so the `SavedField`
+    // is passed to its `Deserialize` impl out of band: `ValueDeserializer` puts it here
+    // right before calling `visit_unit()` and takes it back out right after, all within one
+    // synchronous call on this thread, so no other deserializer can ever see it. A
+    // `SavedField` deserialized by anything else finds the slot empty and fails.
+    static SAVED: RefCell<Option<SavedField>> = const { RefCell::new(None) };
+}
+
+/// An error deserializing the fields of a request, e.g. a missing field or a text field
+/// which could not be parsed.
+///
+/// Returned by `DeserializeForm` as `MultipartError::Deserialize`, converted to the error type
+/// of the body stream with `StreamError::from_multipart()`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DeError(String);
+
+impl fmt::Display for DeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl error::Error for DeError {}
+
+impl de::Error for DeError {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        DeError(msg.to_string())
+    }
+}
+
+/// A `Future` which reads all the fields of a request and deserializes them into a `T`,
+/// returned by `Multipart::deserialize()`.
+///
+/// The request is collected as with `Multipart::collect_form()`, so text fields are subject
+/// to the length limit of `ReadTextField` and file fields are saved as with
+/// `FieldData::save()`. Then, each field name is given to `T` as a key of a map:
+///
+/// * text fields are parsed with `FromStr` to numbers and `char`s; `true`, `on` and `1`
+///   are accepted for `bool`s, as are `false`, `off` and `0`;
+/// * `Option<_>` is `None` if the field is missing or empty;
+/// * `Vec<_>` and other sequences collect all the fields with the same name, in order;
+/// * enums may be deserialized from text fields naming a unit variant;
+/// * file fields can only be deserialized to `SavedField`.
+///
+/// Any other type expecting a single value will fail if more than one field has its name.
+pub struct DeserializeForm<S: TryStream + Unpin, T> where S::Ok: BodyChunk, S::Error: StreamError {
+    collect: CollectForm<S>,
+    _marker: PhantomData<fn() -> T>,
+}
+
+pub fn deserialize_form<S: TryStream + Unpin, T>(collect: CollectForm<S>) -> DeserializeForm<S, T>
+where S::Ok: BodyChunk, S::Error: StreamError {
+    DeserializeForm { collect, _marker: PhantomData }
+}
+
+impl<S: TryStream + Unpin, T: DeserializeOwned> Future for DeserializeForm<S, T>
+where S::Ok: BodyChunk + Unpin, S::Error: StreamError {
+    type Output = Result<T, S::Error>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let form = ready!(Pin::new(&mut self.get_mut().collect).poll(cx)?);
+        Poll::Ready(deserialize(form).map_err(|e| S::Error::from_multipart(MultipartError::Deserialize(e))))
+    }
+}
+
+impl<S: TryStream + Unpin, T> fmt::Debug for DeserializeForm<S, T> where S::Ok: BodyChunk, S::Error: StreamError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("DeserializeForm")
+            .field("collect", &self.collect)
+            .finish()
+    }
+}
+
+fn deserialize<T: DeserializeOwned>(form: Form) -> Result<T, DeError> {
+    let mut entries = Vec::with_capacity(form.texts.len() + form.files.len());
+
+    for (name, files) in form.files {
+        if form.texts.contains_key(&name) {
+            return Err(de::Error::custom(format_args!("field {:?} has both text and file values", name)));
+        }
+
+        entries.push((name, files.into_iter().map(Value::File).collect()));
+    }
+
+    entries.extend(form.texts.into_iter().map(|(name, texts)| {
+        (name, texts.into_iter().map(|field| Value::Text(field.text)).collect())
+    }));
+
+    T::deserialize(FormDeserializer { entries: entries.into_iter(), value: None })
+}
+
+impl<'de> Deserialize<'de> for SavedField {
+    fn deserialize<D: de::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct SavedFieldVisitor;
+
+        impl<'de> Visitor<'de> for SavedFieldVisitor {
+            type Value = SavedField;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a file field of a multipart request")
+            }
+
+            fn visit_unit<E: de::Error>(self) -> Result<SavedField, E> {
+                SAVED.with(|saved| saved.borrow_mut().take())
+                    .ok_or_else(|| E::custom("`SavedField` can only be deserialized from a multipart request"))
+            }
+        }
+
+        deserializer.deserialize_newtype_struct(SAVED_FIELD, SavedFieldVisitor)
+    }
+}
+
+enum Value {
+    Text(String),
+    File(SavedField),
+}
+
+/// Presents the fields of a `Form` as a map from names to values.
+struct FormDeserializer {
+    entries: vec::IntoIter<(String, Vec<Value>)>,
+    value: Option<ValuesDeserializer>,
+}
+
+impl<'de> de::Deserializer<'de> for FormDeserializer {
+    type Error = DeError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeError> {
+        visitor.visit_map(self)
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string bytes byte_buf
+        option unit unit_struct newtype_struct seq tuple tuple_struct map struct enum identifier
+        ignored_any
+    }
+}
+
+impl<'de> MapAccess<'de> for FormDeserializer {
+    type Error = DeError;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(&mut self, seed: K) -> Result<Option<K::Value>, DeError> {
+        let (name, values) = match self.entries.next() {
+            Some(entry) => entry,
+            None => return Ok(None),
+        };
+
+        let key = seed.deserialize(name.as_str().into_deserializer())?;
+        self.value = Some(ValuesDeserializer { name, values });
+        Ok(Some(key))
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value, DeError> {
+        seed.deserialize(self.value.take().expect("next_value_seed() called before next_key_seed()"))
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.entries.len())
+    }
+}
+
+/// All the values of the fields with one name.
+struct ValuesDeserializer {
+    name: String,
+    values: Vec<Value>,
+}
+
+impl ValuesDeserializer {
+    fn single(mut self) -> Result<ValueDeserializer, DeError> {
+        if self.values.len() != 1 {
+            return Err(de::Error::custom(format_args!("expected one value for field {:?}, found {}",
+                                                      self.name, self.values.len())));
+        }
+
+        let value = self.values.pop().expect("values.len() == 1");
+        Ok(ValueDeserializer { name: self.name, value })
+    }
+}
+
+macro_rules! forward_to_single {
+    ($($method:ident),*) => ($(
+        fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeError> {
+            self.single()?.$method(visitor)
+        }
+    )*)
+}
+
+impl<'de> de::Deserializer<'de> for ValuesDeserializer {
+    type Error = DeError;
+
+    forward_to_single!(deserialize_any, deserialize_bool, deserialize_i8, deserialize_i16,
+                       deserialize_i32, deserialize_i64, deserialize_i128, deserialize_u8,
+                       deserialize_u16, deserialize_u32, deserialize_u64, deserialize_u128,
+                       deserialize_f32, deserialize_f64, deserialize_char, deserialize_str,
+                       deserialize_string, deserialize_bytes, deserialize_byte_buf,
+                       deserialize_option, deserialize_unit, deserialize_map,
+                       deserialize_identifier, deserialize_ignored_any);
+
+    fn deserialize_unit_struct<V: Visitor<'de>>(self, name: &'static str, visitor: V) -> Result<V::Value, DeError> {
+        self.single()?.deserialize_unit_struct(name, visitor)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(self, name: &'static str, visitor: V) -> Result<V::Value, DeError> {
+        self.single()?.deserialize_newtype_struct(name, visitor)
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeError> {
+        let name = self.name;
+        let values = self.values.into_iter().map(|value| ValueDeserializer { name: name.clone(), value });
+        let mut seq = SeqDeserializer::new(values);
+        let value = visitor.visit_seq(&mut seq)?;
+        seq.end()?;
+        Ok(value)
+    }
+
+    fn deserialize_tuple<V: Visitor<'de>>(self, _len: usize, visitor: V) -> Result<V::Value, DeError> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_tuple_struct<V: Visitor<'de>>(self, _name: &'static str, _len: usize, visitor: V)
+        -> Result<V::Value, DeError> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(self, name: &'static str, fields: &'static [&'static str], visitor: V)
+        -> Result<V::Value, DeError> {
+        self.single()?.deserialize_struct(name, fields, visitor)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(self, name: &'static str, variants: &'static [&'static str], visitor: V)
+        -> Result<V::Value, DeError> {
+        self.single()?.deserialize_enum(name, variants, visitor)
+    }
+}
+
+/// The value of a single field.
+struct ValueDeserializer {
+    name: String,
+    value: Value,
+}
+
+impl ValueDeserializer {
+    fn text(self) -> Result<String, DeError> {
+        match self.value {
+            Value::Text(text) => Ok(text),
+            Value::File(_) => Err(de::Error::custom(format_args!(
+                "field {:?} is a file, which can only be deserialized to `SavedField`", self.name))),
+        }
+    }
+
+    fn parse<T: FromStr>(self) -> Result<T, DeError> where T::Err: fmt::Display {
+        let name = self.name.clone();
+        let text = self.text()?;
+
+        text.trim().parse().map_err(|e| de::Error::custom(format_args!(
+            "invalid value {:?} for field {:?}: {}", text, name, e)))
+    }
+}
+
+impl<'de> IntoDeserializer<'de, DeError> for ValueDeserializer {
+    type Deserializer = Self;
+
+    fn into_deserializer(self) -> Self {
+        self
+    }
+}
+
+macro_rules! deserialize_parse {
+    ($($method:ident => $visit:ident),*) => ($(
+        fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeError> {
+            visitor.$visit(self.parse()?)
+        }
+    )*)
+}
+
+impl<'de> de::Deserializer<'de> for ValueDeserializer {
+    type Error = DeError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeError> {
+        visitor.visit_string(self.text()?)
+    }
+
+    fn deserialize_bool<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeError> {
+        let name = self.name.clone();
+        let text = self.text()?;
+
+        match text.trim() {
+            "true" | "on" | "1" => visitor.visit_bool(true),
+            "false" | "off" | "0" => visitor.visit_bool(false),
+            _ => Err(de::Error::custom(format_args!("invalid value {:?} for field {:?}: expected a boolean",
+                                                    text, name))),
+        }
+    }
+
+    deserialize_parse!(deserialize_i8 => visit_i8, deserialize_i16 => visit_i16,
+                       deserialize_i32 => visit_i32, deserialize_i64 => visit_i64,
+                       deserialize_i128 => visit_i128, deserialize_u8 => visit_u8,
+                       deserialize_u16 => visit_u16, deserialize_u32 => visit_u32,
+                       deserialize_u64 => visit_u64, deserialize_u128 => visit_u128,
+                       deserialize_f32 => visit_f32, deserialize_f64 => visit_f64,
+                       deserialize_char => visit_char);
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeError> {
+        match self.value {
+            Value::Text(ref text) if text.is_empty() => visitor.visit_none(),
+            _ => visitor.visit_some(self),
+        }
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(self, name: &'static str, visitor: V) -> Result<V::Value, DeError> {
+        if name != SAVED_FIELD {
+            return visitor.visit_newtype_struct(self);
+        }
+
+        match self.value {
+            Value::File(file) => {
+                SAVED.with(|saved| *saved.borrow_mut() = Some(file));
+                let res = visitor.visit_unit();
+                // don't leave the file in the slot if the visitor failed
+                SAVED.with(|saved| saved.borrow_mut().take());
+                res
+            },
+            Value::Text(_) => Err(de::Error::custom(format_args!(
+                "expected a file for field {:?}, found a text field", self.name))),
+        }
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(self, _name: &'static str, _variants: &'static [&'static str], visitor: V)
+        -> Result<V::Value, DeError> {
+        visitor.visit_enum(self.text()?.into_deserializer())
+    }
+
+    forward_to_deserialize_any! {
+        str string bytes byte_buf unit unit_struct seq tuple tuple_struct map struct identifier
+        ignored_any
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use futures::executor::block_on;
+    use serde::Deserialize;
+
+    use std::{fs, io};
+
+    use crate::server::{Multipart, MultipartError, SavedField};
+
+    #[derive(Debug, Deserialize, PartialEq)]
+    #[serde(rename_all = "lowercase")]
+    enum Visibility {
+        Public,
+        Private,
+    }
+
+    #[derive(Debug, Deserialize)]
+    struct UploadForm {
+        title: String,
+        count: u32,
+        ratio: f64,
+        public: bool,
+        visibility: Visibility,
+        tags: Vec<String>,
+        note: Option<String>,
+        empty: Option<u32>,
+        upload: SavedField,
+    }
+
+    #[test]
+    fn test_deserialize() {
+        let stream = crate::mock_stream!(
+            "--boundary\r\n\
+             Content-Disposition: form-data; name=\"title\"\r\n\r\n\
+             Holiday\r\n\
+             --boundary\r\n\
+             Content-Disposition: form-data; name=\"count\"\r\n\r\n\
+             42\r\n\
+             --boundary\r\n\
+             Content-Disposition: form-data; name=\"ratio\"\r\n\r\n\
+             0.5\r\n\
+             --boundary\r\n\
+             Content-Disposition: form-data; name=\"public\"\r\n\r\n\
+             on\r\n\
+             --boundary\r\n\
+             Content-Disposition: form-data; name=\"visibility\"\r\n\r\n\
+             private\r\n\
+             --boundary\r\n\
+             Content-Disposition: form-data; name=\"tags\"\r\n\r\n\
+             beach\r\n\
+             --boundary\r\n\
+             Content-Disposition: form-data; name=\"tags\"\r\n\r\n\
+             sun\r\n\
+             --boundary\r\n\
+             Content-Disposition: form-data; name=\"empty\"\r\n\r\n\
+             \r\n\
+             --boundary\r\n\
+             Content-Disposition: form-data; name=\"upload\"; filename=\"photo.txt\"\r\n\r\n\
+             file data\r\n\
+             --boundary--"
+        );
+
+        let form: UploadForm = block_on(Multipart::with_body(stream, "boundary").deserialize()).unwrap();
+
+        assert_eq!(form.title, "Holiday");
+        assert_eq!(form.count, 42);
+        assert_eq!(form.ratio, 0.5);
+        assert!(form.public);
+        assert_eq!(form.visibility, Visibility::Private);
+        assert_eq!(form.tags, ["beach", "sun"]);
+        assert_eq!(form.note, None);
+        assert_eq!(form.empty, None);
+
+        assert_eq!(form.upload.headers.filename.as_ref().unwrap(), "photo.txt");
+        assert_eq!(fs::read(&form.upload.path).unwrap(), b"file data");
+
+        let path = form.upload.path.clone();
+        drop(form);
+        assert!(!path.exists());
+    }
+
+    macro_rules! deserialize_err {
+        ($ty:ty, $body:expr) => ({
+            let stream = crate::mock_stream!($body);
+            block_on(Multipart::with_body(stream, "boundary").deserialize::<$ty>()).map(drop).map_err(String::from).unwrap_err()
+        })
+    }
+
+    #[test]
+    fn test_deserialize_errors() {
+        #[derive(Debug, Deserialize)]
+        #[allow(dead_code)]
+        struct Count { count: u32 }
+
+        #[derive(Debug, Deserialize)]
+        #[allow(dead_code)]
+        struct Upload { upload: SavedField }
+
+        let err = deserialize_err!(Count,
+            "--boundary\r\n\
+             Content-Disposition: form-data; name=\"count\"\r\n\r\n\
+             many\r\n\
+             --boundary--");
+        assert!(err.contains("invalid value \"many\" for field \"count\""), "{}", err);
+
+        let err = deserialize_err!(Count,
+            "--boundary\r\n\
+             Content-Disposition: form-data; name=\"count\"\r\n\r\n\
+             1\r\n\
+             --boundary\r\n\
+             Content-Disposition: form-data; name=\"count\"\r\n\r\n\
+             2\r\n\
+             --boundary--");
+        assert!(err.contains("expected one value for field \"count\""), "{}", err);
+
+        let err = deserialize_err!(Count, "--boundary--");
+        assert!(err.contains("missing field `count`"), "{}", err);
+
+        let err = deserialize_err!(Upload,
+            "--boundary\r\n\
+             Content-Disposition: form-data; name=\"upload\"\r\n\r\n\
+             text\r\n\
+             --boundary--");
+        assert!(err.contains("expected a file for field \"upload\""), "{}", err);
+    }
+
+    #[test]
+    fn test_deserialize_error_kind() {
+        #[derive(Debug, Deserialize)]
+        #[allow(dead_code)]
+        struct Count { count: u32 }
+
+        let stream = futures::stream::iter(vec![Ok::<_, io::Error>(&b"--boundary--"[..])]);
+        let err = block_on(Multipart::with_body(stream, "boundary").deserialize::<Count>()).unwrap_err();
+        let multipart = MultipartError::from_io_error(&err).unwrap();
+        assert!(matches!(multipart, MultipartError::Deserialize(_)), "{:?}", multipart);
+    }
+}
@@ -0,0 +1,236 @@
+// Copyright 2017 `multipart-async` Crate Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+//! The errors of parsing a multipart request.
+use std::borrow::Cow;
+use std::error::Error;
+use std::str::Utf8Error;
+use std::{fmt, io};
+
+use crate::helpers::show_bytes;
+use crate::StreamError;
+
+use super::LimitExceeded;
+
+/// An error parsing a multipart request.
+///
+/// Errors found by the parser are converted to the error type of the body stream with
+/// `StreamError::from_multipart()`, which by default wraps them in an `io::Error` of kind
+/// `InvalidData`; use `from_io_error()` to get them back out.
+///
+/// Errors in the framing of the request, i.e. its boundaries and field headers, are wrapped in
+/// `WithContext` to tell where in the request they were found; use `without_context()` to match
+/// on the error itself.
+///
+/// This type also implements `StreamError` itself, so a body stream may use it as its error
+/// type by converting its own errors into the `Io` variant.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum MultipartError {
+    /// A boundary was malformed, or there was unexpected data after the closing boundary.
+    Boundary(Cow<'static, str>),
+    /// The headers of a field could not be parsed or were invalid.
+    Headers(Cow<'static, str>),
+    /// A field of a `multipart/form-data` request had no `Content-Disposition` header.
+    MissingContentDisposition,
+    /// One of the `Limits` of the request, or the length limit of `ReadTextField`, was broken.
+    LimitExceeded(LimitExceeded),
+    /// The data of a text field was not valid UTF-8.
+    Utf8(Utf8Error),
+    /// The data of a text field was not valid in its charset.
+    Charset(Cow<'static, str>),
+    /// The data of a field was invalid for its headers, e.g. malformed `base64` or a
+    /// mismatched `Content-MD5`.
+    InvalidData(Cow<'static, str>),
+    /// The body stream ended before the request was complete.
+    UnexpectedEof(Cow<'static, str>),
+    /// The fields of the request could not be deserialized by `DeserializeForm`.
+    #[cfg(feature = "serde")]
+    Deserialize(super::DeError),
+    /// An error of the body stream, or of I/O while reading the request.
+    Io(io::Error),
+    /// Any other error, as created by `StreamError::from_str()` or `from_string()`.
+    Other(Cow<'static, str>),
+    /// Another error with the position in the request where it was found.
+    WithContext {
+        /// The error that was found.
+        error: Box<MultipartError>,
+        /// Where it was found.
+        context: Box<ParseContext>,
+    },
+}
+
+impl MultipartError {
+    /// Get the `MultipartError` from an `io::Error` created by the default implementation of
+    /// `StreamError::from_multipart()`, if it is one.
+    pub fn from_io_error(err: &io::Error) -> Option<&MultipartError> {
+        err.get_ref().and_then(|err| err.downcast_ref())
+    }
+
+    /// The position in the request where this error was found, if known.
+    pub fn context(&self) -> Option<&ParseContext> {
+        match *self {
+            MultipartError::WithContext { ref context, .. } => Some(context),
+            _ => None,
+        }
+    }
+
+    /// This error without its `ParseContext`, if it has one.
+    pub fn without_context(&self) -> &MultipartError {
+        match *self {
+            MultipartError::WithContext { ref error, .. } => error.without_context(),
+            ref error => error,
+        }
+    }
+
+    /// The `LimitExceeded` carried by this error, if it is one.
+    pub fn limit_exceeded(&self) -> Option<&LimitExceeded> {
+        match *self.without_context() {
+            MultipartError::LimitExceeded(ref err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for MultipartError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::MultipartError::*;
+
+        match *self {
+            Boundary(ref msg) => write!(f, "invalid multipart boundary: {}", msg),
+            Headers(ref msg) => write!(f, "invalid field headers: {}", msg),
+            MissingContentDisposition =>
+                f.write_str("missing `Content-Disposition` header on a field of a multipart/form-data request"),
+            LimitExceeded(ref err) => err.fmt(f),
+            Utf8(ref err) => write!(f, "text field is not valid UTF-8: {}", err),
+            Charset(ref msg) | InvalidData(ref msg) | Other(ref msg) => f.write_str(msg),
+            UnexpectedEof(ref msg) => write!(f, "unexpected end of stream {}", msg),
+            #[cfg(feature = "serde")]
+            Deserialize(ref err) => err.fmt(f),
+            Io(ref err) => err.fmt(f),
+            WithContext { ref error, ref context } => write!(f, "{} ({})", error, context),
+        }
+    }
+}
+
+impl Error for MultipartError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            MultipartError::LimitExceeded(ref err) => Some(err),
+            MultipartError::Utf8(ref err) => Some(err),
+            #[cfg(feature = "serde")]
+            MultipartError::Deserialize(ref err) => Some(err),
+            MultipartError::Io(ref err) => Some(err),
+            MultipartError::WithContext { ref error, .. } => error.source(),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for MultipartError {
+    fn from(err: io::Error) -> Self {
+        MultipartError::Io(err)
+    }
+}
+
+impl StreamError for MultipartError {
+    fn from_str(str: &'static str) -> Self {
+        MultipartError::Other(str.into())
+    }
+
+    fn from_string(string: String) -> Self {
+        MultipartError::Other(string.into())
+    }
+
+    fn from_multipart(err: MultipartError) -> Self {
+        err
+    }
+}
+
+/// The number of bytes of the request kept in `ParseContext::excerpt`.
+const EXCERPT_LEN: usize = 64;
+
+/// The position in a request where a `MultipartError` was found.
+///
+/// For the parts of a nested multipart body, positions are relative to the data of the
+/// enclosing field.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct ParseContext {
+    /// The offset of the error in the request body, in bytes.
+    pub offset: u64,
+    /// The index of the field being read, counting from zero, or `None` if the error was found
+    /// outside of any field, in the preamble or the epilogue.
+    pub field_index: Option<u64>,
+    /// The name of the field being read, if its headers have been read.
+    pub field_name: Option<String>,
+    /// Up to 64 bytes of the request starting at `offset`; shown as ASCII with other bytes
+    /// in hexadecimal when this is displayed.
+    pub excerpt: Vec<u8>,
+}
+
+impl ParseContext {
+    pub(crate) fn new(offset: u64, field_index: Option<u64>, field_name: Option<&str>, bytes: &[u8]) -> Self {
+        ParseContext {
+            offset,
+            field_index,
+            field_name: field_name.map(Into::into),
+            excerpt: bytes[..bytes.len().min(EXCERPT_LEN)].to_vec(),
+        }
+    }
+}
+
+impl fmt::Display for ParseContext {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "at byte {}", self.offset)?;
+
+        match (self.field_index, &self.field_name) {
+            (Some(index), Some(name)) => write!(f, " in field {} {:?}", index, name)?,
+            (Some(index), None) => write!(f, " in field {}", index)?,
+            (None, _) => f.write_str(" outside of any field")?,
+        }
+
+        write!(f, ", near: {}", show_bytes(&self.excerpt))
+    }
+}
+
+pub fn multipart_err<T, E: StreamError>(err: MultipartError) -> Result<T, E> {
+    Err(E::from_multipart(err))
+}
+
+/// Attach `context` to `err` and convert it, except for limit errors which always go through
+/// `StreamError::from_limit()`.
+pub fn context_err<T, E: StreamError>(err: MultipartError, context: ParseContext) -> Result<T, E> {
+    match err {
+        MultipartError::LimitExceeded(err) => Err(E::from_limit(err)),
+        err => multipart_err(MultipartError::WithContext { error: Box::new(err), context: Box::new(context) }),
+    }
+}
+
+#[test]
+fn test_from_io_error() {
+    use super::LimitKind;
+
+    let err: io::Error = multipart_err::<(), io::Error>(MultipartError::Headers("bad".into())).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    assert!(matches!(MultipartError::from_io_error(&err), Some(MultipartError::Headers(_))));
+    assert_eq!(err.to_string(), "invalid field headers: bad");
+
+    let err: io::Error = io::Error::from_limit(LimitExceeded { kind: LimitKind::Fields, limit: 2 });
+    let limit = MultipartError::from_io_error(&err).and_then(MultipartError::limit_exceeded);
+    assert_eq!(limit.map(|limit| limit.kind), Some(LimitKind::Fields));
+
+    assert!(MultipartError::from_io_error(&io::Error::other("other")).is_none());
+
+    let context = ParseContext::new(12, Some(1), None, b"--bound\r\n");
+    let err: io::Error = context_err::<(), io::Error>(MultipartError::Boundary("bad".into()), context)
+        .unwrap_err();
+    let multipart = MultipartError::from_io_error(&err).unwrap();
+    assert!(matches!(multipart.without_context(), MultipartError::Boundary(_)));
+    assert_eq!(multipart.context().map(|context| context.offset), Some(12));
+    assert_eq!(err.to_string(), format!("invalid multipart boundary: bad (at byte 12 in field 1, near: {})",
+                                        show_bytes(b"--bound\r\n")));
+}
@@ -1,27 +1,25 @@
-use futures::{Future, Stream};
-use futures::Async::*;
-
-use std::rc::Rc;
 use std::{fmt, str};
 
-use {BodyChunk, StreamError};
+#[cfg(feature = "encoding_rs")]
+use encoding_rs::{Decoder, DecoderResult, Encoding};
+
+use crate::{BodyChunk, StreamError};
+
+use crate::server::limits::{limit_err, LimitKind};
+use crate::server::sync::Shared;
 
 use super::FieldHeaders;
 
-use helpers::*;
+use crate::helpers::*;
 
+#[derive(Default)]
 enum ChunkStack<C> {
+    #[default]
     Empty,
     One(C),
     Two(C, C),
 }
 
-impl<C> Default for ChunkStack<C> {
-    fn default() -> Self {
-        ChunkStack::Empty
-    }
-}
-
 impl<C: BodyChunk> ChunkStack<C> {
     /// Push a chunk onto the stack
     fn push(&mut self, chunk: C) {
@@ -67,7 +65,7 @@ impl<C: BodyChunk> fmt::Debug for ChunkStack<C> {
 #[derive(Clone, Debug)]
 pub struct TextField {
     /// The headers for the original field, provided as a convenience.
-    pub headers: Rc<FieldHeaders>,
+    pub headers: Shared<FieldHeaders>,
     /// The text of the field.
     pub text: String,
 }
@@ -75,15 +73,12 @@ pub struct TextField {
 /// A `Future` which attempts to read a field's data to a string.
 ///
 /// ### Charset
-/// For simplicity, the default UTF-8 character set is assumed, as defined in
+/// By default, the UTF-8 character set is assumed, as defined in
 /// [IETF RFC 7578 Section 5.1.2](https://tools.ietf.org/html/rfc7578#section-5.1.2).
 /// If the field body cannot be decoded as UTF-8, an error is returned.
 ///
-/// Decoding text in a different charset (except ASCII which is compatible with UTF-8) is,
-/// currently, beyond the scope of this crate. However, as a convention, web browsers will send
-/// `multipart/form-data` requests in the same charset as that of the document (page or frame)
-/// containing the form, so if you only serve ASCII/UTF-8 pages then you won't have to worry
-/// too much about decoding strange charsets.
+/// With the `encoding_rs` feature, `FieldData::read_text()` picks the charset of the field
+/// or request instead, and `charset()` can override it.
 ///
 /// ### Warning About Leaks
 /// If this value or the contained `FieldData` is leaked (via `mem::forget()` or some
@@ -91,12 +86,15 @@ pub struct TextField {
 /// stream. The task waiting on the `Multipart` will also never be notified, which, depending on the
 /// event loop/reactor/executor implementation, may cause a deadlock.
 #[derive(Default)]
-pub struct ReadTextField<S: Stream> {
+pub struct ReadTextField<S: TryStream> {
     stream: Option<S>,
     accum: String,
-    chunks: ChunkStack<S::Item>,
+    chunks: ChunkStack<S::Ok>,
+    /// Set if the text is in a charset other than UTF-8.
+    #[cfg(feature = "encoding_rs")]
+    decoder: Option<Decoder>,
     /// The headers for the original field, provided as a convenience.
-    pub headers: Rc<FieldHeaders>,
+    pub headers: Shared<FieldHeaders>,
     /// The length limit for the string, in bytes, to avoid potential DoS attacks from
     /// attackers running the server out of memory. If an incoming chunk is expected to push the
     /// string over this limit, an error is returned and the offending chunk is pushed back
@@ -108,14 +106,16 @@ pub struct ReadTextField<S: Stream> {
 const DEFAULT_LIMIT: usize = 65536; // 65KiB--reasonable enough for one text field, right?
 const MAX_LIMIT: usize = 16_777_216; // 16MiB--highest sane value for one text field, IMO
 
-pub fn read_text<S: Stream>(headers: Rc<FieldHeaders>, data: S) -> ReadTextField<S> {
+pub fn read_text<S: TryStream>(headers: Shared<FieldHeaders>, data: S) -> ReadTextField<S> {
     ReadTextField {
         headers, stream: Some(data), limit: DEFAULT_LIMIT, accum: String::new(),
-        chunks: Default::default()
+        chunks: Default::default(),
+        #[cfg(feature = "encoding_rs")]
+        decoder: None,
     }
 }
 
-impl<S: Stream> ReadTextField<S> {
+impl<S: TryStream> ReadTextField<S> {
     /// Set the length limit, in bytes, for the collected text. If an incoming chunk is expected to
     /// push the string over this limit, an error is returned and the offending chunk is pushed back
     /// to the head of the stream.
@@ -131,6 +131,20 @@ impl<S: Stream> ReadTextField<S> {
         Self { limit, .. self}
     }
 
+    /// Decode the text with the given charset instead of UTF-8.
+    ///
+    /// This should be set before the future is first polled.
+    #[cfg(feature = "encoding_rs")]
+    pub fn charset(self, encoding: &'static Encoding) -> Self {
+        let decoder = if encoding == encoding_rs::UTF_8 {
+            None
+        } else {
+            Some(encoding.new_decoder_without_bom_handling())
+        };
+
+        Self { decoder, .. self }
+    }
+
     /// Soft max limit if the default isn't large enough.
     ///
     /// Going higher than this is allowed, but not recommended.
@@ -160,57 +174,69 @@ impl<S: Stream> ReadTextField<S> {
     }
 }
 
-impl<S: Stream> ReadTextField<S> where S::Item: BodyChunk {
-    fn next_chunk(&mut self) -> PollOpt<S::Item, S::Error> {
+impl<S: TryStream + Unpin> ReadTextField<S> where S::Ok: BodyChunk {
+    fn next_chunk(&mut self, cx: &mut Context) -> PollOpt<S::Ok, S::Error> {
         if let Some(chunk) = self.chunks.pop() {
-            return ready(Some(chunk));
+            return ready_some(chunk);
         }
 
         if let Some(ref mut stream) = self.stream {
-            stream.poll()
+            poll_next(stream, cx)
         } else {
-            ready(None)
+            Poll::Ready(None)
         }
     }
 
     /// Try to poll for another chunk; if successful, return both of them, otherwise push the first
     /// chunk back.
-    fn another_chunk(&mut self, first: S::Item) -> PollOpt<(S::Item, S::Item), S::Error> {
-        match self.next_chunk() {
-            Ok(Ready(Some(second))) => ready(Some((first, second))),
-            Ok(Ready(None)) => ready(None),
-            Ok(NotReady) => { self.chunks.push(first); not_ready() }
-            Err(e) => { self.chunks.push(first); Err(e) },
+    fn another_chunk(&mut self, first: S::Ok, cx: &mut Context) -> PollOpt<(S::Ok, S::Ok), S::Error> {
+        match self.next_chunk(cx) {
+            Poll::Ready(Some(Ok(second))) => ready_some((first, second)),
+            Poll::Ready(None) => Poll::Ready(None),
+            Poll::Pending => { self.chunks.push(first); Poll::Pending }
+            Poll::Ready(Some(Err(e))) => { self.chunks.push(first); Poll::Ready(Some(Err(e))) },
         }
     }
 }
 
-impl<S: Stream> Future for ReadTextField<S> where S::Item: BodyChunk, S::Error: StreamError {
-    type Item = TextField;
-    type Error = S::Error;
+impl<S: TryStream + Unpin> Future for ReadTextField<S> where S::Ok: BodyChunk + Unpin, S::Error: StreamError {
+    type Output = Result<TextField, S::Error>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        self.get_mut().poll_text(cx)
+    }
+}
+
+impl<S: TryStream + Unpin> ReadTextField<S> where S::Ok: BodyChunk, S::Error: StreamError {
+    fn poll_text(&mut self, cx: &mut Context) -> Poll<Result<TextField, S::Error>> {
+        #[cfg(feature = "encoding_rs")]
+        {
+            if self.decoder.is_some() {
+                return self.poll_decode(cx);
+            }
+        }
 
-    fn poll(&mut self) -> Poll<Self::Item, S::Error> {
         loop {
-            let chunk = match try_ready!(self.next_chunk()) {
+            let chunk = match ready!(self.next_chunk(cx)?) {
                 Some(val) => val,
                 _ => break,
             };
 
             // This also catches capacity overflows
-            if self.accum.len().checked_add(chunk.len()).map_or(true, |len| len > self.limit) {
+            if self.accum.len().checked_add(chunk.len()).is_none_or(|len| len > self.limit) {
                 self.chunks.push(chunk);
-                ret_err!("text field {:?} exceeded limit of {} bytes", self.headers, self.limit);
+                return Poll::Ready(self.limit_err());
             }
 
             // Try to convert the chunk to UTF-8 and append it to the accumulator
             let split_idx = match str::from_utf8(chunk.as_slice()) {
                 Ok(s) => { self.accum.push_str(s); continue },
-                Err(e) => if e.valid_up_to() > chunk.len() - 4 {
+                Err(e) => if e.error_len().is_none() {
                     // this may just be a valid sequence split across two chunks
                     e.valid_up_to()
                 } else {
                     // definitely was an invalid byte sequence
-                    return utf8_err(e);
+                    return Poll::Ready(utf8_err(e));
                 },
             };
 
@@ -224,28 +250,28 @@ impl<S: Stream> Future for ReadTextField<S> where S::Item: BodyChunk, S::Error:
             let needed_len =  char_width - invalid.len();
 
             // Get a second chunk or push the first chunk back
-            let (first, second) = match try_ready!(self.another_chunk(invalid)) {
+            let (first, second) = match ready!(self.another_chunk(invalid, cx)?) {
                 Some(pair) => pair,
                 // this also happens if we have some invalid bytes right at the end of the string
                 // should be rare and the end result is the same
-                None => ret_err!("unexpected end of stream while decoding a UTF-8 sequence"),
+                None => return Poll::Ready(fmt_err!(UnexpectedEof, "while decoding a UTF-8 sequence")),
             };
 
             if second.len() < needed_len {
-                ret_err!("got a chunk smaller than the {} byte(s) needed to finish \
-                          decoding this UTF-8 sequence: {:?}",
-                         needed_len, first.as_slice());
+                return Poll::Ready(fmt_err!(Charset, "got a chunk smaller than the {} byte(s) needed to \
+                                            finish decoding this UTF-8 sequence: {:?}",
+                                            needed_len, first.as_slice()));
             }
 
             let over_limit = self.accum.len().checked_add(first.len())
                 .and_then(|len| len.checked_add(second.len()))
-                .map_or(true, |len| len > self.limit);
+                .is_none_or(|len| len > self.limit);
 
             if over_limit {
                 // push chunks in reverse order
                 self.chunks.push(second);
                 self.chunks.push(first);
-                ret_err!("text field {:?} exceeded limit of {} bytes", self.headers, self.limit);
+                return Poll::Ready(self.limit_err());
             }
 
             let mut buf = [0u8; 4];
@@ -255,8 +281,9 @@ impl<S: Stream> Future for ReadTextField<S> where S::Item: BodyChunk, S::Error:
             buf[first.len()..].copy_from_slice(&second.as_slice()[..needed_len]);
 
             // if this fails we definitely got an invalid byte sequence
-            str::from_utf8(&buf[..char_width]).map(|s| self.accum.push_str(s))
-                .or_else(utf8_err)?;
+            if let Err(e) = str::from_utf8(&buf[..char_width]).map(|s| self.accum.push_str(s)) {
+                return Poll::Ready(utf8_err(e));
+            }
 
             let (_, rem) = second.split_at(needed_len);
 
@@ -265,18 +292,71 @@ impl<S: Stream> Future for ReadTextField<S> where S::Item: BodyChunk, S::Error:
             }
         }
 
+        self.finish()
+    }
+
+    /// Decode the text with `self.decoder`, which handles sequences split across chunks itself.
+    #[cfg(feature = "encoding_rs")]
+    fn poll_decode(&mut self, cx: &mut Context) -> Poll<Result<TextField, S::Error>> {
+        loop {
+            let chunk = ready!(self.next_chunk(cx)?);
+
+            if let Some(chunk) = chunk {
+                if self.accum.len().checked_add(chunk.len()).is_none_or(|len| len > self.limit) {
+                    self.chunks.push(chunk);
+                    return Poll::Ready(self.limit_err());
+                }
+
+                self.decode(chunk.as_slice(), false)?;
+            } else {
+                // flush the decoder; this fails if the text ends in an incomplete sequence
+                self.decode(&[], true)?;
+                return self.finish();
+            }
+        }
+    }
+
+    #[cfg(feature = "encoding_rs")]
+    fn decode(&mut self, mut src: &[u8], last: bool) -> Result<(), S::Error> {
+        let decoder = self.decoder.as_mut().expect("`decode()` called without a decoder");
+
+        loop {
+            let needed = decoder.max_utf8_buffer_length_without_replacement(src.len())
+                .unwrap_or(src.len());
+            self.accum.reserve(needed);
+
+            let (res, read) = decoder.decode_to_string_without_replacement(src, &mut self.accum,
+                                                                            last);
+            src = &src[read..];
+
+            match res {
+                DecoderResult::InputEmpty => return Ok(()),
+                DecoderResult::OutputFull => continue,
+                DecoderResult::Malformed(..) =>
+                    return fmt_err!(Charset, "invalid {} byte sequence in text field {:?}",
+                                    decoder.encoding().name(), self.headers),
+            }
+        }
+    }
+
+    fn limit_err(&self) -> Result<TextField, S::Error> {
+        debug!("text field {:?} exceeded limit of {} bytes", self.headers.name, self.limit);
+        limit_err(LimitKind::TextLength, self.limit as u64)
+    }
+
+    fn finish(&mut self) -> Poll<Result<TextField, S::Error>> {
         // Optimization: free the `FieldData` so the parent `Multipart` can yield
         // the next field.
         self.stream = None;
 
-        ready(TextField {
+        ready_ok(TextField {
             headers: self.headers.clone(),
             text: self.take_string(),
         })
     }
 }
 
-impl<S: Stream> fmt::Debug for ReadTextField<S> {
+impl<S: TryStream> fmt::Debug for ReadTextField<S> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         f.debug_struct("ReadFieldText")
             .field("accum", &self.accum)
@@ -309,5 +389,5 @@ static UTF8_CHAR_WIDTH: [u8; 256] = [
 
 #[inline]
 fn utf8_char_width(b: u8) -> usize {
-    return UTF8_CHAR_WIDTH[b as usize] as usize;
+    UTF8_CHAR_WIDTH[b as usize] as usize
 }
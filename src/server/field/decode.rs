@@ -0,0 +1,344 @@
+// Copyright 2017 `multipart-async` Crate Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+//! Decoding the `Content-Transfer-Encoding` of field data.
+use std::borrow::Cow;
+use std::fmt;
+
+use crate::{BodyChunk, StreamError};
+
+use crate::server::sync::Shared;
+
+use super::FieldHeaders;
+
+use crate::helpers::*;
+
+type DecodeResult = Result<(), Cow<'static, str>>;
+
+/// A `Stream` adaptor which decodes field data according to its `Content-Transfer-Encoding`,
+/// returned by `FieldData::decode()`.
+///
+/// `base64` and `quoted-printable` are decoded; `7bit`, `8bit` and `binary` (or no header at
+/// all) are passed through unchanged. Any other encoding, or malformed data, yields an error.
+pub struct DecodeField<S: TryStream> {
+    stream: S,
+    decoder: Decoder,
+    finished: bool,
+    /// The headers for the original field, provided as a convenience.
+    pub headers: Shared<FieldHeaders>,
+}
+
+pub fn decode<S: TryStream>(headers: Shared<FieldHeaders>, data: S) -> DecodeField<S> {
+    let decoder = match headers.transfer_encoding() {
+        None => Decoder::Identity,
+        Some(enc) if ["7bit", "8bit", "binary"].iter().any(|id| id.eq_ignore_ascii_case(enc)) =>
+            Decoder::Identity,
+        Some(enc) if enc.eq_ignore_ascii_case("base64") => Decoder::Base64(Base64::default()),
+        Some(enc) if enc.eq_ignore_ascii_case("quoted-printable") =>
+            Decoder::QuotedPrintable(QuotedPrintable::default()),
+        Some(enc) => Decoder::Unsupported(enc.to_string()),
+    };
+
+    DecodeField { stream: data, decoder, finished: false, headers }
+}
+
+impl<S: TryStream> DecodeField<S> {
+    /// The name of the encoding being decoded, e.g. `base64`.
+    pub fn encoding(&self) -> &str {
+        match self.decoder {
+            Decoder::Identity => "binary",
+            Decoder::Base64(_) => "base64",
+            Decoder::QuotedPrintable(_) => "quoted-printable",
+            Decoder::Unsupported(ref enc) => enc,
+        }
+    }
+}
+
+impl<S: TryStream + Unpin> Stream for DecodeField<S> where S::Ok: BodyChunk, S::Error: StreamError {
+    type Item = Result<Vec<u8>, S::Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+
+        if this.finished {
+            return Poll::Ready(None);
+        }
+
+        if let Decoder::Unsupported(ref enc) = this.decoder {
+            this.finished = true;
+            return Poll::Ready(Some(fmt_err!(Headers, "unsupported Content-Transfer-Encoding {:?} on field {:?}",
+                                             enc, this.headers.name)));
+        }
+
+        let mut out = Vec::new();
+
+        loop {
+            let res = match ready!(poll_next(&mut this.stream, cx)?) {
+                Some(chunk) => this.decoder.decode(chunk.as_slice(), &mut out),
+                None => {
+                    this.finished = true;
+                    this.decoder.finish()
+                },
+            };
+
+            if let Err(e) = res {
+                this.finished = true;
+                return Poll::Ready(Some(fmt_err!(InvalidData, "error decoding {} data of field {:?}: {}",
+                                                 this.encoding(), this.headers.name, e)));
+            }
+
+            if !out.is_empty() {
+                return ready_some(out);
+            }
+
+            if this.finished {
+                return Poll::Ready(None);
+            }
+        }
+    }
+}
+
+impl<S: TryStream> fmt::Debug for DecodeField<S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("DecodeField")
+            .field("headers", &self.headers)
+            .field("encoding", &self.encoding())
+            .field("finished", &self.finished)
+            .finish()
+    }
+}
+
+enum Decoder {
+    Identity,
+    Base64(Base64),
+    QuotedPrintable(QuotedPrintable),
+    Unsupported(String),
+}
+
+impl Decoder {
+    fn decode(&mut self, input: &[u8], out: &mut Vec<u8>) -> DecodeResult {
+        match *self {
+            Decoder::Identity => { out.extend_from_slice(input); Ok(()) },
+            Decoder::Base64(ref mut dec) => dec.decode(input, out),
+            Decoder::QuotedPrintable(ref mut dec) => dec.decode(input, out),
+            Decoder::Unsupported(_) => unreachable!("decoding unsupported encoding"),
+        }
+    }
+
+    fn finish(&self) -> DecodeResult {
+        match *self {
+            Decoder::Base64(ref dec) => dec.finish(),
+            Decoder::QuotedPrintable(ref dec) => dec.finish(),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Decode a complete base64 value, such as a `Content-MD5` header.
+#[cfg(feature = "digest")]
+pub fn decode_base64(input: &[u8]) -> Result<Vec<u8>, Cow<'static, str>> {
+    let mut base64 = Base64::default();
+    let mut out = Vec::new();
+    base64.decode(input, &mut out)?;
+    base64.finish()?;
+    Ok(out)
+}
+
+/// Base64 ([IETF RFC 2045 Section 6.8](https://tools.ietf.org/html/rfc2045#section-6.8)),
+/// ignoring line breaks and other whitespace.
+#[derive(Default)]
+struct Base64 {
+    // a quantum of 4 characters may be split across chunks
+    quantum: [u8; 4],
+    len: usize,
+    padding: usize,
+    done: bool,
+}
+
+impl Base64 {
+    fn decode(&mut self, input: &[u8], out: &mut Vec<u8>) -> DecodeResult {
+        for &byte in input {
+            if byte.is_ascii_whitespace() {
+                continue;
+            }
+
+            if self.done {
+                return Err("data after the end of base64 padding".into());
+            }
+
+            let val = match byte {
+                b'A' ..= b'Z' => byte - b'A',
+                b'a' ..= b'z' => byte - b'a' + 26,
+                b'0' ..= b'9' => byte - b'0' + 52,
+                b'+' => 62,
+                b'/' => 63,
+                b'=' if self.len >= 2 => {
+                    self.padding += 1;
+                    0
+                },
+                b'=' => return Err("unexpected base64 padding".into()),
+                _ => return Err(format!("invalid base64 character {:?}", byte as char).into()),
+            };
+
+            if self.padding > 0 && byte != b'=' {
+                return Err("data after the end of base64 padding".into());
+            }
+
+            self.quantum[self.len] = val;
+            self.len += 1;
+
+            if self.len == 4 {
+                let [a, b, c, d] = self.quantum;
+                let bytes = [a << 2 | b >> 4, b << 4 | c >> 2, c << 6 | d];
+                out.extend_from_slice(&bytes[..3 - self.padding]);
+
+                self.len = 0;
+                self.done = self.padding > 0;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn finish(&self) -> DecodeResult {
+        if self.len != 0 {
+            return Err("base64 data ended in an incomplete quantum".into());
+        }
+
+        Ok(())
+    }
+}
+
+/// Quoted-printable ([IETF RFC 2045 Section 6.7](https://tools.ietf.org/html/rfc2045#section-6.7)).
+///
+/// Lowercase hex digits and bare LF line breaks are also accepted.
+#[derive(Default)]
+struct QuotedPrintable {
+    state: QpState,
+    // whitespace at the end of a line is dropped, so it's held until we know it isn't
+    whitespace: Vec<u8>,
+}
+
+#[derive(Copy, Clone, Default)]
+enum QpState {
+    #[default]
+    Text,
+    /// After `=`
+    Escape,
+    /// After `=` and a hex digit
+    EscapeHex(u8),
+    /// After `=` and whitespace, which must be a soft line break
+    SoftBreak,
+    /// After `=` and CR
+    SoftBreakCr,
+}
+
+impl QuotedPrintable {
+    fn decode(&mut self, input: &[u8], out: &mut Vec<u8>) -> DecodeResult {
+        use self::QpState::*;
+
+        for &byte in input {
+            self.state = match (self.state, byte) {
+                (Text, b' ') | (Text, b'\t') => {
+                    self.whitespace.push(byte);
+                    Text
+                },
+                (Text, b'\r') | (Text, b'\n') => {
+                    self.whitespace.clear();
+                    out.push(byte);
+                    Text
+                },
+                (Text, b'=') => {
+                    out.append(&mut self.whitespace);
+                    Escape
+                },
+                (Text, _) => {
+                    out.append(&mut self.whitespace);
+                    out.push(byte);
+                    Text
+                },
+                (Escape, b'\r') | (SoftBreak, b'\r') => SoftBreakCr,
+                (Escape, b'\n') | (SoftBreak, b'\n') | (SoftBreakCr, b'\n') => Text,
+                (Escape, b' ') | (Escape, b'\t') | (SoftBreak, b' ') | (SoftBreak, b'\t') =>
+                    SoftBreak,
+                (Escape, _) => EscapeHex(hex_val(byte)?),
+                (EscapeHex(high), _) => {
+                    out.push(high << 4 | hex_val(byte)?);
+                    Text
+                },
+                (SoftBreak, _) | (SoftBreakCr, _) =>
+                    return Err(format!("unexpected {:?} in quoted-printable soft line break",
+                                       byte as char).into()),
+            };
+        }
+
+        Ok(())
+    }
+
+    fn finish(&self) -> DecodeResult {
+        match self.state {
+            // trailing whitespace and a final soft line break without CRLF are dropped
+            QpState::EscapeHex(_) => Err("quoted-printable data ended in an incomplete escape".into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+fn hex_val(byte: u8) -> Result<u8, Cow<'static, str>> {
+    match byte {
+        b'0' ..= b'9' => Ok(byte - b'0'),
+        b'A' ..= b'F' => Ok(byte - b'A' + 10),
+        b'a' ..= b'f' => Ok(byte - b'a' + 10),
+        _ => Err(format!("invalid quoted-printable escape {:?}", byte as char).into()),
+    }
+}
+
+#[cfg(test)]
+fn decode_chunks(mut decoder: Decoder, chunks: &[&[u8]]) -> Result<Vec<u8>, Cow<'static, str>> {
+    let mut out = Vec::new();
+
+    for chunk in chunks {
+        decoder.decode(chunk, &mut out)?;
+    }
+
+    decoder.finish()?;
+    Ok(out)
+}
+
+#[test]
+fn test_base64() {
+    let base64 = |chunks: &[&[u8]]| decode_chunks(Decoder::Base64(Base64::default()), chunks);
+
+    assert_eq!(base64(&[b"SGVsbG8sIHdvcmxkIQ=="]).unwrap(), b"Hello, world!");
+    assert_eq!(base64(&[b"SGVsbG8s\r\nIHdvcmxk"]).unwrap(), b"Hello, world");
+    // quanta and padding split across chunks
+    assert_eq!(base64(&[b"SG", b"VsbG8sIHdvcmxkIQ=", b"=\r\n"]).unwrap(), b"Hello, world!");
+    assert_eq!(base64(&[b"", b"YQ", b"", b"==", b""]).unwrap(), b"a");
+
+    assert!(base64(&[b"SGVsbG8"]).is_err());
+    assert!(base64(&[b"SGVs*G8s"]).is_err());
+    assert!(base64(&[b"YQ==YQ=="]).is_err());
+    assert!(base64(&[b"Y==="]).is_err());
+}
+
+#[test]
+fn test_quoted_printable() {
+    let qp = |chunks: &[&[u8]]| decode_chunks(Decoder::QuotedPrintable(QuotedPrintable::default()),
+                                              chunks);
+
+    assert_eq!(qp(&[b"na=C3=AFve caf=c3=a9"]).unwrap(), "na\u{EF}ve caf\u{E9}".as_bytes());
+    // soft line breaks, with and without transport padding
+    assert_eq!(qp(&[b"long=\r\nline= \t\r\nend"]).unwrap(), b"longlineend");
+    // trailing whitespace is dropped, other whitespace kept
+    assert_eq!(qp(&[b"a b  \r\nc\t"]).unwrap(), b"a b\r\nc");
+    // escapes and soft line breaks split across chunks
+    assert_eq!(qp(&[b"caf=", b"C", b"3=A9=\r", b"\n!"]).unwrap(), "caf\u{E9}!".as_bytes());
+    assert_eq!(qp(&[b"a ", b" ", b"b"]).unwrap(), b"a  b");
+
+    assert!(qp(&[b"=ZZ"]).is_err());
+    assert!(qp(&[b"=A"]).is_err());
+    assert!(qp(&[b"= x"]).is_err());
+}
@@ -0,0 +1,176 @@
+// Copyright 2017 `multipart-async` Crate Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+//! Sanitizing client-provided filenames.
+use unicode_normalization::UnicodeNormalization;
+
+/// Names reserved by Windows, with or without an extension.
+const RESERVED_NAMES: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL",
+    "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8", "COM9",
+    "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Characters not allowed in filenames on Windows, replaced with `_`.
+const RESERVED_CHARS: &[char] = &['<', '>', ':', '"', '|', '?', '*'];
+
+// extensions longer than this are not worth keeping when truncating
+const MAX_EXT_LEN: usize = 16;
+
+/// How to sanitize a filename with `FieldHeaders::safe_filename_with()`.
+///
+/// The result is always a single path component which is safe to join to a directory on any
+/// platform:
+///
+/// * everything up to the last `/` or `\` is stripped;
+/// * control characters are removed and characters reserved on Windows (`<>:"|?*`) are
+///   replaced with `_`;
+/// * leading and trailing whitespace and trailing dots are trimmed;
+/// * `.`, `..`, empty names and Windows device names such as `CON` or `nul.txt` are rejected.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FilenamePolicy {
+    /// The maximum length of the filename in bytes, keeping the extension if it's shorter than
+    /// 16 bytes. Default 255, the limit of most filesystems.
+    pub max_len: usize,
+    /// Normalize the filename to Unicode Normalization Form C. Default `true`.
+    pub normalize: bool,
+    /// Allow filenames starting with a `.`, which are hidden on Unix and may be interpreted by
+    /// web servers, such as `.htaccess`. If `false`, leading dots are stripped. Default `false`.
+    pub allow_hidden: bool,
+}
+
+impl Default for FilenamePolicy {
+    fn default() -> Self {
+        FilenamePolicy {
+            max_len: 255,
+            normalize: true,
+            allow_hidden: false,
+        }
+    }
+}
+
+impl FilenamePolicy {
+    /// Set the maximum length of the filename in bytes.
+    pub fn max_len(self, max_len: usize) -> Self {
+        FilenamePolicy { max_len, .. self }
+    }
+
+    /// Set whether to normalize the filename to Unicode Normalization Form C.
+    pub fn normalize(self, normalize: bool) -> Self {
+        FilenamePolicy { normalize, .. self }
+    }
+
+    /// Set whether to allow filenames starting with a `.`.
+    pub fn allow_hidden(self, allow_hidden: bool) -> Self {
+        FilenamePolicy { allow_hidden, .. self }
+    }
+
+    /// Sanitize `filename` according to this policy, returning `None` if no safe filename
+    /// is left.
+    pub fn sanitize(&self, filename: &str) -> Option<String> {
+        // a client may send a full path; take only the last component regardless of platform
+        let filename = filename.rsplit(&['/', '\\'][..]).next().unwrap_or(filename);
+
+        let filename: String = if self.normalize {
+            filename.nfc().collect()
+        } else {
+            filename.into()
+        };
+
+        let filename: String = filename.chars()
+            .filter(|c| !c.is_control())
+            .map(|c| if RESERVED_CHARS.contains(&c) { '_' } else { c })
+            .collect();
+
+        let mut filename = filename.trim().trim_end_matches(&['.', ' '][..]);
+
+        if !self.allow_hidden {
+            filename = filename.trim_start_matches('.');
+        }
+
+        // check what's left after truncating, which may cut a name down to a reserved one
+        let filename = truncate(filename, self.max_len);
+
+        if filename.is_empty() || filename == "." || filename == ".." || is_reserved(&filename)
+            || (!self.allow_hidden && filename.starts_with('.')) {
+            debug!("rejected unsafe filename: {:?}", filename);
+            return None;
+        }
+
+        Some(filename)
+    }
+}
+
+fn is_reserved(filename: &str) -> bool {
+    let stem = filename.split('.').next().unwrap_or(filename).trim_end();
+    RESERVED_NAMES.iter().any(|name| name.eq_ignore_ascii_case(stem))
+}
+
+/// Truncate `filename` to `max_len` bytes at a character boundary, keeping a short extension.
+fn truncate(filename: &str, max_len: usize) -> String {
+    if filename.len() <= max_len {
+        return filename.into();
+    }
+
+    let (stem, ext) = match filename.rfind('.') {
+        Some(idx) if idx > 0 && filename.len() - idx <= MAX_EXT_LEN && filename.len() - idx < max_len =>
+            filename.split_at(idx),
+        _ => (filename, ""),
+    };
+
+    let mut stem_len = max_len - ext.len();
+
+    while !stem.is_char_boundary(stem_len) {
+        stem_len -= 1;
+    }
+
+    let mut out = stem[..stem_len].trim_end_matches(&['.', ' '][..]).to_string();
+    out.push_str(ext);
+    out
+}
+
+#[test]
+fn test_sanitize() {
+    let policy = FilenamePolicy::default();
+    let sanitize = |name| policy.sanitize(name);
+
+    assert_eq!(sanitize("file.txt").as_deref(), Some("file.txt"));
+    assert_eq!(sanitize("../../etc/passwd").as_deref(), Some("passwd"));
+    assert_eq!(sanitize("C:\\Users\\me\\file.txt").as_deref(), Some("file.txt"));
+    assert_eq!(sanitize("..\\..\\file.txt").as_deref(), Some("file.txt"));
+    assert_eq!(sanitize("a\u{0}b\r\nc.txt").as_deref(), Some("abc.txt"));
+    assert_eq!(sanitize("what?.txt").as_deref(), Some("what_.txt"));
+    assert_eq!(sanitize("file.txt. . ").as_deref(), Some("file.txt"));
+    assert_eq!(sanitize(".htaccess").as_deref(), Some("htaccess"));
+    // decomposed to composed
+    assert_eq!(sanitize("nai\u{308}ve.txt").as_deref(), Some("na\u{EF}ve.txt"));
+
+    assert_eq!(sanitize(""), None);
+    assert_eq!(sanitize(".."), None);
+    assert_eq!(sanitize("foo/.."), None);
+    assert_eq!(sanitize("foo/"), None);
+    assert_eq!(sanitize("CON"), None);
+    assert_eq!(sanitize("nul.txt"), None);
+    assert_eq!(sanitize("com1 .tar.gz"), None);
+
+    assert_eq!(FilenamePolicy::default().allow_hidden(true).sanitize(".htaccess").as_deref(),
+               Some(".htaccess"));
+}
+
+#[test]
+fn test_truncate() {
+    let policy = FilenamePolicy::default().max_len(8);
+
+    assert_eq!(policy.sanitize("longfilename.txt").as_deref(), Some("long.txt"));
+    assert_eq!(policy.sanitize("longfilename").as_deref(), Some("longfile"));
+    // extension too long to keep
+    assert_eq!(policy.sanitize("a.verylongextension").as_deref(), Some("a.verylo"));
+    // don't split characters
+    assert_eq!(policy.sanitize("\u{65E5}\u{672C}\u{8A9E}.txt").as_deref(), Some("\u{65E5}.txt"));
+    // reserved after truncating
+    assert_eq!(FilenamePolicy::default().max_len(7).sanitize("CONSOLE.txt"), None);
+    assert_eq!(FilenamePolicy::default().max_len(0).sanitize("file.txt"), None);
+}
@@ -0,0 +1,233 @@
+// Copyright 2017 `multipart-async` Crate Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+//! Computing digests of field data as it is read.
+use crc32fast::Hasher as Crc32;
+use md5::{Digest, Md5};
+use sha2::Sha256;
+
+use std::fmt::{self, Write};
+
+use crate::{BodyChunk, StreamError};
+
+use crate::server::sync::Shared;
+
+use super::decode::decode_base64;
+use super::FieldHeaders;
+
+use crate::helpers::*;
+
+const CONTENT_MD5: &str = "content-md5";
+
+/// A digest algorithm supported by `FieldData::hashed()`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Algorithm {
+    /// SHA-256, from [FIPS 180-4](https://csrc.nist.gov/publications/detail/fips/180/4/final).
+    Sha256,
+    /// MD5, from [IETF RFC 1321](https://tools.ietf.org/html/rfc1321).
+    ///
+    /// Not collision resistant; use it to check for corruption, not to identify content.
+    Md5,
+    /// CRC-32 (IEEE), as used by ZIP and gzip.
+    Crc32,
+}
+
+/// The digests of a field's data, computed by `HashField`.
+///
+/// Only the digests of the requested algorithms are set, except for `md5` which is also
+/// set if the field had a `Content-MD5` header.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Digests {
+    /// The SHA-256 digest, if requested.
+    pub sha256: Option<[u8; 32]>,
+    /// The MD5 digest, if requested or checked against `Content-MD5`.
+    pub md5: Option<[u8; 16]>,
+    /// The CRC-32 checksum, if requested.
+    pub crc32: Option<u32>,
+}
+
+/// Format a digest as lowercase hexadecimal, e.g. to use as a filename.
+///
+/// ```rust,ignore
+/// let name = multipart_async::server::to_hex(&digests.sha256.unwrap());
+/// ```
+pub fn to_hex(digest: &[u8]) -> String {
+    digest.iter().fold(String::with_capacity(digest.len() * 2), |mut hex, byte| {
+        let _ = write!(hex, "{:02x}", byte);
+        hex
+    })
+}
+
+/// A `Stream` adaptor which computes digests of field data as it is passed through,
+/// returned by `FieldData::hashed()`.
+///
+/// The chunks of the field are yielded unchanged. Once the stream has ended, the digests are
+/// available from `digests()`.
+///
+/// If the field has a `Content-MD5` header ([IETF RFC 1864](https://tools.ietf.org/html/rfc1864)),
+/// it is checked at the end of the data and an error is yielded instead of `None` if it doesn't
+/// match.
+pub struct HashField<S: TryStream> {
+    stream: S,
+    sha256: Option<Sha256>,
+    md5: Option<Md5>,
+    crc32: Option<Crc32>,
+    digests: Option<Digests>,
+    /// The headers for the original field, provided as a convenience.
+    pub headers: Shared<FieldHeaders>,
+}
+
+pub fn hashed<S: TryStream>(headers: Shared<FieldHeaders>, data: S, algorithms: &[Algorithm]) -> HashField<S> {
+    let wants = |algorithm| algorithms.contains(&algorithm);
+    let content_md5 = headers.ext.contains_key(CONTENT_MD5);
+
+    HashField {
+        stream: data,
+        sha256: if wants(Algorithm::Sha256) { Some(Sha256::new()) } else { None },
+        md5: if wants(Algorithm::Md5) || content_md5 { Some(Md5::new()) } else { None },
+        crc32: if wants(Algorithm::Crc32) { Some(Crc32::new()) } else { None },
+        digests: None,
+        headers,
+    }
+}
+
+impl<S: TryStream> HashField<S> {
+    /// The digests of the field data, or `None` if the stream hasn't ended yet.
+    pub fn digests(&self) -> Option<&Digests> {
+        self.digests.as_ref()
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        if let Some(ref mut sha256) = self.sha256 {
+            sha256.update(data);
+        }
+
+        if let Some(ref mut md5) = self.md5 {
+            md5.update(data);
+        }
+
+        if let Some(ref mut crc32) = self.crc32 {
+            crc32.update(data);
+        }
+    }
+
+    fn finish(&mut self) -> Digests {
+        Digests {
+            sha256: self.sha256.take().map(|sha256| sha256.finalize().into()),
+            md5: self.md5.take().map(|md5| md5.finalize().into()),
+            crc32: self.crc32.take().map(Crc32::finalize),
+        }
+    }
+}
+
+impl<S: TryStream> HashField<S> where S::Error: StreamError {
+    fn check_content_md5(&self, md5: Option<[u8; 16]>) -> Result<(), S::Error> {
+        let header = match self.headers.ext.get(CONTENT_MD5) {
+            Some(header) => header,
+            None => return Ok(()),
+        };
+
+        let md5 = md5.expect("MD5 is always computed if Content-MD5 is present");
+
+        let expected = match decode_base64(header.as_bytes()) {
+            Ok(expected) => expected,
+            Err(e) => ret_err!(InvalidData, "invalid Content-MD5 header {:?} on field {:?}: {}",
+                                            header, self.headers.name, e),
+        };
+
+        if expected != md5 {
+            ret_err!(InvalidData, "Content-MD5 of field {:?} does not match its data: expected {}, found {}",
+                                  self.headers.name, to_hex(&expected), to_hex(&md5));
+        }
+
+        Ok(())
+    }
+}
+
+impl<S: TryStream + Unpin> Stream for HashField<S> where S::Ok: BodyChunk, S::Error: StreamError {
+    type Item = Result<S::Ok, S::Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+
+        if this.digests.is_some() {
+            return Poll::Ready(None);
+        }
+
+        match ready!(poll_next(&mut this.stream, cx)?) {
+            Some(chunk) => {
+                this.update(chunk.as_slice());
+                ready_some(chunk)
+            },
+            None => {
+                let digests = this.finish();
+                let res = this.check_content_md5(digests.md5);
+                this.digests = Some(digests);
+                res?;
+                Poll::Ready(None)
+            },
+        }
+    }
+}
+
+impl<S: TryStream> fmt::Debug for HashField<S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("HashField")
+            .field("headers", &self.headers)
+            .field("digests", &self.digests)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use futures::executor::block_on;
+    use futures::TryStreamExt;
+    use http::header::HeaderValue;
+
+    use super::{hashed, to_hex, Algorithm, FieldHeaders};
+    use crate::server::sync::Shared;
+
+    #[test]
+    fn test_hashed() {
+        let stream = crate::mock_stream!("hello"; " "; "world");
+        let mut hash = hashed(Default::default(), stream, &[Algorithm::Sha256, Algorithm::Crc32]);
+
+        let data: Vec<u8> = block_on((&mut hash).map_ok(|chunk| chunk.into_owned()).try_concat()).unwrap();
+        assert_eq!(data, b"hello world");
+
+        let digests = hash.digests().unwrap();
+        assert_eq!(to_hex(&digests.sha256.unwrap()),
+                   "b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9");
+        assert_eq!(digests.md5, None);
+        assert_eq!(digests.crc32, Some(0x0d4a_1185));
+    }
+
+    #[test]
+    fn test_content_md5() {
+        let headers = |md5: &'static str| {
+            let mut headers = FieldHeaders::default();
+            headers.ext.insert("content-md5", HeaderValue::from_static(md5));
+            Shared::new(headers)
+        };
+
+        let stream = crate::mock_stream!("hello world");
+        let mut hash = hashed(headers("XrY7u+Ae7tCTyyK7j1rNww=="), stream, &[]);
+        assert!(block_on(hash.try_next()).unwrap().is_some());
+        assert!(block_on(hash.try_next()).unwrap().is_none());
+        assert_eq!(to_hex(&hash.digests().unwrap().md5.unwrap()), "5eb63bbbe01eeed093cb22bb8f5acdc3");
+
+        let stream = crate::mock_stream!("hello, world");
+        let mut hash = hashed(headers("XrY7u+Ae7tCTyyK7j1rNww=="), stream, &[Algorithm::Md5]);
+        assert!(block_on(hash.try_next()).unwrap().is_some());
+        assert!(block_on(hash.try_next()).is_err());
+        assert!(block_on(hash.try_next()).unwrap().is_none());
+
+        let stream = crate::mock_stream!("hello world");
+        let hash = hashed(headers("not base64!"), stream, &[]);
+        assert!(block_on(hash.map_ok(|chunk| chunk.into_owned()).try_concat()).is_err());
+    }
+}
@@ -1,23 +1,24 @@
-use futures::Stream;
-
-use http::header::{HeaderMap, HeaderName, HeaderValue};
+use http::header::{HeaderMap, HeaderName, HeaderValue, CONTENT_DISPOSITION, CONTENT_TYPE};
 
 use mime::{self, Mime, Name};
 
-use std::ascii::AsciiExt;
+use std::borrow::Cow;
 use std::str;
 
-use server::{httparse, twoway};
-use server::boundary::BoundaryFinder;
+use crate::server::{httparse, twoway};
+use crate::server::boundary::BoundaryFinder;
+use crate::server::error::{context_err, multipart_err, ParseContext};
+use crate::server::limits::{limit_err, LimitKind, Limits};
+use crate::server::ParseMode;
+use crate::server::MultipartError;
 
-use { BodyChunk, StreamError};
+use super::FilenamePolicy;
 
-use self::httparse::{EMPTY_HEADER, Status};
+use crate::{BodyChunk, StreamError};
 
-use helpers::*;
+use self::httparse::{EMPTY_HEADER, Status};
 
-const MAX_BUF_LEN: usize = 1024;
-const MAX_HEADERS: usize = 4;
+use crate::helpers::*;
 
 /// The headers of a `Field`, including the name, filename, and `Content-Type`, if provided.
 ///
@@ -25,8 +26,7 @@ const MAX_HEADERS: usize = 4;
 /// These values are provided directly by the client, and as such, should be considered
 /// *untrustworthy* and potentially **dangerous**. Avoid any unsanitized usage on the filesystem
 /// or in a shell or database, or performing unsafe operations with the assumption of a
-/// certain file type. Sanitizing/verifying these values is (currently) beyond the scope of this
-/// crate.
+/// certain file type. Use `safe_filename()` to get a filename that can be used on the filesystem.
 #[derive(Clone, Default, Debug)]
 #[cfg_attr(test, derive(PartialEq, Eq))]
 pub struct FieldHeaders {
@@ -42,13 +42,27 @@ pub struct FieldHeaders {
     pub name: String,
     /// The name of the file as it was on the client. If not provided, it may still have been a
     /// file field.
+    ///
+    /// If the client provided an extended `filename*` parameter that could be decoded, this is
+    /// its decoded value, even if a plain `filename` was also provided.
     pub filename: Option<String>,
+    /// The plain `filename` parameter as provided by the client, even if `filename` holds the
+    /// decoded `filename*` instead.
+    pub filename_plain: Option<String>,
+    /// The extended `filename*` parameter
+    /// ([IETF RFC 5987](https://tools.ietf.org/html/rfc5987)), if provided and decodable.
+    ///
+    /// Browsers don't send this for `multipart/form-data`, but other clients may use it for
+    /// non-ASCII filenames. A value split into
+    /// [RFC 2231](https://tools.ietf.org/html/rfc2231#section-3) continuations
+    /// (`filename*0*=...; filename*1*=...`) is joined before decoding.
+    pub filename_ext: Option<ExtendedValue>,
     /// The `Content-Type` of this field, as provided by the client. If `None`, then the field
     /// is probably text, but this is not guaranteed.
     pub content_type: Option<Mime>,
     /// Any additional headers, standard or otherwise, for this field as provided by the client.
     ///
-    /// The size of this map will be limited internally.
+    /// The size of this map is limited by `Limits::header_count`.
     pub ext: HeaderMap,
 }
 
@@ -56,65 +70,260 @@ impl FieldHeaders {
     /// `true` if `content_type` is `None` or `text/*` (such as `text/plain`).
     ///
     /// **Note**: this does not guarantee that the field data is compatible with
-    /// `FieldData::read_text()`; supporting more encodings than ASCII/UTF-8 is (currently)
-    /// beyond the scope of this crate.
+    /// `FieldData::read_text()`, which only decodes UTF-8 unless the `encoding_rs` feature is
+    /// enabled; see its "Charset" section.
     pub fn is_text(&self) -> bool {
-        self.content_type.as_ref().map_or(true, |ct| ct.type_() == mime::TEXT)
+        self.content_type.as_ref().is_none_or(|ct| ct.type_() == mime::TEXT)
+    }
+
+    /// The filename sanitized with the default `FilenamePolicy`, or `None` if no filename was
+    /// provided or none is left after sanitizing.
+    ///
+    /// The result is a single path component, so it can't escape the directory it's joined to.
+    pub fn safe_filename(&self) -> Option<String> {
+        self.safe_filename_with(&FilenamePolicy::default())
+    }
+
+    /// The filename sanitized with the given `FilenamePolicy`, or `None` if no filename was
+    /// provided or none is left after sanitizing.
+    pub fn safe_filename_with(&self, policy: &FilenamePolicy) -> Option<String> {
+        self.filename.as_ref().and_then(|filename| policy.sanitize(filename))
     }
 
     /// The character set of this field, if provided.
-    pub fn charset(&self) -> Option<Name> {
+    pub fn charset(&self) -> Option<Name<'_>> {
+        self.content_type.as_ref().and_then(|ct| ct.get_param(mime::CHARSET))
+    }
+
+    /// The `Content-Transfer-Encoding` of this field, if provided; see `FieldData::decode()`.
+    pub fn transfer_encoding(&self) -> Option<&str> {
+        self.ext.get(CONTENT_TRANSFER_ENCODING)
+            .and_then(|val| val.to_str().ok())
+            .map(str::trim)
+    }
+}
+
+/// The headers of a `Part` of a multipart body of any subtype.
+///
+/// Unlike `FieldHeaders`, none of these are required, and the disposition may be anything.
+///
+/// ### Note: Untrustworthy
+/// These values are provided directly by the client; see the note on `FieldHeaders`.
+#[derive(Clone, Default, Debug)]
+pub struct PartHeaders {
+    /// All headers of this part, including `Content-Type` and `Content-Disposition`.
+    ///
+    /// The size of this map is limited by `Limits::header_count`.
+    pub headers: HeaderMap,
+    /// The parsed `Content-Type` of this part, if provided.
+    pub content_type: Option<Mime>,
+    /// The disposition type from `Content-Disposition`, in lowercase, e.g. `form-data`,
+    /// `attachment` or `inline`.
+    pub disposition: Option<String>,
+    /// The `name` parameter of `Content-Disposition`, if provided.
+    pub name: Option<String>,
+    /// The `filename` parameter of `Content-Disposition`, or the decoded value of `filename*`
+    /// if provided.
+    pub filename: Option<String>,
+    /// The plain `filename` parameter of `Content-Disposition`, if provided.
+    pub filename_plain: Option<String>,
+    /// The extended `filename*` parameter of `Content-Disposition`, if provided and decodable,
+    /// after joining any RFC 2231 continuations.
+    pub filename_ext: Option<ExtendedValue>,
+}
+
+impl PartHeaders {
+    /// `true` if `content_type` is `None` or `text/*` (such as `text/plain`).
+    pub fn is_text(&self) -> bool {
+        self.content_type.as_ref().is_none_or(|ct| ct.type_() == mime::TEXT)
+    }
+
+    /// The character set of this part, if provided.
+    pub fn charset(&self) -> Option<Name<'_>> {
         self.content_type.as_ref().and_then(|ct| ct.get_param(mime::CHARSET))
     }
+
+    /// Convert to `FieldHeaders` without any validation; `name` is empty if not provided.
+    pub(crate) fn to_field_headers(&self) -> FieldHeaders {
+        self.clone().into_field_headers()
+    }
+
+    fn into_field_headers(self) -> FieldHeaders {
+        let mut ext = self.headers;
+        ext.remove(CONTENT_DISPOSITION);
+        ext.remove(CONTENT_TYPE);
+
+        FieldHeaders {
+            name: self.name.unwrap_or_default(),
+            filename: self.filename,
+            filename_plain: self.filename_plain,
+            filename_ext: self.filename_ext,
+            content_type: self.content_type,
+            ext,
+        }
+    }
+}
+
+const CONTENT_TRANSFER_ENCODING: &str = "content-transfer-encoding";
+
+/// A decoded extended parameter value as defined by
+/// [IETF RFC 5987 Section 3.2](https://tools.ietf.org/html/rfc5987#section-3.2),
+/// e.g. `UTF-8'en'na%C3%AFve.txt`.
+///
+/// UTF-8 and ISO-8859-1 are supported, as required by the RFC; other charsets are supported
+/// with the `encoding_rs` feature.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ExtendedValue {
+    /// The charset the value was encoded with, as provided by the client.
+    pub charset: String,
+    /// The language tag, if provided.
+    pub language: Option<String>,
+    /// The decoded value.
+    pub value: String,
+    /// The value as it appeared in the header, before decoding.
+    pub raw: String,
+}
+
+impl ExtendedValue {
+    /// Parse and decode an extended value, returning `None` if it's malformed or
+    /// in an unsupported charset.
+    pub fn parse(raw: &str) -> Option<ExtendedValue> {
+        let mut parts = raw.splitn(3, '\'');
+
+        let charset = try_opt!(parts.next());
+        let language = try_opt!(parts.next());
+        let bytes = try_opt!(percent_decode(try_opt!(parts.next())));
+
+        let value = if charset.eq_ignore_ascii_case("UTF-8") {
+            try_opt!(String::from_utf8(bytes).ok())
+        } else if charset.eq_ignore_ascii_case("ISO-8859-1") {
+            // the first 256 code points are ISO-8859-1
+            bytes.iter().map(|&b| b as char).collect()
+        } else {
+            try_opt!(decode_charset(charset, &bytes))
+        };
+
+        Some(ExtendedValue {
+            charset: charset.to_string(),
+            language: if language.is_empty() { None } else { Some(language.to_string()) },
+            value,
+            raw: raw.to_string(),
+        })
+    }
+}
+
+#[cfg(feature = "encoding_rs")]
+fn decode_charset(charset: &str, bytes: &[u8]) -> Option<String> {
+    let encoding = try_opt!(encoding_rs::Encoding::for_label(charset.as_bytes()));
+    encoding.decode_without_bom_handling_and_without_replacement(bytes).map(Into::into)
+}
+
+#[cfg(not(feature = "encoding_rs"))]
+fn decode_charset(charset: &str, _bytes: &[u8]) -> Option<String> {
+    debug!("unsupported charset in extended parameter value: {:?}", charset);
+    None
+}
+
+/// Decode `%XX` escapes in `input`, returning `None` if there is an invalid escape.
+fn percent_decode(input: &str) -> Option<Vec<u8>> {
+    fn hex_val(b: u8) -> Option<u8> {
+        (b as char).to_digit(16).map(|d| d as u8)
+    }
+
+    let mut out = Vec::with_capacity(input.len());
+    let mut bytes = input.bytes();
+
+    while let Some(b) = bytes.next() {
+        if b == b'%' {
+            let hi = try_opt!(bytes.next().and_then(hex_val));
+            let lo = try_opt!(bytes.next().and_then(hex_val));
+            out.push(hi << 4 | lo);
+        } else {
+            out.push(b);
+        }
+    }
+
+    Some(out)
 }
 
 #[derive(Debug, Default)]
 pub struct ReadHeaders {
-    accumulator: Vec<u8>
+    accumulator: Vec<u8>,
+    /// Reverse the HTML5 percent-escaping of `Content-Disposition` parameters.
+    pub html5_escapes: bool,
+    /// The name of the enclosing field if reading the parts of a nested multipart body.
+    ///
+    /// Such parts may have a `file`, `attachment` or `inline` disposition and no `name`, in
+    /// which case they take this name.
+    pub parent_name: Option<String>,
+    /// The position of the last header block read, for errors found in validating it.
+    pub context: ParseContext,
+    /// Set if the last error from `read_headers()` was in the headers of a part, rather than
+    /// from the stream, so the part can be skipped.
+    pub part_error: bool,
 }
 
 impl ReadHeaders {
-    pub fn read_headers<S: Stream>(&mut self, stream: &mut BoundaryFinder<S>) -> PollOpt<FieldHeaders, S::Error>
-    where S::Item: BodyChunk, S::Error: StreamError {
+    pub fn read_headers<S: TryStream + Unpin>(&mut self, stream: &mut BoundaryFinder<S>, cx: &mut Context)
+        -> PollOpt<PartHeaders, S::Error> where S::Ok: BodyChunk, S::Error: StreamError {
+        self.part_error = false;
+
         loop {
             trace!("read_headers state: accumulator: {}", show_bytes(&self.accumulator));
 
-            let chunk = match try_ready!(stream.poll()) {
+            let chunk = match ready!(stream.body_chunk(cx)?) {
                 Some(chunk) => chunk,
                 None => return if !self.accumulator.is_empty() {
-                    error("unexpected end of stream")
+                    let context = stream.context(stream.position() - self.accumulator.len() as u64,
+                                                 &self.accumulator);
+                    let err = MultipartError::UnexpectedEof("while reading field headers".into());
+                    Poll::Ready(Some(context_err(err, context)))
                 } else {
                     trace!("end of request reached");
-                    ready(None)
+                    Poll::Ready(None)
                 },
             };
 
             trace!("got chunk for headers: {}", show_bytes(chunk.as_slice()));
 
-            // End of the headers section is signalled by a double-CRLF
-            if let Some(header_end) = twoway::find_bytes(chunk.as_slice(), b"\r\n\r\n") {
+            let header_end = match stream.mode {
+                ParseMode::Strict => strict_header_end(&self.accumulator, chunk.as_slice()),
+                ParseMode::Lenient => lenient_header_end(&self.accumulator, chunk.as_slice()),
+            };
+
+            let header_len = header_end.unwrap_or_else(|| chunk.len());
+
+            if self.accumulator.len().saturating_add(header_len) > stream.limits.header_block_size {
+                let limit = stream.limits.header_block_size;
+                self.accumulator.clear();
+                self.part_error = true;
+                return Poll::Ready(Some(limit_err(LimitKind::HeaderBlockSize, limit as u64)));
+            }
+
+            if let Some(header_end) = header_end {
                 // Split after the double-CRLF because we don't want to yield it and httparse expects it
-                let (headers, rem) = chunk.split_at(header_end + 4);
+                let (headers, rem) = chunk.split_at(header_end);
                 stream.push_chunk(rem);
 
-                if !self.accumulator.is_empty() {
+                let block = if !self.accumulator.is_empty() {
                     self.accumulator.extend_from_slice(headers.as_slice());
-                    let headers = parse_headers(&self.accumulator)?;
-                    self.accumulator.clear();
-
-                    return ready(Some(headers));
+                    &self.accumulator
                 } else {
-                    return ready(Some(parse_headers(headers.as_slice())?));
+                    headers.as_slice()
+                };
+
+                self.context = stream.context(stream.position() - block.len() as u64, block);
+
+                if stream.mode == ParseMode::Lenient && has_bare_lf(block) {
+                    warn!("accepting bare LF line endings in field headers: {}", show_bytes(block));
                 }
-            } else if let Some(split_idx) = header_end_split(&self.accumulator, chunk.as_slice()) {
-                let (head, tail) = chunk.split_at(split_idx);
-                self.accumulator.extend_from_slice(head.as_slice());
-                stream.push_chunk(tail);
-                continue;
-            }
 
-            if self.accumulator.len().saturating_add(chunk.len()) > MAX_BUF_LEN {
-                return error("headers section too long or trailing double-CRLF missing");
+                let res = parse_part_headers::<MultipartError>(block, &stream.limits, self.html5_escapes)
+                    .or_else(|err| context_err(err, self.context.clone()));
+                self.accumulator.clear();
+                self.part_error = res.is_err();
+
+                return Poll::Ready(Some(res));
             }
 
             self.accumulator.extend_from_slice(chunk.as_slice());
@@ -124,6 +333,22 @@ impl ReadHeaders {
 
 const CRLF2: &[u8] = b"\r\n\r\n";
 
+/// Find the end of the header block: the index in `second` after the first empty line, given
+/// the header bytes read before it in `first`.
+fn strict_header_end(first: &[u8], second: &[u8]) -> Option<usize> {
+    // a part with no headers, whose block is just the empty line
+    match first {
+        b"" if second.starts_with(b"\r\n") => return Some(2),
+        b"\r" if second.starts_with(b"\n") => return Some(1),
+        _ => (),
+    }
+
+    // The double-CRLF may be split between `first` and `second`; this has to be checked first
+    // as the field data may contain another double-CRLF
+    header_end_split(first, second)
+        .or_else(|| twoway::find_bytes(second, CRLF2).map(|idx| idx + 4))
+}
+
 /// Check if the double-CRLF falls between chunk boundaries, and if so, the split index of
 /// the second boundary
 fn header_end_split(first: &[u8], second: &[u8]) -> Option<usize> {
@@ -142,137 +367,312 @@ fn header_end_split(first: &[u8], second: &[u8]) -> Option<usize> {
     }
 }
 
-fn parse_headers<E: StreamError>(bytes: &[u8]) -> Result<FieldHeaders, E> {
-    debug_assert!(bytes.ends_with(b"\r\n\r\n"),
-                  "header byte sequence does not end with `\\r\\n\\r\\n`: {}",
+/// Find the end of the header block in lenient mode, where lines may end with a bare LF: the
+/// index in `second` after the first empty line, given the header bytes read before it in
+/// `first`.
+fn lenient_header_end(first: &[u8], second: &[u8]) -> Option<usize> {
+    // only the last two bytes of `first` matter, as it has no empty line in it; if that's all
+    // of it, they start the header block, and so a line
+    let tail = &first[first.len().saturating_sub(2)..];
+    let mut line_start = tail.len() == first.len();
+    let mut cr = false;
+
+    for (idx, &byte) in tail.iter().chain(second).enumerate() {
+        match byte {
+            b'\n' if line_start => return Some(idx + 1 - tail.len()),
+            b'\n' => line_start = true,
+            b'\r' if line_start && !cr => cr = true,
+            _ => line_start = false,
+        }
+
+        if byte != b'\r' {
+            cr = false;
+        }
+    }
+
+    None
+}
+
+/// Check for a LF which is not preceded by a CR.
+fn has_bare_lf(bytes: &[u8]) -> bool {
+    bytes.iter().enumerate().any(|(idx, &byte)| byte == b'\n' && (idx == 0 || bytes[idx - 1] != b'\r'))
+}
+
+/// Parse the headers of a field and validate them as `multipart/form-data`.
+#[cfg(test)]
+fn parse_headers<E: StreamError>(bytes: &[u8], limits: &Limits, html5_escapes: bool,
+                                 parent_name: Option<&str>) -> Result<FieldHeaders, E> {
+    parse_part_headers(bytes, limits, html5_escapes)
+        .and_then(|part| FieldHeaders::form_data(part, parent_name))
+}
+
+fn parse_part_headers<E: StreamError>(bytes: &[u8], limits: &Limits, html5_escapes: bool)
+    -> Result<PartHeaders, E> {
+    debug_assert!(bytes.ends_with(b"\n"),
+                  "header byte sequence does not end with a newline: {}",
                   show_bytes(bytes));
 
-    let mut header_buf = [EMPTY_HEADER; MAX_HEADERS];
+    let mut header_buf = vec![EMPTY_HEADER; limits.header_count];
 
     let headers = match httparse::parse_headers(bytes, &mut header_buf) {
         Ok(Status::Complete((_, headers))) => headers,
-        Ok(Status::Partial) => ret_err!("field headers incomplete: {}", show_bytes(bytes)),
-        Err(e) => ret_err!("error parsing headers: {}; from buffer: {}", e, show_bytes(bytes)),
+        Ok(Status::Partial) => ret_err!(Headers, "field headers incomplete: {}", show_bytes(bytes)),
+        Err(httparse::Error::TooManyHeaders) =>
+            return limit_err(LimitKind::HeaderCount, limits.header_count as u64),
+        Err(e) => ret_err!(Headers, "error parsing headers: {}; from buffer: {}", e, show_bytes(bytes)),
     };
 
     trace!("parsed headers: {:?}", headers);
 
-    let mut out_headers = FieldHeaders::default();
+    let mut out_headers = PartHeaders::default();
 
     let mut dupe_cont_type = false;
 
     for header in headers {
-        if "Content-Disposition".eq_ignore_ascii_case(header.name) {
-            if !out_headers.name.is_empty() {
-                ret_err!("duplicate `Content-Disposition` header on field: {}", out_headers.name);
+        let hdr_name = HeaderName::from_bytes(header.name.as_bytes())
+            .or_else(|e|
+                fmt_err!(Headers, "error on multipart field header \"{}\": {}",
+                         header.name, e)
+            )?;
+
+        let hdr_val = HeaderValue::from_bytes(header.value)
+            .or_else(|e|
+                fmt_err!(Headers, "error on multipart field header \"{}\": {}",
+                         header.name, e)
+            )?;
+
+        if hdr_name == CONTENT_DISPOSITION {
+            if out_headers.disposition.is_some() {
+                ret_err!(Headers, "duplicate `Content-Disposition` header on field: {}",
+                         out_headers.name.as_deref().unwrap_or(""));
             }
 
             let str_val = str::from_utf8(header.value)
-                .or_else(|_| error("multipart `Content-Disposition` header values \
-                                                     must be UTF-8 encoded"))?
+                .or_else(|_| fmt_err!(Headers, "multipart `Content-Disposition` header values \
+                                                must be UTF-8 encoded"))?
                 .trim();
 
-            parse_cont_disp_val(str_val, &mut out_headers)?;
-        } else if "Content-Type".eq_ignore_ascii_case(header.name) {
+            parse_cont_disp_val(str_val, &mut out_headers, html5_escapes)?;
+        } else if hdr_name == CONTENT_TYPE {
             if out_headers.content_type.is_some() {
-                // try to get the field name from `Content-Disposition` first
-                // if none was provided then that's the bigger issue
                 dupe_cont_type = true;
                 continue;
             }
 
             let str_val = str::from_utf8(header.value)
-                .or_else(|_| error("multipart `Content-Type` header values \
-                                                     must be UTF-8 encoded"))?
+                .or_else(|_| fmt_err!(Headers, "multipart `Content-Type` header values \
+                                                must be UTF-8 encoded"))?
                 .trim();
 
             out_headers.content_type = Some(
                 str_val.parse::<Mime>()
                     .or_else(|_|
-                        fmt_err!("could not parse MIME type from {:?}", str_val)
+                        fmt_err!(Headers, "could not parse MIME type from {:?}", str_val)
                     )?
             );
-        } else {
-            let hdr_name = HeaderName::from_bytes(header.name.as_bytes())
-                .or_else(|e|
-                    fmt_err!("error on multipart field header \"{}\": {}",
-                             header.name, e)
-                )?;
-
-            let hdr_val = HeaderValue::from_bytes(bytes)
-                .or_else(|e|
-                    fmt_err!("error on multipart field header \"{}\": {}",
-                             header.name, e)
-                )?;
-
-            out_headers.ext.append(hdr_name, hdr_val);
         }
-    }
 
-    if out_headers.name.is_empty() {
-        // missing `name` parameter in a provided `Content-Disposition` is covered separately
-        if let Some(filename) = out_headers.filename {
-            ret_err!("missing `Content-Disposition` header on a field \
-                      (filename: {}) in this multipart request", filename);
-        }
-
-        if let Some(content_type) = out_headers.content_type {
-            ret_err!("missing `Content-Disposition` header on a field \
-                      (Content-Type: {}) in this multipart request", content_type);
-        }
-
-        ret_err!("missing `Content-Disposition` header on a field in this multipart request");
+        out_headers.headers.append(hdr_name, hdr_val);
     }
 
     if dupe_cont_type {
-        ret_err!("duplicate `Content-Type` header in field: {}", out_headers.name);
+        ret_err!(Headers, "duplicate `Content-Type` header in field: {}",
+                 out_headers.name.as_deref().unwrap_or(""));
     }
 
     Ok(out_headers)
 }
 
-fn parse_cont_disp_val<E: StreamError>(val: &str, out: &mut FieldHeaders) -> Result<(), E> {
+/// Nested parts (RFC 2388 Section 4.5) may use any of these dispositions.
+const NESTED_DISPOSITIONS: &[&str] = &["form-data", "file", "attachment", "inline"];
+
+impl FieldHeaders {
+    /// Validate the headers of a part as a `multipart/form-data` field.
+    ///
+    /// If `parent_name` is set, this is a part of a nested body which may have one of
+    /// `NESTED_DISPOSITIONS` or none at all, and takes that name if it has none of its own.
+    pub(crate) fn form_data<E: StreamError>(part: PartHeaders, parent_name: Option<&str>)
+        -> Result<FieldHeaders, E> {
+        let cont_disp = part.headers.get(CONTENT_DISPOSITION)
+            .and_then(|val| val.to_str().ok())
+            .unwrap_or("");
+
+        let has_name = part.name.as_deref().is_some_and(|name| !name.is_empty());
+
+        match (part.disposition.as_deref(), parent_name) {
+            (Some(disposition), Some(_)) => if !NESTED_DISPOSITIONS.contains(&disposition) {
+                ret_err!(Headers, "unexpected/unsupported header `Content-Disposition: {}` on a part of a \
+                          nested multipart body", cont_disp);
+            },
+            (Some("form-data"), None) => if !has_name {
+                ret_err!(Headers, "expected 'name' parameter in `Content-Disposition: {}`", cont_disp);
+            },
+            (Some(_), None) =>
+                ret_err!(Headers, "unexpected/unsupported field header `Content-Disposition: {}` \
+                          in this multipart request; each field must have exactly one \
+                          `Content-Disposition: form-data` header with a `name` parameter",
+                         cont_disp),
+            (None, Some(_)) => (),
+            (None, None) => {
+                debug!("missing `Content-Disposition` header on a field with headers: {:?}",
+                       part.headers);
+                return multipart_err(MultipartError::MissingContentDisposition);
+            },
+        }
+
+        let mut headers = part.into_field_headers();
+
+        if let (false, Some(parent_name)) = (has_name, parent_name) {
+            headers.name = parent_name.into();
+        }
+
+        Ok(headers)
+    }
+}
+
+fn parse_cont_disp_val<E: StreamError>(val: &str, out: &mut PartHeaders, html5_escapes: bool)
+    -> Result<(), E> {
     debug!("parse_cont_disp_val({:?})", val);
 
     // Only take the first section, the rest can be in quoted strings that we want to handle
     let mut sections = val.splitn(2, ';').map(str::trim);
+    let disposition = sections.next().unwrap_or("");
 
-    if !sections.next().unwrap_or("").eq_ignore_ascii_case("form-data") {
-        ret_err!("unexpected/unsupported field header `Content-Disposition: {}` \
-                  in this multipart request; each field must have exactly one \
-                  `Content-Disposition: form-data` header with a `name` parameter", val);
+    if disposition.is_empty() {
+        ret_err!(Headers, "missing disposition type in `Content-Disposition: {}`", val);
     }
 
+    out.disposition = Some(disposition.to_ascii_lowercase());
+
     let mut rem = sections.next().unwrap_or("");
+    let mut filename_sections = Vec::new();
 
-    while let Some((key, val, rest)) = parse_keyval(rem) {
+    while let Some((key, val, rest)) = parse_keyval(rem, html5_escapes) {
         rem = rest;
 
-        match key {
-            "name" => out.name = val.to_string(),
-            "filename" => out.filename = Some(val.to_string()),
-            _ => debug!("unknown key-value pair in Content-Disposition: {:?} = {:?}", key, val),
+        if key.eq_ignore_ascii_case("name") {
+            out.name = Some(unescape_html5(val, html5_escapes).into_owned());
+        } else if key.eq_ignore_ascii_case("filename") {
+            out.filename = Some(unescape_html5(val, html5_escapes).into_owned());
+        } else if key.eq_ignore_ascii_case("filename*") {
+            out.filename_ext = ExtendedValue::parse(&val);
+
+            if out.filename_ext.is_none() {
+                warn!("could not decode `filename*` parameter in Content-Disposition: {:?}", val);
+            }
+        } else if let Some((idx, encoded)) = continuation_index(key, "filename") {
+            filename_sections.push((idx, encoded, val.into_owned()));
+        } else {
+            debug!("unknown key-value pair in Content-Disposition: {:?} = {:?}", key, val);
+        }
+    }
+
+    if !filename_sections.is_empty() && out.filename_ext.is_none() {
+        match join_continuations(filename_sections) {
+            Some((joined, true)) => {
+                out.filename_ext = ExtendedValue::parse(&joined);
+
+                if out.filename_ext.is_none() {
+                    warn!("could not decode `filename*` continuations in Content-Disposition: {:?}",
+                          joined);
+                }
+            },
+            Some((joined, false)) => if out.filename.is_none() { out.filename = Some(joined) },
+            None => warn!("incomplete `filename*` continuations in Content-Disposition: {:?}", val),
         }
     }
 
-    if out.name.is_empty() {
-        ret_err!("expected 'name' parameter in `Content-Disposition: {}`", val);
+    out.filename_plain = out.filename.clone();
+
+    // the extended parameter takes precedence regardless of order (RFC 6266 Section 4.3)
+    if let Some(ref ext) = out.filename_ext {
+        out.filename = Some(ext.value.clone());
     }
 
     Ok(())
 }
 
-fn parse_keyval(input: &str) -> Option<(&str, &str, &str)> {
+/// If `key` is a section of an RFC 2231 continuation of `param`, e.g. `filename*0` or
+/// `filename*1*`, get its index and whether it's percent-encoded.
+fn continuation_index(key: &str, param: &str) -> Option<(u32, bool)> {
+    let name = key.get(..param.len())?;
+    let rest = key[param.len()..].strip_prefix('*')?;
+
+    if !name.eq_ignore_ascii_case(param) {
+        return None;
+    }
+
+    let (idx, encoded) = match rest.strip_suffix('*') {
+        Some(idx) => (idx, true),
+        None => (rest, false),
+    };
+
+    // no leading zeroes, as with `filename*00` the section is ambiguous
+    if idx.is_empty() || !idx.bytes().all(|b| b.is_ascii_digit())
+        || (idx.len() > 1 && idx.starts_with('0')) {
+        return None;
+    }
+
+    Some((idx.parse().ok()?, encoded))
+}
+
+/// Join the sections of an RFC 2231 continuation in order, returning the joined value and
+/// whether it's an extended value, or `None` if any section is missing or repeated.
+///
+/// If any section is percent-encoded, the plain sections are encoded to match. A charset can
+/// only be given on the first section; if that isn't encoded, UTF-8 is assumed.
+fn join_continuations(mut sections: Vec<(u32, bool, String)>) -> Option<(String, bool)> {
+    sections.sort_by_key(|&(idx, ..)| idx);
+
+    if sections.iter().enumerate().any(|(i, &(idx, ..))| idx as usize != i) {
+        return None;
+    }
+
+    if !sections.iter().any(|&(_, encoded, _)| encoded) {
+        return Some((sections.into_iter().map(|(.., val)| val).collect(), false));
+    }
+
+    let mut joined = if sections[0].1 { String::new() } else { "UTF-8''".into() };
+
+    for (_, encoded, val) in sections {
+        if encoded {
+            joined.push_str(&val);
+        } else {
+            percent_encode(&val, &mut joined);
+        }
+    }
+
+    Some((joined, true))
+}
+
+/// Percent-encode all but alphanumeric ASCII characters of `input` into `out`.
+fn percent_encode(input: &str, out: &mut String) {
+    use std::fmt::Write;
+
+    for b in input.bytes() {
+        if b.is_ascii_alphanumeric() {
+            out.push(b as char);
+        } else {
+            let _ = write!(out, "%{:02X}", b);
+        }
+    }
+}
+
+/// Parse the next `key=value` pair, returning the key, the value and the rest of the input.
+///
+/// Quoted values may contain backslash escapes (RFC 7230 `quoted-pair`) unless `html5_escapes`
+/// is set, as browsers don't use them.
+fn parse_keyval(input: &str, html5_escapes: bool) -> Option<(&str, Cow<'_, str>, &str)> {
     if input.trim().is_empty() { return None; }
 
     let (name, rest) = try_opt!(param_name(input));
-    let (val, rest) = try_opt!(param_val(rest));
+    let (val, rest) = param_val(rest, !html5_escapes);
 
     Some((name, val, rest))
 }
 
 fn param_name(input: &str) -> Option<(&str, &str)> {
-    let mut splits = input.trim_left_matches(&[' ', ';'][..]).splitn(2, '=');
+    let mut splits = input.trim_start_matches(&[' ', ';'][..]).splitn(2, '=');
 
     let name = try_opt!(splits.next()).trim();
     let rem = splits.next().unwrap_or("");
@@ -280,26 +680,100 @@ fn param_name(input: &str) -> Option<(&str, &str)> {
     Some((name, rem))
 }
 
-fn param_val(input: &str) -> Option<(&str, &str)> {
-    // continue until the opening quote or the terminating semicolon
-    let mut tk_splits = input.splitn(2, &['"', ';'][..]);
+fn param_val(input: &str, backslash_escapes: bool) -> (Cow<'_, str>, &str) {
+    let input = input.trim_start();
 
-    let token = try_opt!(tk_splits.next()).trim();
-    let rem = tk_splits.next().unwrap_or("");
+    let (val, rem) = match input.strip_prefix('"') {
+        Some(quoted) => quoted_string(quoted, backslash_escapes),
+        // the value doesn't have to be in quotes if it doesn't contain forbidden chars like `;`
+        None => {
+            let mut tk_splits = input.splitn(2, ';');
+            let token = tk_splits.next().unwrap_or("").trim();
+            (token.into(), tk_splits.next().unwrap_or(""))
+        }
+    };
+
+    (val, rem.trim_matches(&[' ', ';'][..]))
+}
 
-    // the value doesn't have to be in quotes if it doesn't contain forbidden chars like `;`
-    if !token.is_empty() {
-        return Some((token, rem.trim_matches(&[' ', ';'][..])));
+/// Parse the rest of a quoted string after the opening quote, returning the unescaped string and
+/// the input after the closing quote.
+fn quoted_string(input: &str, backslash_escapes: bool) -> (Cow<'_, str>, &str) {
+    let mut unescaped: Option<String> = None;
+    let mut chars = input.char_indices();
+
+    while let Some((idx, c)) = chars.next() {
+        match c {
+            '"' => {
+                let val = match unescaped {
+                    Some(string) => string.into(),
+                    None => input[..idx].into(),
+                };
+
+                return (val, &input[idx + 1 ..]);
+            },
+            '\\' if backslash_escapes => {
+                let string = unescaped.get_or_insert_with(|| input[..idx].to_string());
+
+                // a trailing backslash is kept as-is
+                string.push(chars.next().map_or('\\', |(_, c)| c));
+            },
+            c => if let Some(ref mut string) = unescaped { string.push(c) },
+        }
+    }
+
+    warn!("unterminated quote: {:?}", input);
+
+    (unescaped.map_or(input.into(), Into::into), "")
+}
+
+/// Reverse the escaping of `"`, CR and LF in field names and filenames which browsers apply
+/// according to the [HTML5 spec][spec], if `enabled`.
+///
+/// [spec]: https://html.spec.whatwg.org/multipage/form-control-infrastructure.html#multipart-form-data
+fn unescape_html5(val: Cow<'_, str>, enabled: bool) -> Cow<'_, str> {
+    if !enabled || !val.contains('%') {
+        return val;
     }
 
-    // continue until the terminating quote
-    let mut qt_splits = rem.splitn(2, '"');
+    let mut out = String::with_capacity(val.len());
+    let mut rem = &*val;
 
-    let qstr = try_opt!(qt_splits.next()).trim();
-    let rem = qt_splits.next().unwrap_or_else(|| { warn!("unterminated quote: {:?}", qstr); "" })
-        .trim_matches(&[' ', ';'][..]);
+    while let Some(idx) = rem.find('%') {
+        out.push_str(&rem[..idx]);
+        rem = &rem[idx..];
 
-    Some((qstr, rem))
+        let escaped = rem.get(..3).and_then(|esc| match esc {
+            "%22" => Some('"'),
+            _ if esc.eq_ignore_ascii_case("%0D") => Some('\r'),
+            _ if esc.eq_ignore_ascii_case("%0A") => Some('\n'),
+            _ => None,
+        });
+
+        match escaped {
+            Some(c) => { out.push(c); rem = &rem[3..]; },
+            None => { out.push('%'); rem = &rem[1..]; },
+        }
+    }
+
+    out.push_str(rem);
+    out.into()
+}
+
+#[test]
+fn test_extended_value() {
+    let val = ExtendedValue::parse("iso-8859-1''%A3%20rates").unwrap();
+    assert_eq!(val.value, "\u{A3} rates");
+    assert_eq!(val.charset, "iso-8859-1");
+    assert_eq!(val.language, None);
+
+    assert_eq!(ExtendedValue::parse("UTF-8''%E2%82%AC").unwrap().value, "\u{20AC}");
+
+    // invalid escapes
+    assert_eq!(ExtendedValue::parse("UTF-8''%E2%8"), None);
+    assert_eq!(ExtendedValue::parse("UTF-8''%ZZ"), None);
+    // missing language
+    assert_eq!(ExtendedValue::parse("UTF-8'value"), None);
 }
 
 #[test]
@@ -311,31 +785,64 @@ fn test_header_end_split() {
     assert_eq!(header_end_split(b"FOOBAR", b"\r\n\r\n"), None);
 }
 
+#[test]
+fn test_strict_header_end() {
+    assert_eq!(strict_header_end(b"", b"Content-Type: text/plain\r\n\r\ndata"), Some(28));
+    // no headers at all
+    assert_eq!(strict_header_end(b"", b"\r\ndata\r\n\r\n"), Some(2));
+    assert_eq!(strict_header_end(b"\r", b"\ndata"), Some(1));
+    // split across chunks
+    assert_eq!(strict_header_end(b"Content-Type: text/plain\r\n", b"\r\ndata"), Some(2));
+    assert_eq!(strict_header_end(b"Content-Type: text", b"/plain\r\ndata"), None);
+}
+
 #[test]
 fn test_parse_keyval() {
     assert_eq!(
-        parse_keyval("name = field; x-attr = \"some;value\"; filename = file.bin"),
-        Some(("name", "field", "x-attr = \"some;value\"; filename = file.bin"))
+        parse_keyval("name = field; x-attr = \"some;value\"; filename = file.bin", false),
+        Some(("name", "field".into(), "x-attr = \"some;value\"; filename = file.bin"))
+    );
+
+    assert_eq!(
+        parse_keyval("x-attr = \"some;value\"; filename = file.bin", false),
+        Some(("x-attr", "some;value".into(), "filename = file.bin"))
+    );
+
+    assert_eq!(
+        parse_keyval("filename = file.bin", false),
+        Some(("filename", "file.bin".into(), ""))
     );
 
+    assert_eq!(parse_keyval("", false), None);
+
+    // backslash escapes
     assert_eq!(
-        parse_keyval("x-attr = \"some;value\"; filename = file.bin"),
-        Some(("x-attr", "some;value", "filename = file.bin"))
+        parse_keyval(r#"filename="a \"quoted\" name.txt"; name=field"#, false),
+        Some(("filename", r#"a "quoted" name.txt"#.into(), "name=field"))
     );
 
+    // browsers don't escape backslashes
     assert_eq!(
-        parse_keyval("filename = file.bin"),
-        Some(("filename", "file.bin", ""))
+        parse_keyval(r#"filename="C:\Users\file.txt""#, true),
+        Some(("filename", r#"C:\Users\file.txt"#.into(), ""))
     );
 
-    assert_eq!(parse_keyval(""), None);
+    // unterminated quote
+    assert_eq!(parse_keyval(r#"name="field"#, false), Some(("name", "field".into(), "")));
+}
+
+#[test]
+fn test_unescape_html5() {
+    assert_eq!(unescape_html5("a%22quoted%22%0D%0aname".into(), true), "a\"quoted\"\r\nname");
+    assert_eq!(unescape_html5("100%25%".into(), true), "100%25%");
+    assert_eq!(unescape_html5("a%22b".into(), false), "a%22b");
 }
 
 #[test]
 fn test_parse_headers() {
-    use StringError;
+    use crate::mock::StringError;
 
-    let parse_headers = parse_headers::<StringError>;
+    let parse_headers = |bytes| parse_headers::<StringError>(bytes, &Limits::default(), false, None);
 
     assert_eq!(
         parse_headers(b"Content-Disposition: form-data; name = \"field\"\r\n\r\n"),
@@ -427,6 +934,7 @@ fn test_parse_headers() {
         Ok(FieldHeaders {
             name: "field".into(),
             filename: Some("file.bin".into()),
+            filename_plain: Some("file.bin".into()),
             content_type: Some(mime::APPLICATION_OCTET_STREAM),
             .. FieldHeaders::default()
         })
@@ -439,11 +947,82 @@ fn test_parse_headers() {
         Ok(FieldHeaders {
             name: "field".into(),
             filename: Some("file.bin".into()),
+            filename_plain: Some("file.bin".into()),
             content_type: Some(mime::APPLICATION_OCTET_STREAM),
             .. FieldHeaders::default()
         })
     );
 
+    // extended filename parameter, preferred over the plain one
+    assert_eq!(
+        parse_headers(b"Content-Disposition: form-data; name = field; \
+                        filename* = UTF-8'en'na%C3%AFve.txt; filename = naive.txt\r\n\r\n"),
+        Ok(FieldHeaders {
+            name: "field".into(),
+            filename: Some("na\u{EF}ve.txt".into()),
+            filename_plain: Some("naive.txt".into()),
+            filename_ext: Some(ExtendedValue {
+                charset: "UTF-8".into(),
+                language: Some("en".into()),
+                value: "na\u{EF}ve.txt".into(),
+                raw: "UTF-8'en'na%C3%AFve.txt".into(),
+            }),
+            .. FieldHeaders::default()
+        })
+    );
+
+    // undecodable extended filename parameter, falls back to the plain one
+    assert_eq!(
+        parse_headers(b"Content-Disposition: form-data; name = field; \
+                        filename* = UTF-8''na%C3ve.txt; filename = naive.txt\r\n\r\n"),
+        Ok(FieldHeaders {
+            name: "field".into(),
+            filename: Some("naive.txt".into()),
+            filename_plain: Some("naive.txt".into()),
+            .. FieldHeaders::default()
+        })
+    );
+
+    // RFC 2231 continuations of the extended filename, out of order and partly unencoded
+    assert_eq!(
+        parse_headers(b"Content-Disposition: form-data; name = field; filename*1*=%AFve; \
+                        filename*0*=UTF-8''na%C3; filename*2=\".txt\"; filename = naive.txt\r\n\r\n"),
+        Ok(FieldHeaders {
+            name: "field".into(),
+            filename: Some("na\u{EF}ve.txt".into()),
+            filename_plain: Some("naive.txt".into()),
+            filename_ext: Some(ExtendedValue {
+                charset: "UTF-8".into(),
+                language: None,
+                value: "na\u{EF}ve.txt".into(),
+                raw: "UTF-8''na%C3%AFve%2Etxt".into(),
+            }),
+            .. FieldHeaders::default()
+        })
+    );
+
+    // continuations of a plain filename
+    assert_eq!(
+        parse_headers(b"Content-Disposition: form-data; name = field; \
+                        filename*0=\"a long \"; filename*1=\"name.txt\"\r\n\r\n"),
+        Ok(FieldHeaders {
+            name: "field".into(),
+            filename: Some("a long name.txt".into()),
+            filename_plain: Some("a long name.txt".into()),
+            .. FieldHeaders::default()
+        })
+    );
+
+    // a missing section discards the continuations
+    assert_eq!(
+        parse_headers(b"Content-Disposition: form-data; name = field; \
+                        filename*0*=UTF-8''a; filename*2*=b\r\n\r\n"),
+        Ok(FieldHeaders {
+            name: "field".into(),
+            .. FieldHeaders::default()
+        })
+    );
+
     // quoted parameter with semicolon (allowed by spec)
     assert_eq!(
         parse_headers(b"Content-Disposition: form-data; name = field; x-attr = \"some;value\"; \
@@ -451,29 +1030,82 @@ fn test_parse_headers() {
         Ok(FieldHeaders {
             name: "field".into(),
             filename: Some("file.bin".into()),
+            filename_plain: Some("file.bin".into()),
             content_type: None,
             .. FieldHeaders::default()
         })
-    )
+    );
+
+    let headers = parse_headers(b"Content-Disposition: form-data; name = field\r\n\
+                                  X-Header: value\r\n\r\n").unwrap();
+    assert_eq!(headers.ext.len(), 1);
+    assert_eq!(headers.ext["x-header"], "value");
+
+    // parts of a nested body may omit `Content-Disposition` or the name
+    let parse_nested = |bytes| self::parse_headers::<StringError>(bytes, &Limits::default(), false,
+                                                            Some("parent"));
+
+    assert_eq!(
+        parse_nested(b"Content-Disposition: file; filename = file.bin\r\n\r\n"),
+        Ok(FieldHeaders {
+            name: "parent".into(),
+            filename: Some("file.bin".into()),
+            filename_plain: Some("file.bin".into()),
+            .. FieldHeaders::default()
+        })
+    );
+
+    assert_eq!(
+        parse_nested(b"Content-Type: text/plain\r\n\r\n"),
+        Ok(FieldHeaders {
+            name: "parent".into(),
+            content_type: Some(mime::TEXT_PLAIN),
+            .. FieldHeaders::default()
+        })
+    );
+}
+
+#[test]
+fn test_lenient_header_end() {
+    assert_eq!(lenient_header_end(b"", b"Content-Type: text/plain\n\ndata"), Some(26));
+    assert_eq!(lenient_header_end(b"", b"Content-Type: text/plain\r\n\r\ndata"), Some(28));
+    assert_eq!(lenient_header_end(b"", b"Content-Type: text/plain\n\r\ndata"), Some(27));
+    // no headers at all
+    assert_eq!(lenient_header_end(b"", b"\r\ndata"), Some(2));
+    // split across chunks
+    assert_eq!(lenient_header_end(b"Content-Type: text/plain\n", b"\ndata"), Some(1));
+    assert_eq!(lenient_header_end(b"Content-Type: text/plain\r\n\r", b"\ndata"), Some(1));
+    assert_eq!(lenient_header_end(b"Content-Type: text/plain\r", b"\n\r\n"), Some(3));
+    assert_eq!(lenient_header_end(b"Content-Type: text", b"/plain\r\ndata"), None);
+    assert_eq!(lenient_header_end(b"\r", b"\ndata"), Some(1));
+    // a CR alone doesn't end a line
+    assert_eq!(lenient_header_end(b"", b"Content-Type: text/plain\r\r\ndata\n"), None);
 }
 
 #[test]
 fn test_parse_headers_errors() {
-    use StringError;
+    use crate::mock::StringError;
 
-    let parse_headers = parse_headers::<StringError>;
+    let parse_headers = |bytes| parse_headers::<StringError>(bytes, &Limits::default(), false, None);
 
     // missing content-disposition
     assert_eq!(
         parse_headers(b"Content-Type: application/octet-stream\r\n\r\n").unwrap_err(),
-        "missing `Content-Disposition` header on a field \
-         (Content-Type: application/octet-stream) in this multipart request"
+        "missing `Content-Disposition` header on a field of a multipart/form-data request"
     );
 
     // duplicate content-disposition
     assert_eq!(
         parse_headers(b"Content-Disposition: form-data; name = field\r\n\
                         Content-Disposition: form-data; name = field2\r\n\r\n").unwrap_err(),
-        "duplicate `Content-Disposition` header on field: field"
+        "invalid field headers: duplicate `Content-Disposition` header on field: field"
+    );
+
+    // too many headers
+    assert_eq!(
+        parse_headers(b"Content-Disposition: form-data; name = field\r\n\
+                        X-Header-1: 1\r\nX-Header-2: 2\r\nX-Header-3: 3\r\nX-Header-4: 4\r\n\r\n")
+            .unwrap_err(),
+        "field header count limit of 4 exceeded"
     );
 }
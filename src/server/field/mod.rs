@@ -4,43 +4,97 @@
 // http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
 // http://opensource.org/licenses/MIT>, at your option. This file may not be
 // copied, modified, or distributed except according to those terms.
-use futures::{Stream, Poll};
+use std::pin::Pin;
+use std::task::{Context, Poll, Waker};
 
-use std::rc::Rc;
-use std::str;
-
-use server::boundary::BoundaryFinder;
-use server::Internal;
+use crate::server::{Internal, Multipart};
+use crate::server::limits::{check_limit, LimitKind};
+use crate::server::spool::Spool;
+use crate::server::sync::{Lock, Shared};
 
+use std::error::Error;
 use std::fmt;
 
-use {BodyChunk, StreamError};
+use futures::{Stream, TryStream};
+
+#[cfg(feature = "encoding_rs")]
+use encoding_rs::Encoding;
+
+use crate::{BodyChunk, StreamError};
 
 mod collect;
+mod decode;
+mod filename;
+#[cfg(feature = "digest")]
+mod hash;
 mod headers;
+mod read;
+mod save;
+mod sniff;
 
-pub use self::headers::{FieldHeaders, ReadHeaders};
+pub use self::filename::FilenamePolicy;
+
+#[cfg(feature = "digest")]
+pub use self::hash::{to_hex, Algorithm, Digests, HashField};
+
+pub use self::headers::{ExtendedValue, FieldHeaders, PartHeaders, ReadHeaders};
 
 pub use self::collect::{ReadTextField, TextField};
 
-pub(super) fn new_field<S: Stream>(headers: FieldHeaders, internal: Rc<Internal<S>>) -> Field<S> {
-    let headers = Rc::new(headers);
+pub use self::decode::DecodeField;
+
+pub use self::read::{AsyncReadField, ReadField};
+
+pub use self::save::{SaveField, SavedField};
+
+pub use self::sniff::{sniff_content_type, SniffField, SniffPolicy, Sniffed, SNIFF_LEN};
+
+pub(super) fn new_field<S: TryStream>(headers: FieldHeaders, internal: Shared<Internal<S>>) -> Field<S> {
+    let headers = Shared::new(headers);
 
     Field {
         headers: headers.clone(),
-        data: FieldData {
-            headers, internal
-        },
-        _priv: (),
+        data: new_data(headers, internal),
+    }
+}
+
+pub(super) fn new_part<S: TryStream>(headers: PartHeaders, internal: Shared<Internal<S>>) -> Part<S> {
+    Part {
+        data: new_data(Shared::new(headers.to_field_headers()), internal),
+        headers: Shared::new(headers),
     }
 }
 
+fn new_data<S: TryStream>(headers: Shared<FieldHeaders>, internal: Shared<Internal<S>>) -> FieldData<S> {
+    let state = Shared::new(Lock::new(FieldState { size: 0, spool: None, error: None, waker: None }));
+
+    internal.field_in_flight.set(true);
+    *internal.current_field.lock() = Some(state.clone());
+
+    FieldData { headers, internal, state }
+}
+
+pub(crate) type SharedState<C, E> = Shared<Lock<FieldState<C, E>>>;
+
+/// The state of a field's data shared with `Multipart`, so it can spool the rest of the field.
+pub(crate) struct FieldState<C, E> {
+    /// The number of bytes of the field read from the request so far.
+    pub size: u64,
+    /// The unread data of the field, if `Multipart` has moved on to the next one.
+    pub spool: Option<Spool<C>>,
+    /// The error which stopped `Multipart` spooling the field, yielded after the spooled data.
+    pub error: Option<E>,
+    /// The task reading the field, to wake when `Multipart` spools more of it.
+    pub waker: Option<Waker>,
+}
+
 /// A single field in a multipart stream.
 ///
 /// The data of the field is provided as a `Stream` impl in the `data` field.
 ///
 /// To avoid the next field being initialized before this one is done being read
-/// (in a linear stream), only one instance per `Multipart` instance is allowed at a time.
+/// (in a linear stream), only one instance per `Multipart` instance is allowed at a time,
+/// unless spooling is enabled with `Multipart::set_spooling()`.
 /// A `Drop` implementation on `FieldData` is used to notify `Multipart` that this field is done
 /// being read, thus:
 ///
@@ -49,15 +103,17 @@ pub(super) fn new_field<S: Stream>(headers: FieldHeaders, internal: Rc<Internal<
 /// other mechanism), then the parent `Multipart` will never be able to yield the next field in the
 /// stream. The task waiting on the `Multipart` will also never be notified, which, depending on the
 /// event loop/reactor/executor implementation, may cause a deadlock.
-pub struct Field<S: Stream> {
+#[non_exhaustive]
+pub struct Field<S: TryStream> {
     /// The headers of this field, including the name, filename, and `Content-Type`, if provided.
-    pub headers: Rc<FieldHeaders>,
+    ///
+    /// This is an `Rc`, or an `Arc` if the `use_arc` feature is enabled.
+    pub headers: Shared<FieldHeaders>,
     /// The data of this field in the request, represented as a stream of chunks.
     pub data: FieldData<S>,
-    _priv: (),
 }
 
-impl<S: Stream> fmt::Debug for Field<S> {
+impl<S: TryStream> fmt::Debug for Field<S> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         f.debug_struct("Field")
             .field("headers", &self.headers)
@@ -66,12 +122,40 @@ impl<S: Stream> fmt::Debug for Field<S> {
     }
 }
 
+/// A single part of a multipart body of any subtype, as yielded by `Parts`.
+///
+/// The same restrictions apply as to `Field`: only one `Part` is yielded at a time, and the
+/// next can't be read until the `FieldData` of this one has been dropped.
+#[non_exhaustive]
+pub struct Part<S: TryStream> {
+    /// All headers of this part, and its disposition, name and filename if provided.
+    ///
+    /// This is an `Rc`, or an `Arc` if the `use_arc` feature is enabled.
+    pub headers: Shared<PartHeaders>,
+    /// The data of this part, represented as a stream of chunks.
+    ///
+    /// This has all the adaptors of a field's data; the `FieldHeaders` they carry are derived
+    /// from `headers` and have an empty name if none was provided.
+    pub data: FieldData<S>,
+}
+
+impl<S: TryStream> fmt::Debug for Part<S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Part")
+            .field("headers", &self.headers)
+            .field("data", &"<FieldData>")
+            .finish()
+    }
+}
+
 /// The data of a field in a multipart stream, as a stream of chunks.
 ///
-/// It may be read to completion via the `Stream` impl, or collected to a string with `read_text()`.
+/// It may be read to completion via the `Stream` impl, collected to a string with `read_text()`,
+/// or saved to a file with `save()`.
 ///
 /// To avoid the next field being initialized before this one is done being read
-/// (in a linear stream), only one instance per `Multipart` instance is allowed at a time.
+/// (in a linear stream), only one instance per `Multipart` instance is allowed at a time,
+/// unless spooling is enabled with `Multipart::set_spooling()`.
 /// A `Drop` implementation on `FieldData` is used to notify `Multipart` that this field is done
 /// being read, thus:
 ///
@@ -81,14 +165,19 @@ impl<S: Stream> fmt::Debug for Field<S> {
 /// `Multipart` will also never be notified, which, depending on the event loop/reactor/executor
 /// implementation, may cause a deadlock.
 // N.B.: must **never** be Clone!
-pub struct FieldData<S: Stream> {
-    headers: Rc<FieldHeaders>,
-    internal: Rc<Internal<S>>,
+pub struct FieldData<S: TryStream> {
+    headers: Shared<FieldHeaders>,
+    internal: Shared<Internal<S>>,
+    state: SharedState<S::Ok, S::Error>,
 }
 
-impl<S: Stream> FieldData<S> where S::Item: BodyChunk, S::Error: StreamError {
+impl<S: TryStream + Unpin> FieldData<S> where S::Ok: BodyChunk, S::Error: StreamError {
     /// Get a `Future` which attempts to read the field data to a string.
     ///
+    /// ```rust,ignore
+    /// let text = field.data.read_text().await?;
+    /// ```
+    ///
     /// If a field is meant to be read as text, it will either have no content-type or
     /// will have a content-type that starts with "text"; `FieldHeaders::is_text()` is
     /// provided to help determine this.
@@ -99,45 +188,252 @@ impl<S: Stream> FieldData<S> where S::Item: BodyChunk, S::Error: StreamError {
     /// on `ReadTextField` if desired.
     ///
     /// ### Charset
-    /// For simplicity, the default UTF-8 character set is assumed, as defined in
+    /// By default, the UTF-8 character set is assumed, as defined in
     /// [IETF RFC 7578 Section 5.1.2](https://tools.ietf.org/html/rfc7578#section-5.1.2).
     /// If the field body cannot be decoded as UTF-8, an error is returned.
     ///
-    /// Decoding text in a different charset (except ASCII which
-    /// is compatible with UTF-8) is, currently, beyond the scope of this crate. However, as a
-    /// convention, web browsers will send `multipart/form-data` requests in the same
-    /// charset as that of the document (page or frame) containing the form, so if you only serve
-    /// ASCII/UTF-8 pages then you won't have to worry too much about decoding strange charsets.
+    /// With the `encoding_rs` feature, the field is instead decoded with the charset named by the
+    /// `charset` parameter of its `Content-Type`, or if there is none, the value of a `_charset_`
+    /// field read earlier in the request ([RFC 7578 Section 4.6][4.6]), falling back to UTF-8.
+    /// Malformed byte sequences in any charset are an error. The charset can also be set
+    /// explicitly with `ReadTextField::charset()`.
+    ///
+    /// Without that feature, decoding text in a different charset (except ASCII which is
+    /// compatible with UTF-8) is left to the user. However, as a convention, web browsers will
+    /// send `multipart/form-data` requests in the same charset as that of the document
+    /// (page or frame) containing the form, so if you only serve ASCII/UTF-8 pages then you
+    /// won't have to worry too much about decoding strange charsets.
+    ///
+    /// [4.6]: https://tools.ietf.org/html/rfc7578#section-4.6
     pub fn read_text(self) -> ReadTextField<Self> {
         if !self.headers.is_text() {
             debug!("attempting to read a non-text field as text: {:?}", self.headers);
         }
 
+        #[cfg(feature = "encoding_rs")]
+        {
+            let encoding = self.encoding();
+            collect::read_text(self.headers.clone(), self).charset(encoding)
+        }
+
+        #[cfg(not(feature = "encoding_rs"))]
         collect::read_text(self.headers.clone(), self)
     }
 
-    fn stream_mut(&mut self) -> &mut BoundaryFinder<S> {
-        debug_assert!(Rc::strong_count(&self.internal) <= 2,
-                      "More than two copies of an `Rc<Internal>` at one time");
+    /// The charset of this field: from its `Content-Type`, then the request's `_charset_` field,
+    /// then UTF-8.
+    #[cfg(feature = "encoding_rs")]
+    fn encoding(&self) -> &'static Encoding {
+        if let Some(charset) = self.headers.charset() {
+            match Encoding::for_label(charset.as_str().as_bytes()) {
+                Some(encoding) => return encoding,
+                None => warn!("unknown charset {:?} on field {:?}, falling back to the request \
+                               charset", charset.as_str(), self.headers.name),
+            }
+        }
+
+        self.internal.stream.lock().charset.unwrap_or(encoding_rs::UTF_8)
+    }
+
+    /// Get a `Future` which saves the field data to a file.
+    ///
+    /// ```rust,ignore
+    /// let saved = field.data.save().size_limit(1024 * 1024).await?;
+    /// println!("saved {} bytes to {:?}", saved.size, saved.path);
+    /// ```
+    ///
+    /// By default, the file is written to a new temporary directory which is deleted along with
+    /// the returned `SavedField`; call `with_dir()` on the `SaveField` to choose the directory.
+    ///
+    /// A default size limit of 8 MiB is set. Data past the limit is discarded and
+    /// `SavedField::truncated` is set; the limit can be changed on `SaveField`.
+    ///
+    /// If an error occurs, the partially written file is deleted.
+    pub fn save(self) -> SaveField<Self> {
+        save::save(self.headers.clone(), self)
+    }
+
+    /// Wrap the field data in a `Stream` which decodes it according to its
+    /// `Content-Transfer-Encoding` header, yielding `Vec<u8>` chunks.
+    ///
+    /// ```rust,ignore
+    /// let data: Vec<u8> = field.data.decode().try_concat().await?;
+    /// ```
+    ///
+    /// Browsers don't use transfer encodings, but mail-derived and some older clients send
+    /// parts encoded as `base64` or `quoted-printable`. Those are decoded, even when an encoded
+    /// unit is split across chunks; fields with no header or an identity encoding (`7bit`,
+    /// `8bit` or `binary`) are passed through. Malformed data or an unknown encoding yields an
+    /// error.
+    pub fn decode(self) -> DecodeField<Self> {
+        decode::decode(self.headers.clone(), self)
+    }
+
+    /// Parse the field data as a nested multipart body, if it has a `multipart/*` content type
+    /// with a boundary; otherwise `self` is returned.
+    ///
+    /// ```rust,ignore
+    /// let mut files = field.data.into_multipart().map_err(|_| "expected multipart/mixed")?;
+    ///
+    /// while let Some(file) = files.try_next().await? {
+    ///     println!("file: {:?}", file.headers.filename);
+    /// }
+    /// ```
+    ///
+    /// Clients following [IETF RFC 2388 Section 4.5][4.5] send several files for one field
+    /// this way, in a `multipart/mixed` body. Its parts may use a `file` disposition
+    /// (`Content-Disposition: file; filename="file1.txt"`) and omit the `name` parameter, in
+    /// which case they take the name of this field.
+    ///
+    /// The limits and `ParseMode` of the parent `Multipart` also apply separately to the nested
+    /// body; the parent can't yield another field until the returned `Multipart` has been dropped.
+    ///
+    /// [4.5]: https://tools.ietf.org/html/rfc2388#section-4.5
+    pub fn into_multipart(self) -> Result<Multipart<Self>, Self> {
+        let boundary = match self.headers.content_type {
+            Some(ref mime) if mime.type_() == mime::MULTIPART =>
+                mime.get_param(mime::BOUNDARY).map(|bnd| bnd.as_str().to_string()),
+            _ => None,
+        };
 
-        // This is safe as we have guaranteed exclusive access, the lifetime is tied to `self`,
-        // and is never null.
-        unsafe { &mut *self.internal.stream.as_ptr() }
+        let boundary = match boundary {
+            Some(boundary) => boundary,
+            None => return Err(self),
+        };
+
+        let (limits, mode) = {
+            let stream = self.internal.stream.lock();
+            (stream.limits.clone(), stream.mode)
+        };
+        let name = self.headers.name.clone();
+
+        let mut multipart = Multipart::nested(self, boundary, limits, name);
+        multipart.set_parse_mode(mode);
+        Ok(multipart)
+    }
+
+    /// Wrap the field data in a `Stream` which detects its type from the first `SNIFF_LEN` bytes
+    /// and checks it against the declared `Content-Type` and filename extension.
+    ///
+    /// ```rust,ignore
+    /// let mut data = field.data.sniff(SniffPolicy::Reject);
+    /// let first = data.try_next().await?;
+    /// println!("detected type: {:?}", data.sniffed().and_then(|s| s.content_type.as_ref()));
+    /// ```
+    ///
+    /// With `SniffPolicy::Reject`, a field whose data contradicts its headers (such as an
+    /// executable uploaded as `image/png`) yields an error instead of any data; with
+    /// `SniffPolicy::Flag` the mismatch is logged and reported by `Sniffed::is_mismatch()`.
+    pub fn sniff(self, policy: SniffPolicy) -> SniffField<Self> {
+        sniff::sniff(self.headers.clone(), self, policy)
+    }
+
+    /// Wrap the field data in a `Stream` which computes digests of it with each of
+    /// `algorithms` as it is read, and checks its `Content-MD5` header if present.
+    ///
+    /// ```rust,ignore
+    /// let mut data = field.data.hashed(&[Algorithm::Sha256, Algorithm::Md5]);
+    ///
+    /// while let Some(chunk) = data.try_next().await? {
+    ///     file.write_all(chunk.as_slice())?;
+    /// }
+    ///
+    /// let sha256 = to_hex(&data.digests().unwrap().sha256.unwrap());
+    /// ```
+    ///
+    /// The digests are available from `HashField::digests()` once the stream has ended. If the
+    /// field has a `Content-MD5` header which doesn't match the data, an error is yielded at
+    /// the end of the stream instead.
+    #[cfg(feature = "digest")]
+    pub fn hashed(self, algorithms: &[Algorithm]) -> HashField<Self> {
+        hash::hashed(self.headers.clone(), self, algorithms)
     }
 }
 
-impl<S: Stream> Stream for FieldData<S> where S::Item: BodyChunk, S::Error: StreamError {
-    type Item = S::Item;
-    type Error = S::Error;
+impl<S: TryStream + Unpin> FieldData<S> where S::Ok: BodyChunk, S::Error: StreamError + Into<Box<dyn Error + Send + Sync>> {
+    /// Wrap the field data in an adaptor implementing `AsyncRead` and `AsyncBufRead`.
+    ///
+    /// ```rust,ignore
+    /// let mut reader = field.data.into_async_read();
+    /// futures::io::copy(&mut reader, &mut writer).await?;
+    /// ```
+    ///
+    /// This allows field data to be passed to libraries that read from an I/O object, such as
+    /// decoders and archive readers. Errors from the body stream (including limit errors) are
+    /// converted to `io::Error`. The traits are those of the `futures` crate; for Tokio's, use
+    /// the `compat()` adaptor of `tokio-util`.
+    pub fn into_async_read(self) -> AsyncReadField<Self> {
+        read::async_read(self.headers.clone(), self)
+    }
+
+    /// Wrap the field data in an adaptor implementing `Read` and `BufRead` by blocking the
+    /// current thread until data is available.
+    ///
+    /// ```rust,ignore
+    /// let reader = field.data.into_read();
+    /// let image = tokio::task::spawn_blocking(move || image::load(reader, format)).await?;
+    /// ```
+    ///
+    /// This must only be used on a thread where blocking is allowed. Moving the reader to
+    /// another thread requires the `use_arc` feature.
+    pub fn into_read(self) -> ReadField<Self> {
+        self.into_async_read().into_blocking()
+    }
+}
+
+impl<S: TryStream + Unpin> Stream for FieldData<S> where S::Ok: BodyChunk, S::Error: StreamError {
+    type Item = Result<S::Ok, S::Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
 
-    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
-        self.stream_mut().body_chunk()
+        let mut state = this.state.lock();
+        let state = &mut *state;
+
+        match state.spool {
+            Some(ref mut spool) if !spool.is_empty() || spool.is_complete() => match spool.read()? {
+                Some(chunk) => Poll::Ready(Some(Ok(chunk))),
+                None => Poll::Ready(state.error.take().map(Err)),
+            },
+            // `Multipart` only touches the stream while we exist to spool the rest of the field,
+            // which it does in order after any data we've already read
+            _ => {
+                let mut stream = this.internal.stream.lock();
+                let res = stream.body_chunk(cx);
+
+                match res {
+                    // `Multipart` may take over the stream to spool the rest of the field
+                    Poll::Pending => state.waker = Some(cx.waker().clone()),
+                    Poll::Ready(ref next) => {
+                        // `Multipart` may be waiting on the stream to spool the field, but this
+                        // read took over its waker
+                        this.internal.wake_waiting_task();
+
+                        if let Some(Ok(ref chunk)) = *next {
+                            state.size += chunk.len() as u64;
+                            check_limit(LimitKind::FieldSize, state.size, stream.limits.field_size)?;
+                        }
+                    },
+                }
+
+                res
+            },
+        }
+    }
+}
+
+impl<S: TryStream> fmt::Debug for FieldData<S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("FieldData")
+            .field("headers", &self.headers)
+            .field("size", &self.state.lock().size)
+            .finish()
     }
 }
 
 /// Notifies a task waiting on the parent `Multipart` that another field is available.
-impl<S: Stream> Drop for FieldData<S> {
+impl<S: TryStream> Drop for FieldData<S> {
     fn drop(&mut self) {
-        self.internal.notify_task();
+        self.internal.field_done(&self.state);
     }
 }
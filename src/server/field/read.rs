@@ -0,0 +1,204 @@
+// Copyright 2017 `multipart-async` Crate Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+//! Reading field data through the `AsyncRead` and `Read` traits.
+use futures::executor::block_on;
+use futures::future::poll_fn;
+use futures::io::{AsyncBufRead, AsyncRead};
+
+use std::error::Error;
+use std::fmt;
+use std::io::{self, BufRead, Read};
+
+use crate::BodyChunk;
+
+use crate::server::sync::Shared;
+
+use super::FieldHeaders;
+
+use crate::helpers::*;
+
+/// Convert an error of the body stream to `io::Error`, unwrapping it if it already is one.
+fn into_io_error<E: Into<Box<dyn Error + Send + Sync>>>(err: E) -> io::Error {
+    match err.into().downcast::<io::Error>() {
+        Ok(err) => *err,
+        Err(err) => io::Error::other(err),
+    }
+}
+
+/// An adaptor implementing `AsyncRead` and `AsyncBufRead` for field data, returned by
+/// `FieldData::into_async_read()`.
+///
+/// Chunks are read from the field as needed; a read into a buffer smaller than the current
+/// chunk takes only part of it and leaves the rest for the next read. Errors from the body
+/// stream are returned as `io::Error`: an `io::Error` is returned as-is, and anything else is
+/// wrapped with `io::ErrorKind::Other`.
+pub struct AsyncReadField<S: TryStream> {
+    stream: S,
+    chunk: Option<S::Ok>,
+    pos: usize,
+    finished: bool,
+    /// The headers for the original field, provided as a convenience.
+    pub headers: Shared<FieldHeaders>,
+}
+
+pub fn async_read<S: TryStream>(headers: Shared<FieldHeaders>, data: S) -> AsyncReadField<S> {
+    AsyncReadField { stream: data, chunk: None, pos: 0, finished: false, headers }
+}
+
+impl<S: TryStream + Unpin> AsyncReadField<S> where S::Ok: BodyChunk, S::Error: Into<Box<dyn Error + Send + Sync>> {
+    /// Read the next non-empty chunk if the current one has been consumed.
+    fn poll_fill(&mut self, cx: &mut Context) -> Poll<io::Result<()>> {
+        while !self.finished && self.buffer().is_empty() {
+            match ready!(poll_next(&mut self.stream, cx)) {
+                Some(Ok(chunk)) => {
+                    self.chunk = Some(chunk);
+                    self.pos = 0;
+                },
+                Some(Err(e)) => return Poll::Ready(Err(into_io_error(e))),
+                None => {
+                    self.chunk = None;
+                    self.finished = true;
+                },
+            }
+        }
+
+        ready_ok(())
+    }
+
+    /// The unread part of the current chunk.
+    fn buffer(&self) -> &[u8] {
+        self.chunk.as_ref().map_or(&[], |chunk| &chunk.as_slice()[self.pos..])
+    }
+
+    fn consume_buffer(&mut self, amt: usize) {
+        self.pos = (self.pos + amt).min(self.chunk.as_ref().map_or(0, BodyChunk::len));
+    }
+
+    /// Convert this into a blocking reader; see `FieldData::into_read()`.
+    pub fn into_blocking(self) -> ReadField<S> {
+        ReadField { inner: self }
+    }
+}
+
+impl<S: TryStream + Unpin> AsyncRead for AsyncReadField<S>
+where S::Ok: BodyChunk + Unpin, S::Error: Into<Box<dyn Error + Send + Sync>> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+
+        if buf.is_empty() {
+            return ready_ok(0);
+        }
+
+        ready!(this.poll_fill(cx)?);
+
+        let data = this.buffer();
+        let len = data.len().min(buf.len());
+        buf[..len].copy_from_slice(&data[..len]);
+        this.consume_buffer(len);
+
+        ready_ok(len)
+    }
+}
+
+impl<S: TryStream + Unpin> AsyncBufRead for AsyncReadField<S>
+where S::Ok: BodyChunk + Unpin, S::Error: Into<Box<dyn Error + Send + Sync>> {
+    fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<&[u8]>> {
+        let this = self.get_mut();
+        ready!(this.poll_fill(cx)?);
+        ready_ok(this.buffer())
+    }
+
+    fn consume(self: Pin<&mut Self>, amt: usize) {
+        self.get_mut().consume_buffer(amt)
+    }
+}
+
+impl<S: TryStream> fmt::Debug for AsyncReadField<S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("AsyncReadField")
+            .field("headers", &self.headers)
+            .field("pos", &self.pos)
+            .field("finished", &self.finished)
+            .finish()
+    }
+}
+
+/// An adaptor implementing `Read` and `BufRead` for field data by blocking the current thread,
+/// returned by `FieldData::into_read()`.
+///
+/// Each read blocks until the body stream yields data, so this must not be used on a thread
+/// running an asynchronous executor; use `AsyncReadField` there instead.
+pub struct ReadField<S: TryStream> {
+    inner: AsyncReadField<S>,
+}
+
+impl<S: TryStream> ReadField<S> {
+    /// The headers for the original field, provided as a convenience.
+    pub fn headers(&self) -> &Shared<FieldHeaders> {
+        &self.inner.headers
+    }
+}
+
+impl<S: TryStream + Unpin> Read for ReadField<S> where S::Ok: BodyChunk + Unpin, S::Error: Into<Box<dyn Error + Send + Sync>> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        block_on(poll_fn(|cx| Pin::new(&mut self.inner).poll_read(cx, buf)))
+    }
+}
+
+impl<S: TryStream + Unpin> BufRead for ReadField<S> where S::Ok: BodyChunk + Unpin, S::Error: Into<Box<dyn Error + Send + Sync>> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        block_on(poll_fn(|cx| self.inner.poll_fill(cx)))?;
+        Ok(self.inner.buffer())
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.inner.consume_buffer(amt)
+    }
+}
+
+impl<S: TryStream> fmt::Debug for ReadField<S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ReadField")
+            .field("inner", &self.inner)
+            .finish()
+    }
+}
+
+#[test]
+fn test_async_read() {
+    use futures::io::AsyncReadExt;
+
+    let stream = crate::mock_stream!("hello, "; ""; "world"; "!");
+    let mut read = async_read(Default::default(), stream);
+
+    // partial reads of a chunk
+    let mut buf = [0; 3];
+    assert_eq!(block_on(read.read(&mut buf)).unwrap(), 3);
+    assert_eq!(&buf, b"hel");
+
+    let mut rest = String::new();
+    block_on(read.read_to_string(&mut rest)).unwrap();
+    assert_eq!(rest, "lo, world!");
+    assert_eq!(block_on(read.read(&mut buf)).unwrap(), 0);
+}
+
+#[test]
+fn test_read_error() {
+    let stream = crate::mock_stream!("data"; Poll::Ready(Some(Err("stream failed"))));
+    let mut read = async_read(Default::default(), stream).into_blocking();
+
+    let mut data = Vec::new();
+    let err = read.read_to_end(&mut data).unwrap_err();
+    assert_eq!(data, b"data");
+    assert_eq!(err.kind(), io::ErrorKind::Other);
+    assert_eq!(err.to_string(), "stream failed");
+
+    let stream = crate::mock_stream!("line one\nline two");
+    let lines: Vec<_> = async_read(Default::default(), stream).into_blocking().lines()
+        .collect::<Result<_, _>>().unwrap();
+    assert_eq!(lines, ["line one", "line two"]);
+}
@@ -0,0 +1,324 @@
+// Copyright 2017 `multipart-async` Crate Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+use tempdir::TempDir;
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::fmt;
+
+use crate::{BodyChunk, StreamError};
+
+use crate::server::sync::Shared;
+
+use super::FieldHeaders;
+
+use crate::helpers::*;
+
+const RANDOM_FILENAME_LEN: usize = 12;
+
+const TEMP_DIR_PREFIX: &str = "multipart-async";
+
+// 8 MiB, large enough for most uploads without letting one field fill up the disk
+const DEFAULT_SIZE_LIMIT: u64 = 8 * 1024 * 1024;
+
+/// The result of saving a field's data to the filesystem.
+#[derive(Debug)]
+pub struct SavedField {
+    /// The headers for the original field, provided as a convenience.
+    pub headers: Shared<FieldHeaders>,
+    /// The path of the saved file.
+    pub path: PathBuf,
+    /// The number of bytes written to the file.
+    pub size: u64,
+    /// `true` if the field data exceeded the size limit and the file only contains the first
+    /// `size` bytes of it.
+    pub truncated: bool,
+    temp_dir: Option<TempDir>,
+}
+
+impl SavedField {
+    /// If the field was saved to a new temporary directory, keep that directory and the file
+    /// after this value is dropped. Otherwise, this does nothing.
+    ///
+    /// Files saved to a directory given with `SaveField::with_dir()` are never deleted.
+    pub fn keep(&mut self) {
+        if let Some(dir) = self.temp_dir.take() {
+            let _ = dir.into_path();
+        }
+    }
+}
+
+enum SaveDir {
+    Temp,
+    Dir(PathBuf),
+}
+
+struct OpenFile {
+    file: File,
+    path: PathBuf,
+}
+
+/// A `Future` which saves a field's data to a file.
+///
+/// By default, the field is saved to a randomly named file in a new temporary directory which
+/// is deleted when the resulting `SavedField` is dropped, unless `SavedField::keep()` is called.
+/// Use `with_dir()` to save the file to a directory of your choosing instead.
+///
+/// If the future fails or is dropped before it completes, the partially written file is deleted.
+///
+/// ### Note: Blocking
+/// The file is created and written to with blocking I/O as the field data is read. If this
+/// is not acceptable for your executor, read the `FieldData` stream into your runtime's
+/// asynchronous file type instead.
+///
+/// ### Warning About Leaks
+/// If this value or the contained `FieldData` is leaked (via `mem::forget()` or some
+/// other mechanism), then the parent `Multipart` will never be able to yield the next field in the
+/// stream. The task waiting on the `Multipart` will also never be notified, which, depending on the
+/// event loop/reactor/executor implementation, may cause a deadlock.
+pub struct SaveField<S: TryStream> {
+    stream: Option<S>,
+    dir: SaveDir,
+    temp_dir: Option<TempDir>,
+    file: Option<OpenFile>,
+    size: u64,
+    /// The headers for the original field, provided as a convenience.
+    pub headers: Shared<FieldHeaders>,
+    /// The maximum number of bytes to write to the file. If the field data is longer than this,
+    /// the rest of it is discarded and `SavedField::truncated` is set.
+    pub size_limit: u64,
+}
+
+pub fn save<S: TryStream>(headers: Shared<FieldHeaders>, data: S) -> SaveField<S> {
+    SaveField {
+        stream: Some(data), dir: SaveDir::Temp, temp_dir: None, file: None, size: 0,
+        headers, size_limit: DEFAULT_SIZE_LIMIT,
+    }
+}
+
+impl<S: TryStream> SaveField<S> {
+    /// Set the maximum number of bytes to write to the file; the default is 8 MiB.
+    ///
+    /// If the field data is longer than this, the rest of it is discarded and
+    /// `SavedField::truncated` is set. Setting this to `u64::MAX` effectively removes the limit.
+    pub fn size_limit(mut self, size_limit: u64) -> Self {
+        self.size_limit = size_limit;
+        self
+    }
+
+    /// Save the field to a randomly named file in `dir`, creating the directory if necessary.
+    ///
+    /// The file is not deleted when the `SavedField` is dropped.
+    pub fn with_dir<P: Into<PathBuf>>(mut self, dir: P) -> Self {
+        self.dir = SaveDir::Dir(dir.into());
+        self
+    }
+
+    /// Save the field to a randomly named file in a new temporary directory. This is the default.
+    pub fn temp(mut self) -> Self {
+        self.dir = SaveDir::Temp;
+        self
+    }
+
+    /// Destructure this future, taking the internal `FieldData` instance back.
+    ///
+    /// Will be `None` if the field was saved, because the internal `FieldData`
+    /// instance is dropped afterwards to allow the parent `Multipart` to immediately start
+    /// working on the next field.
+    pub fn into_data(mut self) -> Option<S> {
+        self.stream.take()
+    }
+
+    fn open_file(&mut self) -> io::Result<&mut OpenFile> {
+        if self.file.is_none() {
+            let dir = match self.dir {
+                SaveDir::Temp => {
+                    if self.temp_dir.is_none() {
+                        self.temp_dir = Some(TempDir::new(TEMP_DIR_PREFIX)?);
+                    }
+
+                    self.temp_dir.as_ref().expect("temp dir was just created").path().to_owned()
+                }
+                SaveDir::Dir(ref dir) => {
+                    fs::create_dir_all(dir)?;
+                    dir.clone()
+                }
+            };
+
+            let path = dir.join(crate::random_alphanumeric(RANDOM_FILENAME_LEN));
+
+            debug!("saving field {:?} to {:?}", self.headers, path);
+
+            let file = OpenOptions::new().write(true).create_new(true).open(&path)?;
+            self.file = Some(OpenFile { file, path });
+        }
+
+        Ok(self.file.as_mut().expect("file was just opened"))
+    }
+
+    /// Delete the partially written file, if there is one.
+    fn remove_file(&mut self) {
+        if let Some(OpenFile { file, path }) = self.file.take() {
+            drop(file);
+
+            if let Err(e) = fs::remove_file(&path) {
+                warn!("failed to delete partially saved field at {:?}: {}", path, e);
+            }
+        }
+    }
+}
+
+impl<S: TryStream + Unpin> Future for SaveField<S> where S::Ok: BodyChunk, S::Error: StreamError {
+    type Output = Result<SavedField, S::Error>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = self.get_mut();
+
+        let res = this.poll_save(cx);
+
+        if let Poll::Ready(Err(_)) = res {
+            this.remove_file();
+        }
+
+        res
+    }
+}
+
+impl<S: TryStream + Unpin> SaveField<S> where S::Ok: BodyChunk, S::Error: StreamError {
+    fn poll_save(&mut self, cx: &mut Context) -> Poll<Result<SavedField, S::Error>> {
+        self.open_file()?;
+
+        let mut truncated = false;
+
+        while let Some(ref mut stream) = self.stream {
+            let chunk = match ready!(poll_next(stream, cx)?) {
+                Some(chunk) => chunk,
+                None => break,
+            };
+
+            let remaining = self.size_limit - self.size;
+
+            let data = if chunk.len() as u64 > remaining {
+                truncated = true;
+                &chunk.as_slice()[..remaining as usize]
+            } else {
+                chunk.as_slice()
+            };
+
+            self.file.as_mut().expect("file should be open").file.write_all(data)?;
+            self.size += data.len() as u64;
+
+            if truncated {
+                debug!("field {:?} exceeded size limit of {} bytes", self.headers,
+                       self.size_limit);
+                break;
+            }
+        }
+
+        // Optimization: free the `FieldData` so the parent `Multipart` can yield
+        // the next field.
+        self.stream = None;
+
+        let OpenFile { mut file, path } = self.file.take().expect("file should be open");
+
+        if let Err(e) = file.flush() {
+            self.file = Some(OpenFile { file, path });
+            return Poll::Ready(Err(e.into()));
+        }
+
+        ready_ok(SavedField {
+            headers: self.headers.clone(),
+            path,
+            size: self.size,
+            truncated,
+            temp_dir: self.temp_dir.take(),
+        })
+    }
+}
+
+/// Deletes the partially written file if the future did not complete.
+impl<S: TryStream> Drop for SaveField<S> {
+    fn drop(&mut self) {
+        self.remove_file();
+    }
+}
+
+impl<S: TryStream> fmt::Debug for SaveField<S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SaveField")
+            .field("headers", &self.headers)
+            .field("size_limit", &self.size_limit)
+            .field("size", &self.size)
+            .field("path", &self.file.as_ref().map(|f| &f.path))
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use futures::executor::block_on;
+    use futures::TryStreamExt;
+
+    use std::fs;
+
+    use tempdir::TempDir;
+
+    use crate::server::Multipart;
+
+    macro_rules! mock_multipart (
+        () => (
+            Multipart::with_body(crate::mock_stream!(
+                "--boundary\r\n\
+                 Content-Disposition: form-data; name=\"file\"; filename=\"file.txt\"\r\n\r\n";
+                "file ";
+                "data";
+                "\r\n--boundary--"
+            ), "boundary")
+        )
+    );
+
+    #[test]
+    fn test_save_temp() {
+        let mut multipart = mock_multipart!();
+
+        block_on(async {
+            let field = multipart.try_next().await.unwrap().unwrap();
+            let saved = field.data.save().await.unwrap();
+
+            assert_eq!(saved.headers.name, "file");
+            assert_eq!(saved.size, 9);
+            assert!(!saved.truncated);
+            assert_eq!(fs::read(&saved.path).unwrap(), b"file data");
+
+            let path = saved.path.clone();
+            drop(saved);
+            assert!(!path.exists());
+
+            assert!(multipart.try_next().await.unwrap().is_none());
+        });
+    }
+
+    #[test]
+    fn test_save_truncated() {
+        let dir = TempDir::new("multipart-async-test").unwrap();
+        let mut multipart = mock_multipart!();
+
+        block_on(async {
+            let field = multipart.try_next().await.unwrap().unwrap();
+            let saved = field.data.save().with_dir(dir.path()).size_limit(7).await.unwrap();
+
+            assert_eq!(saved.size, 7);
+            assert!(saved.truncated);
+            assert!(saved.path.starts_with(dir.path()));
+            assert_eq!(fs::read(&saved.path).unwrap(), b"file da");
+
+            // the rest of the field should be skipped
+            assert!(multipart.try_next().await.unwrap().is_none());
+        });
+    }
+}
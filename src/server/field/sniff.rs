@@ -0,0 +1,299 @@
+// Copyright 2017 `multipart-async` Crate Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+//! Detecting the type of field data from its first bytes.
+use mime::{self, Mime};
+
+use std::fmt;
+use std::path::Path;
+
+use crate::{BodyChunk, StreamError};
+
+use crate::server::sync::Shared;
+
+use super::FieldHeaders;
+
+use crate::helpers::*;
+
+/// The number of bytes inspected by `sniff_content_type()` and `SniffField`.
+pub const SNIFF_LEN: usize = 16;
+
+/// Signatures as `(offset, magic bytes, MIME type)`; the first match wins.
+static SIGNATURES: &[(usize, &[u8], &str)] = &[
+    (0, b"\x89PNG\r\n\x1A\n", "image/png"),
+    (0, b"\xFF\xD8\xFF", "image/jpeg"),
+    (0, b"GIF87a", "image/gif"),
+    (0, b"GIF89a", "image/gif"),
+    (8, b"WEBP", "image/webp"),
+    (0, b"II*\0", "image/tiff"),
+    (0, b"MM\0*", "image/tiff"),
+    (0, b"\0\0\x01\0", "image/x-icon"),
+    (0, b"BM", "image/bmp"),
+    (0, b"%PDF-", "application/pdf"),
+    (0, b"PK\x03\x04", "application/zip"),
+    (0, b"PK\x05\x06", "application/zip"),
+    (0, b"\x1F\x8B", "application/gzip"),
+    (0, b"7z\xBC\xAF\x27\x1C", "application/x-7z-compressed"),
+    (0, b"Rar!\x1A\x07", "application/vnd.rar"),
+    (0, b"OggS", "application/ogg"),
+    (0, b"ID3", "audio/mpeg"),
+    (4, b"ftyp", "video/mp4"),
+    (0, b"\0asm", "application/wasm"),
+    (0, b"\x7FELF", "application/x-executable"),
+    (0, b"MZ", "application/vnd.microsoft.portable-executable"),
+    (0, b"\xFE\xED\xFA\xCE", "application/x-mach-binary"),
+    (0, b"\xFE\xED\xFA\xCF", "application/x-mach-binary"),
+    (0, b"\xCE\xFA\xED\xFE", "application/x-mach-binary"),
+    (0, b"\xCF\xFA\xED\xFE", "application/x-mach-binary"),
+    (0, b"#!", "text/x-shellscript"),
+];
+
+/// Container formats which are ZIP archives, and so sniff as `application/zip`.
+static ZIP_CONTAINERS: &[&str] = &[
+    "application/java-archive",
+    "application/vnd.android.package-archive",
+    "application/vnd.oasis.opendocument.",
+    "application/vnd.openxmlformats-officedocument.",
+];
+
+/// Detect the type of data from its first bytes, or `None` if it's not recognized.
+///
+/// Only the first `SNIFF_LEN` bytes are inspected.
+pub fn sniff_content_type(bytes: &[u8]) -> Option<Mime> {
+    SIGNATURES.iter()
+        .find(|&&(offset, magic, _)| bytes.get(offset .. offset + magic.len()) == Some(magic))
+        .map(|&(_, _, mime)| mime.parse().expect("invalid MIME type in signatures"))
+}
+
+/// What `SniffField` does when the sniffed type doesn't match the declared one.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SniffPolicy {
+    /// Log a warning and report the mismatch with `Sniffed::is_mismatch()`.
+    Flag,
+    /// Yield an error instead of any field data.
+    Reject,
+}
+
+/// The result of sniffing a field's data.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Sniffed {
+    /// The detected type, or `None` if it wasn't recognized.
+    pub content_type: Option<Mime>,
+    /// `false` if the `Content-Type` of the field contradicts the detected type.
+    ///
+    /// Always `true` if either type is unknown or the field was declared
+    /// `application/octet-stream`.
+    pub content_type_matches: bool,
+    /// `false` if the extension of the field's filename contradicts the detected type.
+    ///
+    /// Always `true` if there is no filename, the extension is not known or the type was
+    /// not detected.
+    pub extension_matches: bool,
+}
+
+impl Sniffed {
+    fn new(bytes: &[u8], headers: &FieldHeaders) -> Self {
+        let content_type = sniff_content_type(bytes);
+
+        let (content_type_matches, extension_matches) = match content_type {
+            Some(ref sniffed) => (
+                headers.content_type.as_ref().is_none_or(|declared| compatible(declared, sniffed)),
+                extension_matches(headers.filename.as_deref(), sniffed),
+            ),
+            None => (true, true),
+        };
+
+        Sniffed { content_type, content_type_matches, extension_matches }
+    }
+
+    /// `true` if either the declared `Content-Type` or the filename extension contradicts the
+    /// detected type.
+    pub fn is_mismatch(&self) -> bool {
+        !(self.content_type_matches && self.extension_matches)
+    }
+}
+
+fn compatible(declared: &Mime, sniffed: &Mime) -> bool {
+    let declared = match declared.essence_str() {
+        "image/jpg" | "image/pjpeg" => "image/jpeg",
+        "image/x-png" => "image/png",
+        "image/vnd.microsoft.icon" => "image/x-icon",
+        "application/x-gzip" => "application/gzip",
+        "application/x-zip-compressed" => "application/zip",
+        "application/x-pdf" => "application/pdf",
+        other => other,
+    };
+
+    if declared == mime::APPLICATION_OCTET_STREAM.essence_str() || declared == sniffed.essence_str() {
+        return true;
+    }
+
+    match sniffed.essence_str() {
+        "application/zip" =>
+            declared.ends_with("+zip") || ZIP_CONTAINERS.iter().any(|c| declared.starts_with(c)),
+        // the MP4 signature is shared by the whole ISO media family
+        "video/mp4" => declared.starts_with("video/") || declared.starts_with("audio/"),
+        "application/ogg" => declared.ends_with("/ogg"),
+        "audio/mpeg" => declared.starts_with("audio/"),
+        // a script could be any text type
+        "text/x-shellscript" => declared.starts_with("text/") || declared.starts_with("application/x-"),
+        _ => false,
+    }
+}
+
+fn extension_matches(filename: Option<&str>, sniffed: &Mime) -> bool {
+    let ext = match filename.and_then(|name| Path::new(name).extension()).and_then(|e| e.to_str()) {
+        Some(ext) => ext,
+        None => return true,
+    };
+
+    let mut guesses = mime_guess::from_ext(ext).iter().peekable();
+
+    // unknown extension
+    if guesses.peek().is_none() {
+        return true;
+    }
+
+    guesses.any(|guess| compatible(&guess, sniffed))
+}
+
+/// A `Stream` adaptor which detects the type of a field's data from its first bytes,
+/// returned by `FieldData::sniff()`.
+///
+/// The chunks of the field are yielded unchanged. At most `SNIFF_LEN` bytes are held back
+/// while the first chunks are inspected; the result is available from `sniffed()` once the
+/// first chunk has been yielded.
+pub struct SniffField<S: TryStream> {
+    stream: S,
+    policy: SniffPolicy,
+    window: Vec<u8>,
+    held: Vec<S::Ok>,
+    sniffed: Option<Sniffed>,
+    /// The headers for the original field, provided as a convenience.
+    pub headers: Shared<FieldHeaders>,
+}
+
+pub fn sniff<S: TryStream>(headers: Shared<FieldHeaders>, data: S, policy: SniffPolicy) -> SniffField<S> {
+    SniffField {
+        stream: data, policy, window: Vec::with_capacity(SNIFF_LEN), held: Vec::new(),
+        sniffed: None, headers,
+    }
+}
+
+impl<S: TryStream> SniffField<S> {
+    /// The result of sniffing, or `None` if no data has been yielded yet.
+    pub fn sniffed(&self) -> Option<&Sniffed> {
+        self.sniffed.as_ref()
+    }
+}
+
+impl<S: TryStream + Unpin> SniffField<S> where S::Ok: BodyChunk, S::Error: StreamError {
+    fn finish_sniff(&mut self) -> Result<(), S::Error> {
+        let sniffed = Sniffed::new(&self.window, &self.headers);
+        let mismatch = sniffed.is_mismatch();
+
+        if mismatch {
+            warn!("field {:?} declared as {:?} looks like {:?}", self.headers.name,
+                  self.headers.content_type, sniffed.content_type);
+        }
+
+        self.sniffed = Some(sniffed);
+
+        if mismatch && self.policy == SniffPolicy::Reject {
+            self.held.clear();
+            ret_err!(InvalidData, "data of field {:?} does not match its declared type or filename extension",
+                     self.headers.name);
+        }
+
+        // yield in stream order
+        self.held.reverse();
+
+        Ok(())
+    }
+}
+
+impl<S: TryStream + Unpin> Stream for SniffField<S> where S::Ok: BodyChunk + Unpin, S::Error: StreamError {
+    type Item = Result<S::Ok, S::Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+
+        if let Some(ref sniffed) = this.sniffed {
+            if let Some(chunk) = this.held.pop() {
+                return ready_some(chunk);
+            }
+
+            if sniffed.is_mismatch() && this.policy == SniffPolicy::Reject {
+                return Poll::Ready(None);
+            }
+
+            return poll_next(&mut this.stream, cx);
+        }
+
+        while let Some(chunk) = ready!(poll_next(&mut this.stream, cx)?) {
+            let needed = SNIFF_LEN - this.window.len();
+            let slice = chunk.as_slice();
+            this.window.extend_from_slice(&slice[..needed.min(slice.len())]);
+            this.held.push(chunk);
+
+            if this.window.len() == SNIFF_LEN {
+                break;
+            }
+        }
+
+        this.finish_sniff()?;
+
+        Poll::Ready(this.held.pop().map(Ok))
+    }
+}
+
+impl<S: TryStream> fmt::Debug for SniffField<S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SniffField")
+            .field("headers", &self.headers)
+            .field("policy", &self.policy)
+            .field("sniffed", &self.sniffed)
+            .finish()
+    }
+}
+
+#[test]
+fn test_sniff_content_type() {
+    assert_eq!(sniff_content_type(b"\x89PNG\r\n\x1A\n\0\0\0\rIHDR"), Some(mime::IMAGE_PNG));
+    assert_eq!(sniff_content_type(b"RIFF\0\0\0\0WEBPVP8 ").unwrap().essence_str(), "image/webp");
+    assert_eq!(sniff_content_type(b"MZ\x90\0").unwrap().essence_str(),
+               "application/vnd.microsoft.portable-executable");
+    assert_eq!(sniff_content_type(b"Hello, world!"), None);
+    assert_eq!(sniff_content_type(b""), None);
+}
+
+#[test]
+fn test_sniffed_matches() {
+    let headers = |content_type: &str, filename: &str| FieldHeaders {
+        content_type: Some(content_type.parse().unwrap()),
+        filename: Some(filename.into()),
+        .. FieldHeaders::default()
+    };
+
+    let png = b"\x89PNG\r\n\x1A\n";
+
+    assert!(!Sniffed::new(png, &headers("image/png", "image.png")).is_mismatch());
+    assert!(!Sniffed::new(png, &headers("application/octet-stream", "image.png")).is_mismatch());
+
+    let sniffed = Sniffed::new(b"MZ\x90\0", &headers("image/png", "image.png"));
+    assert!(!sniffed.content_type_matches);
+    assert!(!sniffed.extension_matches);
+
+    let sniffed = Sniffed::new(png, &headers("image/png", "image.pdf"));
+    assert!(sniffed.content_type_matches);
+    assert!(!sniffed.extension_matches);
+
+    let docx = "application/vnd.openxmlformats-officedocument.wordprocessingml.document";
+    assert!(!Sniffed::new(b"PK\x03\x04", &headers(docx, "doc.docx")).is_mismatch());
+
+    // unknown content
+    assert!(!Sniffed::new(b"Hello", &headers("image/png", "image.png")).is_mismatch());
+}
@@ -0,0 +1,265 @@
+// Copyright 2017 `multipart-async` Crate Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+//! Collecting a whole request into a `Form`.
+use tempdir::TempDir;
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::{fmt, mem};
+
+use crate::{BodyChunk, StreamError};
+
+use super::field::{FieldData, ReadTextField, SaveField, SavedField, TextField};
+use super::Multipart;
+
+use crate::helpers::*;
+
+const TEMP_DIR_PREFIX: &str = "multipart-async-form";
+
+/// Options for `Multipart::collect_form()`.
+#[derive(Clone, Debug, Default)]
+pub struct FormOptions {
+    /// The length limit, in bytes, for each text field; see `ReadTextField::limit()`.
+    ///
+    /// If `None`, the default of `ReadTextField` is used.
+    pub text_limit: Option<usize>,
+    /// The size limit, in bytes, for each file field; see `SaveField::size_limit()`.
+    ///
+    /// If `None`, the default of `SaveField` is used.
+    pub size_limit: Option<u64>,
+    /// The directory to save file fields to.
+    ///
+    /// If `None`, a new temporary directory is created which is deleted along with the `Form`.
+    pub dir: Option<PathBuf>,
+}
+
+impl FormOptions {
+    /// Set the length limit for each text field.
+    pub fn text_limit(self, text_limit: usize) -> Self {
+        FormOptions { text_limit: Some(text_limit), .. self }
+    }
+
+    /// Set the size limit for each file field.
+    pub fn size_limit(self, size_limit: u64) -> Self {
+        FormOptions { size_limit: Some(size_limit), .. self }
+    }
+
+    /// Save file fields to `dir` instead of a new temporary directory.
+    pub fn with_dir<P: Into<PathBuf>>(self, dir: P) -> Self {
+        FormOptions { dir: Some(dir.into()), .. self }
+    }
+}
+
+/// The fields of a request collected by `Multipart::collect_form()`.
+///
+/// Fields with a `filename` in their headers are saved to files, all others are read as text.
+/// The fields are kept in the order they appear in the request for each name.
+#[derive(Debug, Default)]
+pub struct Form {
+    /// The text fields of the request, keyed by name.
+    pub texts: HashMap<String, Vec<TextField>>,
+    /// The file fields of the request, keyed by name.
+    pub files: HashMap<String, Vec<SavedField>>,
+    temp_dir: Option<TempDir>,
+}
+
+impl Form {
+    /// Get the first text field with the given name.
+    pub fn text(&self, name: &str) -> Option<&str> {
+        self.texts.get(name).and_then(|texts| texts.first()).map(|field| &*field.text)
+    }
+
+    /// Get the first file field with the given name.
+    pub fn file(&self, name: &str) -> Option<&SavedField> {
+        self.files.get(name).and_then(|files| files.first())
+    }
+
+    /// The temporary directory the file fields were saved to, if one was created.
+    pub fn temp_dir(&self) -> Option<&Path> {
+        self.temp_dir.as_ref().map(TempDir::path)
+    }
+
+    /// If file fields were saved to a temporary directory, keep that directory and its files
+    /// after this value is dropped. Otherwise, this does nothing.
+    pub fn keep(&mut self) {
+        if let Some(dir) = self.temp_dir.take() {
+            let _ = dir.into_path();
+        }
+    }
+}
+
+enum CollectState<S: TryStream + Unpin> where S::Ok: BodyChunk, S::Error: StreamError {
+    NextField,
+    Text(ReadTextField<FieldData<S>>),
+    File(SaveField<FieldData<S>>),
+}
+
+/// A `Future` which collects all the fields of a request into a `Form`.
+///
+/// Fields are read one at a time, so text fields are subject to the length limit of
+/// `ReadTextField`, and file fields are saved as with `FieldData::save()`.
+pub struct CollectForm<S: TryStream + Unpin> where S::Ok: BodyChunk, S::Error: StreamError {
+    multipart: Multipart<S>,
+    options: FormOptions,
+    state: CollectState<S>,
+    form: Form,
+    // if `false`, each file field without `options.dir` gets its own temporary directory
+    shared_temp_dir: bool,
+}
+
+pub fn collect_form<S: TryStream + Unpin>(multipart: Multipart<S>, options: FormOptions) -> CollectForm<S>
+where S::Ok: BodyChunk, S::Error: StreamError {
+    CollectForm {
+        multipart, options, state: CollectState::NextField, form: Form::default(),
+        shared_temp_dir: true,
+    }
+}
+
+impl<S: TryStream + Unpin> CollectForm<S> where S::Ok: BodyChunk, S::Error: StreamError {
+    /// Save each file field to its own temporary directory, owned by its `SavedField`,
+    /// so it can outlive the `Form`.
+    #[cfg_attr(not(feature = "serde"), allow(dead_code))]
+    pub(super) fn separate_temp_dirs(mut self) -> Self {
+        self.shared_temp_dir = false;
+        self
+    }
+}
+
+impl<S: TryStream + Unpin> Future for CollectForm<S> where S::Ok: BodyChunk + Unpin, S::Error: StreamError {
+    type Output = Result<Form, S::Error>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        self.get_mut().poll_form(cx)
+    }
+}
+
+impl<S: TryStream + Unpin> CollectForm<S> where S::Ok: BodyChunk + Unpin, S::Error: StreamError {
+    fn poll_form(&mut self, cx: &mut Context) -> Poll<Result<Form, S::Error>> {
+        loop {
+            match self.state {
+                CollectState::NextField => {
+                    let field = match ready!(self.multipart.poll_field(cx)?) {
+                        Some(field) => field,
+                        None => return ready_ok(mem::take(&mut self.form)),
+                    };
+
+                    self.state = if field.headers.filename.is_some() {
+                        let save = match self.save_dir()? {
+                            Some(dir) => field.data.save().with_dir(dir),
+                            None => field.data.save(),
+                        };
+
+                        CollectState::File(match self.options.size_limit {
+                            Some(limit) => save.size_limit(limit),
+                            None => save,
+                        })
+                    } else {
+                        let read = field.data.read_text();
+
+                        CollectState::Text(match self.options.text_limit {
+                            Some(limit) => read.limit(limit),
+                            None => read,
+                        })
+                    };
+                },
+                CollectState::Text(ref mut read) => {
+                    let text = ready!(Pin::new(read).poll(cx)?);
+                    self.form.texts.entry(text.headers.name.clone()).or_default().push(text);
+                    self.state = CollectState::NextField;
+                },
+                CollectState::File(ref mut save) => {
+                    let file = ready!(Pin::new(save).poll(cx)?);
+                    self.form.files.entry(file.headers.name.clone()).or_default().push(file);
+                    self.state = CollectState::NextField;
+                },
+            }
+        }
+    }
+
+    /// The directory to save the next file field to, creating the temporary directory if needed.
+    ///
+    /// `None` if the field should be saved to a temporary directory of its own.
+    fn save_dir(&mut self) -> Result<Option<PathBuf>, S::Error> {
+        if let Some(ref dir) = self.options.dir {
+            return Ok(Some(dir.clone()));
+        }
+
+        if !self.shared_temp_dir {
+            return Ok(None);
+        }
+
+        if self.form.temp_dir.is_none() {
+            self.form.temp_dir = Some(TempDir::new(TEMP_DIR_PREFIX)?);
+        }
+
+        Ok(Some(self.form.temp_dir().expect("temp dir was just created").to_owned()))
+    }
+}
+
+impl<S: TryStream + Unpin> fmt::Debug for CollectForm<S> where S::Ok: BodyChunk, S::Error: StreamError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("CollectForm")
+            .field("options", &self.options)
+            .field("form", &self.form)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use futures::executor::block_on;
+
+    use std::fs;
+
+    use super::FormOptions;
+    use crate::server::Multipart;
+
+    #[test]
+    fn test_collect_form() {
+        let stream = crate::mock_stream!(
+            "--boundary\r\n\
+             Content-Disposition: form-data; name=\"text\"\r\n\r\n\
+             first\r\n\
+             --boundary\r\n";
+            "Content-Disposition: form-data; name=\"file\"; filename=\"file.txt\"\r\n\r\n";
+            "file data";
+            "\r\n--boundary\r\n\
+             Content-Disposition: form-data; name=\"text\"\r\n\r\n\
+             second\r\n\
+             --boundary--"
+        );
+
+        let multipart = Multipart::with_body(stream, "boundary");
+        let form = block_on(multipart.collect_form(FormOptions::default())).unwrap();
+
+        let texts: Vec<_> = form.texts["text"].iter().map(|field| &*field.text).collect();
+        assert_eq!(texts, ["first", "second"]);
+        assert_eq!(form.text("text"), Some("first"));
+
+        let file = form.file("file").unwrap();
+        assert_eq!(file.headers.filename.as_ref().unwrap(), "file.txt");
+        assert!(file.path.starts_with(form.temp_dir().unwrap()));
+        assert_eq!(fs::read(&file.path).unwrap(), b"file data");
+
+        let dir = form.temp_dir().unwrap().to_owned();
+        drop(form);
+        assert!(!dir.exists());
+    }
+
+    #[test]
+    fn test_collect_form_text_limit() {
+        let stream = crate::mock_stream!(
+            "--boundary\r\n\
+             Content-Disposition: form-data; name=\"text\"\r\n\r\n";
+            "too long";
+            "\r\n--boundary--"
+        );
+
+        let multipart = Multipart::with_body(stream, "boundary");
+        assert!(block_on(multipart.collect_form(FormOptions::default().text_limit(4))).is_err());
+    }
+}
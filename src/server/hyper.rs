@@ -4,87 +4,97 @@
 // http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
 // http://opensource.org/licenses/MIT>, at your option. This file may not be
 // copied, modified, or distributed except according to those terms.
-//! Server-side integration with [Hyper](https://github.com/hyperium/hyper).
+//! Server-side integration with [Hyper](https://github.com/hyperium/hyper) 1.x.
 //! Enabled with the `hyper` feature (on by default).
 use bytes::Bytes;
 
-use futures::future::{Either, IntoFuture};
+use futures::future::Either;
 
-use hyper::header::ContentType;
-pub use hyper::{Body, Chunk, Error, Headers, HttpVersion, Method, Request, Response, Uri};
-pub use hyper::server::Service;
+use http::header::CONTENT_TYPE;
+use http::request::Parts;
+
+use hyper::body::{Body, Incoming};
+pub use hyper::{Request, Response};
+pub use hyper::service::Service;
 
 use mime::{self, Mime};
 
-use std::str::Utf8Error;
+use std::io;
+
+use crate::helpers::*;
 
 use super::{Multipart, RequestExt};
-use {BodyChunk, StreamError};
 
-impl RequestExt for Request {
-    type Multipart = (Multipart<Body>, MinusBody);
+impl RequestExt for Request<Incoming> {
+    type Multipart = (Multipart<IncomingBody>, MinusBody);
 
     fn into_multipart(self) -> Result<Self::Multipart, Self> {
-        if let Some(boundary) = get_boundary(&self) {
+        if let Some(boundary) = get_boundary(&self, false) {
+            info!("multipart request received, boundary: {}", boundary);
+            let (minus_body, body) = self.into_parts();
+            Ok((Multipart::with_body(IncomingBody(body), boundary), minus_body))
+        } else {
+            Err(self)
+        }
+    }
+
+    fn into_multipart_any(self) -> Result<Self::Multipart, Self> {
+        if let Some(boundary) = get_boundary(&self, true) {
             info!("multipart request received, boundary: {}", boundary);
-            let (body, minus_body) = MinusBody::from_req(self);
-            Ok((Multipart::with_body(body, boundary), minus_body))
+            let (minus_body, body) = self.into_parts();
+            Ok((Multipart::with_body(IncomingBody(body), boundary), minus_body))
         } else {
             Err(self)
         }
     }
 }
 
-/// A deconstructed `server::Request` with the body extracted.
-#[allow(missing_docs)]
+/// A deconstructed `hyper::Request` with the body extracted.
+pub type MinusBody = Parts;
+
+/// The data frames of a `hyper::body::Incoming` as a `Stream`, skipping any trailers.
+///
+/// Errors from Hyper are wrapped in `io::Error` so they may be used with `StreamError`.
 #[derive(Debug)]
-pub struct MinusBody {
-    pub method: Method,
-    pub uri: Uri,
-    pub version: HttpVersion,
-    pub headers: Headers,
-}
+pub struct IncomingBody(pub Incoming);
+
+impl Stream for IncomingBody {
+    type Item = Result<Bytes, io::Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let body = &mut self.get_mut().0;
 
-impl MinusBody {
-    fn from_req(req: Request<Body>) -> (Body, Self) {
-        let (method, uri, version, headers, body) = req.deconstruct();
-        (body, MinusBody { method, uri, version, headers })
+        loop {
+            let frame = match ready!(Pin::new(&mut *body).poll_frame(cx)) {
+                Some(Ok(frame)) => frame,
+                Some(Err(e)) => return Poll::Ready(Some(Err(io::Error::other(e)))),
+                None => return Poll::Ready(None),
+            };
+
+            match frame.into_data() {
+                Ok(data) => return ready_some(data),
+                Err(_) => debug!("skipping non-data frame in multipart request body"),
+            }
+        }
     }
 }
 
-fn get_boundary(req: &Request<Body>) -> Option<String> {
-    req.headers().get::<ContentType>()
-        .and_then(|&ContentType(ref mime)| get_boundary_mime(mime))
+fn get_boundary<B>(req: &Request<B>, any_subtype: bool) -> Option<String> {
+    req.headers().get(CONTENT_TYPE)
+        .and_then(|val| val.to_str().ok())
+        .and_then(|val| val.parse::<Mime>().ok())
+        .and_then(|mime| get_boundary_mime(&mime, any_subtype))
 }
 
-fn get_boundary_mime(mime: &Mime) -> Option<String> {
-    if mime.type_() == mime::MULTIPART && mime.subtype() == mime::FORM_DATA {
+fn get_boundary_mime(mime: &Mime, any_subtype: bool) -> Option<String> {
+    if mime.type_() == mime::MULTIPART && (any_subtype || mime.subtype() == mime::FORM_DATA) {
         mime.get_param(mime::BOUNDARY).map(|n|n.as_ref().into())
     } else {
         None
     }
 }
 
-impl BodyChunk for Chunk {
-    #[inline]
-    fn split_at(self, idx: usize) -> (Self, Self) {
-        let (first, second) = Bytes::from(self).split_at(idx);
-        (first.into(), second.into())
-    }
-
-    #[inline]
-    fn as_slice(&self) -> &[u8] {
-        self
-    }
-}
-
-impl StreamError for Error {
-    fn from_utf8(err: Utf8Error) -> Self {
-        err.into()
-    }
-}
-
-/// A `hyper::server::Service` implementation that handles extraction of a `Multipart` instance
+/// A `hyper::service::Service` implementation that handles extraction of a `Multipart` instance
 pub struct MultipartService<M, N> {
     /// The handler for when the request is `multipart`
     pub multipart: M,
@@ -92,19 +102,44 @@ pub struct MultipartService<M, N> {
     pub normal: N,
 }
 
-impl<M, MFut, N, NFut, Bd> Service for MultipartService<M, N> where M: Fn((Multipart<Body>, MinusBody)) -> MFut,
-                                                                MFut: IntoFuture<Item = Response<Bd>, Error = Error>,
-                                                                N: Fn(Request) -> NFut,
-                                                                NFut: IntoFuture<Item = Response<Bd>, Error = Error> {
-    type Request = Request;
+impl<M, MFut, N, NFut, Bd, E> Service<Request<Incoming>> for MultipartService<M, N>
+where M: Fn((Multipart<IncomingBody>, MinusBody)) -> MFut,
+      MFut: Future<Output = Result<Response<Bd>, E>>,
+      N: Fn(Request<Incoming>) -> NFut,
+      NFut: Future<Output = Result<Response<Bd>, E>> {
     type Response = Response<Bd>;
-    type Error = Error;
-    type Future = Either<MFut::Future, NFut::Future>;
+    type Error = E;
+    type Future = Either<MFut, NFut>;
 
-    fn call(&self, req: Self::Request) -> Self::Future {
+    fn call(&self, req: Request<Incoming>) -> Self::Future {
         match req.into_multipart() {
-            Ok(multi) => Either::A((self.multipart)(multi).into_future()),
-            Err(req) => Either::B((self.normal)(req).into_future()),
+            Ok(multi) => Either::Left((self.multipart)(multi)),
+            Err(req) => Either::Right((self.normal)(req)),
         }
     }
 }
+
+#[test]
+fn test_get_boundary() {
+    let req = Request::builder()
+        .header(CONTENT_TYPE, "multipart/form-data; boundary=\"boundary\"")
+        .body(())
+        .unwrap();
+
+    assert_eq!(get_boundary(&req, false), Some("boundary".into()));
+
+    let req = Request::builder()
+        .header(CONTENT_TYPE, "multipart/related; boundary=boundary; type=\"text/html\"")
+        .body(())
+        .unwrap();
+
+    assert_eq!(get_boundary(&req, false), None);
+    assert_eq!(get_boundary(&req, true), Some("boundary".into()));
+
+    let req = Request::builder()
+        .header(CONTENT_TYPE, "application/x-www-form-urlencoded")
+        .body(())
+        .unwrap();
+
+    assert_eq!(get_boundary(&req, true), None);
+}
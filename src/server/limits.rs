@@ -0,0 +1,190 @@
+// Copyright 2017 `multipart-async` Crate Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+//! Limits on the size and shape of a request, to protect servers from malicious clients.
+use std::error::Error;
+use std::{fmt, io};
+
+use crate::StreamError;
+
+use super::MultipartError;
+
+/// Limits on a `Multipart` request, set with `Multipart::with_limits()` or
+/// `Multipart::set_limits()`.
+///
+/// Breaking any of these limits yields an error constructed with `StreamError::from_limit()`.
+///
+/// The defaults only limit the headers of each field; all other limits are unset.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Limits {
+    /// The maximum size, in bytes, of the headers section of a single field. Default 1 KiB.
+    pub header_block_size: usize,
+    /// The maximum number of headers on a single field. Default 4.
+    pub header_count: usize,
+    /// The maximum number of fields in the request.
+    pub fields: Option<u64>,
+    /// The maximum number of fields with a filename in the request.
+    pub files: Option<u64>,
+    /// The maximum size, in bytes, of the data of a single field, as read from `FieldData`.
+    pub field_size: Option<u64>,
+    /// The maximum size, in bytes, of the whole request body.
+    pub total_size: Option<u64>,
+    /// The maximum size, in bytes, of the preamble before the first boundary.
+    pub preamble_size: Option<u64>,
+    /// The maximum size, in bytes, of the epilogue after the closing boundary, as read with
+    /// `Multipart::epilogue()`.
+    pub epilogue_size: Option<u64>,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            header_block_size: 1024,
+            header_count: 4,
+            fields: None,
+            files: None,
+            field_size: None,
+            total_size: None,
+            preamble_size: None,
+            epilogue_size: None,
+        }
+    }
+}
+
+impl Limits {
+    /// Set the maximum size of the headers section of a single field.
+    pub fn header_block_size(self, header_block_size: usize) -> Self {
+        Limits { header_block_size, .. self }
+    }
+
+    /// Set the maximum number of headers on a single field.
+    pub fn header_count(self, header_count: usize) -> Self {
+        Limits { header_count, .. self }
+    }
+
+    /// Set the maximum number of fields in the request.
+    pub fn fields(self, fields: u64) -> Self {
+        Limits { fields: Some(fields), .. self }
+    }
+
+    /// Set the maximum number of fields with a filename in the request.
+    pub fn files(self, files: u64) -> Self {
+        Limits { files: Some(files), .. self }
+    }
+
+    /// Set the maximum size of the data of a single field.
+    pub fn field_size(self, field_size: u64) -> Self {
+        Limits { field_size: Some(field_size), .. self }
+    }
+
+    /// Set the maximum size of the whole request body.
+    pub fn total_size(self, total_size: u64) -> Self {
+        Limits { total_size: Some(total_size), .. self }
+    }
+
+    /// Set the maximum size of the preamble before the first boundary.
+    pub fn preamble_size(self, preamble_size: u64) -> Self {
+        Limits { preamble_size: Some(preamble_size), .. self }
+    }
+
+    /// Set the maximum size of the epilogue after the closing boundary.
+    pub fn epilogue_size(self, epilogue_size: u64) -> Self {
+        Limits { epilogue_size: Some(epilogue_size), .. self }
+    }
+}
+
+/// The limit that was exceeded, as carried by `LimitExceeded`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum LimitKind {
+    /// `Limits::header_block_size`
+    HeaderBlockSize,
+    /// `Limits::header_count`
+    HeaderCount,
+    /// `Limits::fields`
+    Fields,
+    /// `Limits::files`
+    Files,
+    /// `Limits::field_size`
+    FieldSize,
+    /// `Limits::total_size`
+    TotalSize,
+    /// `Limits::preamble_size`
+    PreambleSize,
+    /// `Limits::epilogue_size`
+    EpilogueSize,
+    /// `ReadTextField::limit`
+    TextLength,
+}
+
+impl LimitKind {
+    fn description(self) -> &'static str {
+        use self::LimitKind::*;
+
+        match self {
+            HeaderBlockSize => "field headers size",
+            HeaderCount => "field header count",
+            Fields => "field count",
+            Files => "file count",
+            FieldSize => "field size",
+            TotalSize => "request body size",
+            PreambleSize => "preamble size",
+            EpilogueSize => "epilogue size",
+            TextLength => "text field length",
+        }
+    }
+}
+
+/// The error for a request which broke one of its `Limits`.
+///
+/// Servers should respond to this with `413 Payload Too Large`.
+///
+/// `StreamError::from_limit()` wraps this in a `MultipartError` in an `io::Error` by default;
+/// use `from_io_error()` to get it back out.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LimitExceeded {
+    /// The limit that was exceeded.
+    pub kind: LimitKind,
+    /// The value of the limit.
+    pub limit: u64,
+}
+
+impl LimitExceeded {
+    /// Get the `LimitExceeded` from an `io::Error` created by the default implementation of
+    /// `StreamError::from_limit()`, if it is one.
+    pub fn from_io_error(err: &io::Error) -> Option<&LimitExceeded> {
+        MultipartError::from_io_error(err).and_then(MultipartError::limit_exceeded)
+    }
+}
+
+impl fmt::Display for LimitExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} limit of {} exceeded", self.kind.description(), self.limit)
+    }
+}
+
+impl Error for LimitExceeded {}
+
+pub fn limit_err<T, E: StreamError>(kind: LimitKind, limit: u64) -> Result<T, E> {
+    Err(E::from_limit(LimitExceeded { kind, limit }))
+}
+
+/// Check `count` against `limit`, if set.
+pub fn check_limit<E: StreamError>(kind: LimitKind, count: u64, limit: Option<u64>) -> Result<(), E> {
+    match limit {
+        Some(limit) if count > limit => limit_err(kind, limit),
+        _ => Ok(()),
+    }
+}
+
+#[test]
+fn test_from_io_error() {
+    let err: io::Error = limit_err::<(), io::Error>(LimitKind::Fields, 2).unwrap_err();
+    assert_eq!(LimitExceeded::from_io_error(&err),
+               Some(&LimitExceeded { kind: LimitKind::Fields, limit: 2 }));
+    assert_eq!(err.to_string(), "field count limit of 2 exceeded");
+
+    assert_eq!(LimitExceeded::from_io_error(&io::Error::other("other")), None);
+}
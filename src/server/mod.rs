@@ -11,18 +11,16 @@
 //! to accept, parse, and serve HTTP `multipart/form-data` requests (file uploads).
 //!
 //! See the `Multipart` struct for more info.
-extern crate httparse;
-extern crate twoway;
+pub(crate) extern crate httparse;
+pub(crate) extern crate twoway;
 
-use futures::{Poll, Stream};
-use futures::task::{self, Task};
+use self::boundary::BoundaryFinder;
 
-use std::cell::Cell;
-use std::rc::Rc;
+use self::error::context_err;
 
-use self::boundary::BoundaryFinder;
+use self::sync::{Flag, Lock};
 
-use {BodyChunk, StreamError};
+use crate::{BodyChunk, StreamError};
 
 macro_rules! try_opt (
     ($expr:expr) => (
@@ -35,131 +33,652 @@ macro_rules! try_opt (
 
 macro_rules! ret_err (
     ($($args:tt)+) => (
-            return fmt_err!($($args)+);
+            return fmt_err!($($args)+)
     )
 );
 
 macro_rules! fmt_err(
-    ($string:expr) => (
-        ::helpers::error($string)
+    ($kind:ident, $string:literal) => (
+        crate::server::error::multipart_err(crate::server::MultipartError::$kind($string.into()))
     );
-    ($string:expr, $($args:tt)*) => (
-        ::helpers::error(format!($string, $($args)*))
+    ($kind:ident, $string:literal, $($args:tt)*) => (
+        crate::server::error::multipart_err(crate::server::MultipartError::$kind(
+            format!($string, $($args)*).into()))
     );
 );
 
 mod boundary;
+#[cfg(feature = "serde")]
+mod de;
+mod error;
 mod field;
+mod form;
+mod limits;
+mod mode;
+mod preamble;
+mod progress;
+mod spool;
+mod sync;
+
+use crate::helpers::*;
+
+use self::field::{ReadHeaders, SharedState};
+
+use self::spool::{Spool, Spooling};
+
+pub use self::field::{sniff_content_type, AsyncReadField, DecodeField, ExtendedValue, Field,
+                      FieldHeaders, FieldData, FilenamePolicy, Part, PartHeaders, ReadField,
+                      ReadTextField, SaveField, SavedField, SniffField, SniffPolicy, Sniffed,
+                      TextField, SNIFF_LEN};
+
+#[cfg(feature = "digest")]
+pub use self::field::{to_hex, Algorithm, Digests, HashField};
+
+pub use self::form::{CollectForm, Form, FormOptions};
+
+#[cfg(feature = "serde")]
+pub use self::de::{DeError, DeserializeForm};
+
+pub use self::error::{MultipartError, ParseContext};
+
+pub use self::limits::{LimitExceeded, LimitKind, Limits};
+
+pub use self::mode::ParseMode;
 
-use helpers::*;
+pub use self::preamble::{Epilogue, Preamble};
 
-use self::field::ReadHeaders;
+pub use self::progress::{FieldProgress, Progress, ProgressListener};
 
-pub use self::field::{Field, FieldHeaders, FieldData, ReadTextField, TextField};
+pub use self::spool::SpoolOptions;
+
+use self::limits::{check_limit, LimitKind::*};
+
+pub use self::sync::{MaybeSend, Shared};
+
+#[cfg(feature = "futures01")]
+mod compat;
+
+#[cfg(feature = "futures01")]
+pub use self::compat::Compat01Body;
 
 #[cfg(feature = "hyper")]
 mod hyper;
 
 #[cfg(feature = "hyper")]
-pub use self::hyper::{MinusBody, MultipartService};
+pub use self::hyper::{IncomingBody, MinusBody, MultipartService};
 
 /// The server-side implementation of `multipart/form-data` requests.
 ///
 /// This will parse the incoming stream into `Field` instances via its
-/// `Stream` implementation.
+/// `Stream` implementation, so fields can be read in an `async` block:
+///
+/// ```rust,ignore
+/// use futures::TryStreamExt;
+///
+/// while let Some(field) = multipart.try_next().await? {
+///     println!("field: {:?}", field.headers);
+///     // read the field data before asking for the next field
+/// }
+/// ```
+///
+/// The body stream must be `Unpin`; wrap it with `Box::pin()` if it isn't.
+///
+/// ### Other Multipart Types
+/// Fields are validated as `multipart/form-data`. Bodies of other subtypes, such as
+/// `multipart/mixed` or `multipart/related`, can be read as `Part`s with `into_parts()`,
+/// which don't require a `Content-Disposition` header and carry the full header map.
+///
+/// ### Limits
+/// The size of field headers is limited by default; limits on the number of fields, the size of
+/// field data and of the whole request can be set with `Limits`. Breaking a limit yields an error
+/// constructed with `StreamError::from_limit()`, which should be answered with
+/// `413 Payload Too Large`.
+///
+/// ### Errors
+/// Errors found while parsing the request are described by `MultipartError` and converted to
+/// the error type of the body stream with `StreamError::from_multipart()`. With the default
+/// implementation they can be recovered from the resulting `io::Error` with
+/// `MultipartError::from_io_error()`, e.g. to answer a `MissingContentDisposition` with
+/// `400 Bad Request`.
+///
+/// Errors in the boundaries and field headers also carry a `ParseContext` with their byte offset
+/// in the request, the index and name of the field being read and an excerpt of the data; it is
+/// included in the message of the error, and can be read with `MultipartError::context()`.
+/// To skip parts with invalid headers and keep reading the rest of the request, enable
+/// `set_recovery()`.
+///
+/// ### Threading
+/// By default, this type and `Field` share state via `Rc` and so are `!Send`. With the `use_arc`
+/// feature enabled, `Arc` and `Mutex` are used instead, so `Multipart`, `Field`, `FieldData`
+/// and `TextField` are `Send` if the body stream and its chunk type are.
 ///
 /// To maintain consistency in the underlying stream, this will not yield more than one
 /// `Field` at a time. A `Drop` implementation on `FieldData` is used to signal
 /// when it's time to move forward, so do avoid leaking that type or anything which contains it
 /// (`Field`, `ReadTextField`, or any stream combinators).
-pub struct Multipart<S: Stream> {
-    internal: Rc<Internal<S>>,
+///
+/// To hold more than one field at once, enable spooling with `set_spooling()`: the rest of the
+/// current field is then buffered when the next one is polled for.
+pub struct Multipart<S: TryStream> {
+    internal: Shared<Internal<S>>,
     read_hdr: ReadHeaders,
     consumed: bool,
+    fields: u64,
+    files: u64,
+    strict_epilogue: bool,
+    recovery: bool,
+    spooling: Option<Spooling<S::Ok>>,
 }
 
 // Q: why can't we just wrap up these bounds into a trait?
 // A: https://github.com/rust-lang/rust/issues/24616#issuecomment-112065997
 // (The workaround mentioned in a later comment doesn't seem to be worth the added complexity)
-impl<S: Stream> Multipart<S> where S::Item: BodyChunk, S::Error: StreamError {
+impl<S: TryStream + Unpin> Multipart<S> where S::Ok: BodyChunk, S::Error: StreamError {
     /// Construct a new `Multipart` with the given body reader and boundary.
     ///
     /// This will add the requisite `--` and CRLF (`\r\n`) to the boundary as per
     /// [IETF RFC 7578 section 4.1](https://tools.ietf.org/html/rfc7578#section-4.1).
     pub fn with_body<B: Into<String>>(stream: S, boundary: B) -> Self {
+        Self::with_limits(stream, boundary, Limits::default())
+    }
+
+    /// Construct a new `Multipart` with the given body reader, boundary and limits.
+    ///
+    /// See `Limits` for the defaults used by `with_body()`.
+    pub fn with_limits<B: Into<String>>(stream: S, boundary: B, limits: Limits) -> Self {
         let mut boundary = boundary.into();
         boundary.insert_str(0, "--");
 
         debug!("Boundary: {}", boundary);
 
         Multipart { 
-            internal: Rc::new(Internal::new(stream, boundary)),
+            internal: Shared::new(Internal::new(stream, boundary, limits)),
             read_hdr: ReadHeaders::default(),
             consumed: false,
+            fields: 0,
+            files: 0,
+            strict_epilogue: false,
+            recovery: false,
+            spooling: None,
         }
     }
-}
 
-impl<S: Stream> Stream for Multipart<S> where S::Item: BodyChunk, S::Error: StreamError {
-    type Item = Field<S>;
-    type Error = S::Error;
-
-    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
-        // FIXME: combine this with the next statement when non-lexical lifetimes are added
-        // shouldn't be an issue anyway because the optimizer can fold these checks together
-        if Rc::get_mut(&mut self.internal).is_none() {
-            debug!("returning NotReady, field was in flight");
-            self.internal.park_curr_task();
-            return not_ready();
+    /// A `Multipart` for a nested body in the field `parent_name`, whose parts inherit its name.
+    fn nested(stream: S, boundary: String, limits: Limits, parent_name: String) -> Self {
+        let mut multipart = Self::with_limits(stream, boundary, limits);
+        multipart.read_hdr.parent_name = Some(parent_name);
+        multipart
+    }
+
+    /// Change the limits of this request.
+    ///
+    /// Size limits already apply to data read before this call, and count limits to fields
+    /// already yielded.
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.internal.stream.lock().limits = limits;
+    }
+
+    /// Decode field names and filenames the way browsers encode them.
+    ///
+    /// Per the [HTML5 spec][spec], browsers don't use backslash escapes in the quoted
+    /// `Content-Disposition` parameters, and instead send `"`, CR and LF as `%22`, `%0D` and
+    /// `%0A`. If enabled, backslashes are taken literally and these escapes are reversed, so
+    /// the parsed names match what was in the HTML form. Other `%` sequences are left alone.
+    ///
+    /// Disabled by default, in which case quoted parameters are parsed according to
+    /// [IETF RFC 7230 Section 3.2.6](https://tools.ietf.org/html/rfc7230#section-3.2.6).
+    ///
+    /// [spec]: https://html.spec.whatwg.org/multipage/form-control-infrastructure.html#multipart-form-data
+    pub fn set_html5_escapes(&mut self, enabled: bool) {
+        self.read_hdr.html5_escapes = enabled;
+    }
+
+    /// Reject a request with anything but whitespace after the closing boundary.
+    ///
+    /// If enabled, the epilogue is read when `poll_field()` or `poll_part()` reaches the end of
+    /// the request, yielding an error if it isn't all whitespace, and so can't be read with
+    /// `epilogue()` afterwards. Disabled by default, in which case the epilogue is ignored
+    /// unless it is read.
+    pub fn set_strict_epilogue(&mut self, enabled: bool) {
+        self.strict_epilogue = enabled;
+    }
+
+    /// Skip parts with invalid headers instead of failing the whole request.
+    ///
+    /// If enabled, an error in the headers of a part, such as a malformed header, a missing
+    /// `Content-Disposition` or breaking `Limits::header_block_size`, is yielded as an error
+    /// item, after which polling again skips to the next boundary and yields the parts after it.
+    /// Errors from the body stream, in the boundaries or from other limits can't be recovered
+    /// from this way. Disabled by default, in which case the request should be abandoned
+    /// after an error.
+    ///
+    /// The `ParseContext` of the error tells which part was skipped; see `MultipartError`.
+    pub fn set_recovery(&mut self, enabled: bool) {
+        self.recovery = enabled;
+    }
+
+    /// Set how strictly the framing of the request is checked; `ParseMode::Strict` by default.
+    ///
+    /// In `ParseMode::Lenient`, bare LF line endings, padding after boundaries and a missing
+    /// closing boundary are accepted, as sent by some non-conforming clients.
+    pub fn set_parse_mode(&mut self, mode: ParseMode) {
+        self.internal.stream.lock().mode = mode;
+    }
+
+    /// Allow more than one field to be held at once by spooling the rest of the current field
+    /// when the next one is polled for, or disable spooling with `None`.
+    ///
+    /// ```rust,ignore
+    /// multipart.set_spooling(Some(SpoolOptions::default().memory_limit(16 * 1024)));
+    ///
+    /// let file = multipart.try_next().await?.unwrap();
+    /// let meta = multipart.try_next().await?.unwrap().data.read_text().await?;
+    /// let saved = file.data.save().with_dir(&meta.text).await?;
+    /// ```
+    ///
+    /// By default, polling for the next field waits until the `FieldData` of the current one
+    /// has been dropped. With spooling enabled, the remaining data of the current field is
+    /// instead read into a buffer, in memory up to `SpoolOptions::memory_limit` and then in a
+    /// temporary file, and the next field is yielded. The earlier `FieldData` reads from that
+    /// buffer, so the fields can be consumed independently and in any order. The spooled data
+    /// still counts against `Limits::field_size`. If that limit is broken while spooling, or the
+    /// temporary file can't be written, the earlier `FieldData` yields the error after the data
+    /// spooled before it, and the rest of that field is skipped.
+    ///
+    /// Spooling requires the chunk type to be constructible from `Vec<u8>`, as data read back
+    /// from disk has to be yielded as chunks. The temporary file is written and read with
+    /// blocking I/O, like `FieldData::save()`, and deleted when the `FieldData` is dropped.
+    pub fn set_spooling(&mut self, options: Option<SpoolOptions>) where S::Ok: From<Vec<u8>> {
+        self.spooling = options.map(|options| Spooling { options, from_vec: S::Ok::from });
+    }
+
+    /// Report the progress of reading the request to `listener`, replacing any previous one.
+    ///
+    /// ```rust,ignore
+    /// let uploaded = Arc::new(AtomicU64::new(0));
+    /// let counter = uploaded.clone();
+    ///
+    /// multipart.set_progress_listener(move |progress: &Progress| {
+    ///     counter.store(progress.bytes_read, Ordering::Relaxed);
+    /// });
+    /// ```
+    ///
+    /// The listener is told the total number of bytes read from the body stream, and the name,
+    /// filename and number of bytes read of the current field. It is called as the parser
+    /// reads the request, so the counts include fields which are skipped by dropping them.
+    pub fn set_progress_listener<L: ProgressListener + 'static>(&mut self, listener: L) {
+        self.internal.stream.lock().set_progress_listener(listener);
+    }
+
+    /// Poll for the next chunk of the preamble, the data before the first boundary, with the
+    /// same semantics as `Stream::poll_next()`.
+    ///
+    /// Yields `None` once the first boundary is reached, or if the first field has already been
+    /// polled for, as the preamble is then skipped. Its size is limited by
+    /// `Limits::preamble_size`.
+    pub fn poll_preamble(&mut self, cx: &mut Context) -> PollOpt<S::Ok, S::Error> {
+        self.internal.stream.lock().preamble_chunk(cx)
+    }
+
+    /// Get a `Stream` of the chunks of the preamble; see `poll_preamble()`.
+    ///
+    /// ```rust,ignore
+    /// let preamble: Vec<u8> = multipart.preamble().map_ok(|c| c.to_vec()).try_concat().await?;
+    ///
+    /// while let Some(field) = multipart.try_next().await? {
+    ///     // ...
+    /// }
+    /// ```
+    pub fn preamble(&mut self) -> Preamble<'_, S> {
+        Preamble { multipart: self }
+    }
+
+    /// Poll for the next chunk of the epilogue, the data after the closing boundary, with the
+    /// same semantics as `Stream::poll_next()`.
+    ///
+    /// Yields `None` until `poll_field()` or `poll_part()` has reached the end of the request,
+    /// and again when the body stream ends. Its size is limited by `Limits::epilogue_size`.
+    pub fn poll_epilogue(&mut self, cx: &mut Context) -> PollOpt<S::Ok, S::Error> {
+        let mut stream = self.internal.stream.lock();
+        let chunk = ready!(stream.epilogue_chunk(cx)?);
+
+        match chunk {
+            Some(ref chunk) if self.strict_epilogue => check_epilogue(&stream, chunk)?,
+            _ => (),
+        }
+
+        Poll::Ready(chunk.map(Ok))
+    }
+
+    /// Get a `Stream` of the chunks of the epilogue; see `poll_epilogue()`.
+    pub fn epilogue(&mut self) -> Epilogue<'_, S> {
+        Epilogue { multipart: self }
+    }
+
+    /// Poll for the next field in the request, with the same semantics as `Stream::poll_next()`
+    /// but without requiring `Pin`.
+    ///
+    /// Each field must have a `Content-Disposition: form-data` header with a `name`; use
+    /// `poll_part()` or `into_parts()` to read other kinds of multipart bodies.
+    pub fn poll_field(&mut self, cx: &mut Context) -> Poll<Option<Result<Field<S>, S::Error>>> {
+        let headers = match ready!(self.poll_headers(cx)?) {
+            Some(headers) => {
+                let parent_name = self.read_hdr.parent_name.as_deref();
+                FieldHeaders::form_data::<MultipartError>(headers, parent_name)
+                    .or_else(|err| context_err(err, self.read_hdr.context.clone()))?
+            },
+            None => return Poll::Ready(None),
+        };
+
+        info!("read field: {:?}", headers);
+
+        Poll::Ready(Some(Ok(field::new_field(headers, self.internal.clone()))))
+    }
+
+    /// Poll for the next part of a multipart body of any subtype, such as `multipart/mixed`,
+    /// `multipart/related` or `multipart/alternative`.
+    ///
+    /// No headers are required on the part, and `Content-Disposition` is parsed if present
+    /// but may have any disposition type.
+    pub fn poll_part(&mut self, cx: &mut Context) -> Poll<Option<Result<Part<S>, S::Error>>> {
+        let headers = match ready!(self.poll_headers(cx)?) {
+            Some(headers) => headers,
+            None => return Poll::Ready(None),
+        };
+
+        info!("read part: {:?}", headers);
+
+        Poll::Ready(Some(Ok(field::new_part(headers, self.internal.clone()))))
+    }
+
+    /// Get a `Stream` of the `Part`s of a multipart body of any subtype.
+    ///
+    /// ```rust,ignore
+    /// let mut parts = multipart.into_parts();
+    ///
+    /// while let Some(part) = parts.try_next().await? {
+    ///     println!("part: {:?}", part.headers);
+    /// }
+    /// ```
+    pub fn into_parts(self) -> Parts<S> {
+        Parts { multipart: self }
+    }
+
+    /// Spool the rest of the field in flight, if any, after which it no longer blocks the
+    /// next field.
+    fn poll_spool(&mut self, cx: &mut Context) -> Poll<Result<(), S::Error>> {
+        let field = match *self.internal.current_field.lock() {
+            Some(ref field) => field.clone(),
+            None => return ready_ok(()),
+        };
+
+        let spooling = self.spooling.as_ref().expect("spooling not enabled");
+
+        {
+            let mut state = field.lock();
+            let state = &mut *state;
+            let spool = state.spool.get_or_insert_with(|| Spool::new(spooling));
+
+            let mut stream = self.internal.stream.lock();
+
+            loop {
+                let chunk = match stream.body_chunk(cx) {
+                    Poll::Ready(Some(chunk)) => chunk?,
+                    Poll::Ready(None) => break,
+                    // the field may read the stream itself in the meantime, taking over its
+                    // waker, so it wakes us after every read instead
+                    Poll::Pending => {
+                        self.internal.park_curr_task(cx);
+                        return Poll::Pending;
+                    },
+                };
+
+                state.size += chunk.len() as u64;
+
+                let res = check_limit(FieldSize, state.size, stream.limits.field_size)
+                    .and_then(|()| spool.push(chunk).map_err(S::Error::from));
+
+                if let Err(err) = res {
+                    // the field yields the error after the data spooled before it, rather than
+                    // reading on from the stream with a hole in its data; the rest is skipped
+                    debug!("failed to spool the rest of the field in flight");
+                    state.error = Some(err);
+                    break;
+                }
+
+                wake(&mut state.waker);
+            }
+
+            debug!("finished spooling the field in flight");
+            spool.set_complete();
+            wake(&mut state.waker);
+        }
+
+        self.internal.field_done(&field);
+
+        ready_ok(())
+    }
+
+    fn poll_headers(&mut self, cx: &mut Context) -> PollOpt<PartHeaders, S::Error> {
+        if self.spooling.is_some() {
+            ready!(self.poll_spool(cx)?);
+        }
+
+        // We don't want to return another `Field` until the previous one has been dropped.
+        if self.internal.field_in_flight.get() {
+            self.internal.park_curr_task(cx);
+
+            // the field may have been dropped before we parked, in which case nobody will wake us
+            if self.internal.field_in_flight.get() {
+                debug!("returning Pending, field was in flight");
+                return Poll::Pending;
+            }
         }
 
-        // We don't want to return another `Field` unless we have exclusive access.
         let headers = {
-            let stream = Rc::get_mut(&mut self.internal).unwrap().stream.get_mut();
+            let mut stream = self.internal.stream.lock();
+            let stream = &mut *stream;
 
             // only attempt to consume the boundary if it hasn't been done yet
-            self.consumed = self.consumed || try_ready!(stream.consume_boundary());
+            self.consumed = self.consumed || ready!(stream.consume_boundary(cx)?);
 
             if !self.consumed {
-                return ready(None);
+                if self.strict_epilogue {
+                    while let Some(chunk) = ready!(stream.epilogue_chunk(cx)?) {
+                        check_epilogue(stream, &chunk)?;
+                    }
+                }
+
+                return Poll::Ready(None);
             }
 
-            match try_ready!(self.read_hdr.read_headers(stream)) {
-                Some(headers) => headers,
-                None => return ready(None),
+            let headers = match ready!(self.read_hdr.read_headers(stream, cx)) {
+                Some(Ok(headers)) => headers,
+                Some(Err(err)) => {
+                    // consume the next boundary the next time, skipping the rest of the part
+                    if self.recovery && self.read_hdr.part_error {
+                        debug!("skipping a part with invalid headers");
+                        self.consumed = false;
+                    }
+
+                    return Poll::Ready(Some(Err(err)));
+                },
+                None => return Poll::Ready(None),
+            };
+
+            self.fields += 1;
+            check_limit(Fields, self.fields, stream.limits.fields)?;
+
+            if headers.filename.is_some() {
+                self.files += 1;
+                check_limit(Files, self.files, stream.limits.files)?;
             }
+
+            let name = headers.name.as_deref().or(self.read_hdr.parent_name.as_deref());
+            stream.start_field(name, headers.filename.as_deref());
+
+            headers
         };
 
         // the boundary should be consumed the next time poll() is ready to move forward
         self.consumed = false;
 
-        info!("read field: {:?}", headers);
+        ready_some(headers)
+    }
+
+    /// Get a `Future` which reads all the fields of the request into a `Form`.
+    ///
+    /// ```rust,ignore
+    /// let form = multipart.collect_form(FormOptions::default()).await?;
+    /// let name = form.text("name");
+    /// let upload = form.file("upload");
+    /// ```
+    ///
+    /// Fields with a filename are saved to files as with `FieldData::save()`, in a temporary
+    /// directory owned by the `Form` unless `FormOptions::dir` is set; all other fields are
+    /// read to strings as with `FieldData::read_text()`. The limits of either may be set
+    /// in `options`.
+    pub fn collect_form(self, options: FormOptions) -> CollectForm<S> {
+        form::collect_form(self, options)
+    }
 
-        ready(field::new_field(headers, self.internal.clone()))
+    /// Get a `Future` which reads all the fields of the request into a `T` with its
+    /// `serde::Deserialize` implementation, using the default `FormOptions`.
+    ///
+    /// ```rust,ignore
+    /// #[derive(Deserialize)]
+    /// struct UploadForm {
+    ///     title: String,
+    ///     count: u32,
+    ///     public: bool,
+    ///     tags: Vec<String>,
+    ///     upload: SavedField,
+    /// }
+    ///
+    /// let form: UploadForm = multipart.deserialize().await?;
+    /// ```
+    ///
+    /// See `Form::deserialize()` for how fields are mapped to `T`.
+    #[cfg(feature = "serde")]
+    pub fn deserialize<T: serde::de::DeserializeOwned>(self) -> DeserializeForm<S, T> {
+        self.deserialize_with(FormOptions::default())
+    }
+
+    /// Like `deserialize()`, but with the limits and directory set in `options`.
+    ///
+    /// Unless `FormOptions::dir` is set, each file field is saved to its own temporary
+    /// directory which is deleted when its `SavedField` is dropped.
+    #[cfg(feature = "serde")]
+    pub fn deserialize_with<T: serde::de::DeserializeOwned>(self, options: FormOptions)
+        -> DeserializeForm<S, T> {
+        de::deserialize_form(form::collect_form(self, options).separate_temp_dirs())
     }
 }
 
-struct Internal<S: Stream> {
-    stream: Cell<BoundaryFinder<S>>,
-    waiting_task: Cell<Option<Task>>,
+impl<S: TryStream + Unpin> Stream for Multipart<S> where S::Ok: BodyChunk, S::Error: StreamError {
+    type Item = Result<Field<S>, S::Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        self.get_mut().poll_field(cx)
+    }
+}
+
+/// Wake the task reading a field, if it's waiting.
+fn wake(waker: &mut Option<Waker>) {
+    if let Some(waker) = waker.take() {
+        waker.wake();
+    }
 }
 
-impl<S: Stream> Internal<S> {
-    fn new(stream: S, boundary: String) -> Self {
+/// Check that `chunk`, which was just read from the epilogue of `stream`, is only whitespace.
+fn check_epilogue<S: TryStream + Unpin>(stream: &BoundaryFinder<S>, chunk: &S::Ok) -> Result<(), S::Error>
+where S::Ok: BodyChunk, S::Error: StreamError {
+    let chunk = chunk.as_slice();
+
+    if let Some(idx) = chunk.iter().position(|b| !b.is_ascii_whitespace()) {
+        let offset = stream.position() - (chunk.len() - idx) as u64;
+        let err = MultipartError::Boundary("unexpected data after the closing boundary".into());
+        return context_err(err, stream.context(offset, &chunk[idx..]));
+    }
+
+    Ok(())
+}
+
+/// A `Stream` of the `Part`s of a multipart body of any subtype, returned by
+/// `Multipart::into_parts()`.
+pub struct Parts<S: TryStream> {
+    multipart: Multipart<S>,
+}
+
+impl<S: TryStream + Unpin> Parts<S> where S::Ok: BodyChunk, S::Error: StreamError {
+    /// Poll for the next part, with the same semantics as `Stream::poll_next()`
+    /// but without requiring `Pin`.
+    pub fn poll_part(&mut self, cx: &mut Context) -> Poll<Option<Result<Part<S>, S::Error>>> {
+        self.multipart.poll_part(cx)
+    }
+
+    /// Get the `Multipart` back, e.g. to change its limits.
+    pub fn into_inner(self) -> Multipart<S> {
+        self.multipart
+    }
+}
+
+impl<S: TryStream + Unpin> Stream for Parts<S> where S::Ok: BodyChunk, S::Error: StreamError {
+    type Item = Result<Part<S>, S::Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        self.get_mut().poll_part(cx)
+    }
+}
+
+struct Internal<S: TryStream> {
+    stream: Lock<BoundaryFinder<S>>,
+    waiting_task: Lock<Option<Waker>>,
+    field_in_flight: Flag,
+    /// The state of the field in flight, if any.
+    current_field: Lock<Option<SharedState<S::Ok, S::Error>>>,
+}
+
+impl<S: TryStream> Internal<S> {
+    fn new(stream: S, boundary: String, limits: Limits) -> Self {
         debug_assert!(boundary.starts_with("--"), "Boundary must start with --");
 
         Internal {
-            stream: BoundaryFinder::new(stream, boundary).into(),
-            waiting_task: None.into(),
+            stream: Lock::new(BoundaryFinder::new(stream, boundary, limits)),
+            waiting_task: Lock::new(None),
+            field_in_flight: Flag::new(false),
+            current_field: Lock::new(None),
         }
     }
 
-    fn park_curr_task(&self) {
-        self.waiting_task.set(Some(task::current()));
+    fn park_curr_task(&self, cx: &mut Context) {
+        *self.waiting_task.lock() = Some(cx.waker().clone());
+    }
+
+    /// Called when a field is dropped; does nothing if it was spooled and another field is
+    /// in flight.
+    fn field_done(&self, field: &SharedState<S::Ok, S::Error>) {
+        {
+            let mut current = self.current_field.lock();
+
+            if !current.as_ref().is_some_and(|current| Shared::ptr_eq(current, field)) {
+                return;
+            }
+
+            *current = None;
+        }
+
+        self.field_in_flight.set(false);
+        self.wake_waiting_task();
     }
 
-    fn notify_task(&self) {
-        self.waiting_task.take().map(|t| t.notify());
+    fn wake_waiting_task(&self) {
+        // don't hold the lock while waking
+        let waker = self.waiting_task.lock().take();
+
+        if let Some(waker) = waker {
+            waker.wake();
+        }
     }
 }
 
@@ -170,4 +689,881 @@ pub trait RequestExt: Sized {
 
     /// Convert `Self` into `Self::Multipart` if applicable.
     fn into_multipart(self) -> Result<Self::Multipart, Self>;
+
+    /// Like `into_multipart()`, but also accepting other multipart subtypes such as
+    /// `multipart/mixed` or `multipart/related`, whose body should be read with
+    /// `Multipart::into_parts()`.
+    ///
+    /// By default this is the same as `into_multipart()`.
+    fn into_multipart_any(self) -> Result<Self::Multipart, Self> {
+        self.into_multipart()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use futures::executor::block_on;
+    use futures::TryStreamExt;
+
+    use super::Multipart;
+
+    const BOUNDARY: &str = "boundary";
+
+    #[test]
+    fn test_read_fields() {
+        let stream = crate::mock_stream!(
+            "--boundary\r\n";
+            "Content-Disposition: form-data; name=\"foo\"\r\n\r\n";
+            "field data";
+            "\r\n--boundary\r\n";
+            "Content-Disposition: form-data; name=\"bar\"; filename=\"bar.txt\"\r\n\
+             Content-Type: text/plain\r\n\r\n";
+            "file data";
+            "\r\n--boundary--"
+        );
+
+        let mut multipart = Multipart::with_body(stream, BOUNDARY);
+
+        block_on(async {
+            let field = multipart.try_next().await.unwrap().unwrap();
+            assert_eq!(field.headers.name, "foo");
+            assert_eq!(field.headers.filename, None);
+            assert_eq!(field.data.read_text().await.unwrap().text, "field data");
+
+            let field = multipart.try_next().await.unwrap().unwrap();
+            assert_eq!(field.headers.name, "bar");
+            assert_eq!(field.headers.filename.as_ref().unwrap(), "bar.txt");
+
+            let data: Vec<u8> = field.data.map_ok(|chunk| chunk.into_owned())
+                .try_concat().await.unwrap();
+            assert_eq!(data, b"file data");
+
+            assert!(multipart.try_next().await.unwrap().is_none());
+        });
+    }
+
+    #[test]
+    fn test_spooling() {
+        let stream = crate::mock_stream!(
+            "--boundary\r\n";
+            "Content-Disposition: form-data; name=\"file\"; filename=\"file.txt\"\r\n\r\n";
+            "file ";
+            "data";
+            " spilled to disk";
+            "\r\n--boundary\r\n";
+            "Content-Disposition: form-data; name=\"text\"\r\n\r\n";
+            "text data";
+            "\r\n--boundary\r\n";
+            "Content-Disposition: form-data; name=\"dropped\"\r\n\r\n";
+            "dropped data";
+            "\r\n--boundary--"
+        );
+
+        let mut multipart = Multipart::with_body(stream, BOUNDARY);
+        multipart.set_spooling(Some(super::SpoolOptions::default().memory_limit(10)));
+
+        block_on(async {
+            let mut file = multipart.try_next().await.unwrap().unwrap();
+            assert_eq!(file.headers.name, "file");
+            assert_eq!(&*file.data.try_next().await.unwrap().unwrap(), b"file ");
+
+            let text = multipart.try_next().await.unwrap().unwrap();
+            assert_eq!(text.headers.name, "text");
+
+            // skipping a field entirely still spools it
+            let dropped = multipart.try_next().await.unwrap().unwrap();
+            assert_eq!(dropped.headers.name, "dropped");
+            assert!(multipart.try_next().await.unwrap().is_none());
+
+            assert_eq!(text.data.read_text().await.unwrap().text, "text data");
+            assert_eq!(file.data.read_text().await.unwrap().text, "data spilled to disk");
+        });
+    }
+
+    #[test]
+    fn test_spooling_concurrent() {
+        spooling_concurrent(true);
+        spooling_concurrent(false);
+    }
+
+    /// Read two fields in separate tasks while the body stream is pending, spawning the
+    /// reader of the first field before or after the task reading the second.
+    fn spooling_concurrent(reader_first: bool) {
+        use std::io;
+
+        use futures::channel::mpsc;
+        use futures::executor::LocalPool;
+        use futures::future::{self, FutureExt};
+        use futures::task::LocalSpawnExt;
+
+        let (tx, body) = mpsc::unbounded::<Result<Vec<u8>, io::Error>>();
+
+        let mut multipart = Multipart::with_body(body, BOUNDARY);
+        multipart.set_spooling(Some(super::SpoolOptions::default()));
+
+        tx.unbounded_send(Ok(b"--boundary\r\n\
+                                Content-Disposition: form-data; name=\"first\"\r\n\r\n\
+                                first ".to_vec())).unwrap();
+
+        let mut pool = LocalPool::new();
+        let first = pool.run_until(multipart.try_next()).unwrap().unwrap();
+
+        let read_first = first.data.read_text();
+        let read_second = async move {
+            let field = multipart.try_next().await?.unwrap();
+            assert_eq!(field.headers.name, "second");
+            field.data.read_text().await
+        };
+
+        // both tasks wait on the body stream, which only wakes the last one to poll it
+        let spawner = pool.spawner();
+        let (first, second) = if reader_first {
+            let first = spawner.spawn_local_with_handle(read_first).unwrap();
+            (first, spawner.spawn_local_with_handle(read_second).unwrap())
+        } else {
+            let second = spawner.spawn_local_with_handle(read_second).unwrap();
+            (spawner.spawn_local_with_handle(read_first).unwrap(), second)
+        };
+
+        pool.run_until_stalled();
+
+        tx.unbounded_send(Ok(b"data\r\n\
+                                --boundary\r\n\
+                                Content-Disposition: form-data; name=\"second\"\r\n\r\n\
+                                second data\r\n\
+                                --boundary--".to_vec())).unwrap();
+        drop(tx);
+
+        pool.run_until_stalled();
+
+        let (first, second) = future::join(first, second).now_or_never()
+            .expect("a task reading a spooled field was not woken");
+
+        assert_eq!(first.unwrap().text, "first data");
+        assert_eq!(second.unwrap().text, "second data");
+    }
+
+    #[test]
+    fn test_spooling_error() {
+        use super::Limits;
+
+        let stream = crate::mock_stream!(
+            "--boundary\r\n\
+             Content-Disposition: form-data; name=\"first\"\r\n\r\n";
+            "first ";
+            "data";
+            " and more";
+            "\r\n--boundary\r\n\
+             Content-Disposition: form-data; name=\"second\"\r\n\r\n\
+             second data\r\n\
+             --boundary--"
+        );
+
+        let mut multipart = Multipart::with_limits(stream, BOUNDARY, Limits::default().field_size(12));
+        multipart.set_spooling(Some(super::SpoolOptions::default()));
+
+        block_on(async {
+            let mut first = multipart.try_next().await.unwrap().unwrap();
+            assert_eq!(&*first.data.try_next().await.unwrap().unwrap(), b"first ");
+
+            // the limit is broken while spooling; the rest of the field is skipped
+            let second = multipart.try_next().await.unwrap().unwrap();
+            assert_eq!(second.data.read_text().await.unwrap().text, "second data");
+            assert!(multipart.try_next().await.unwrap().is_none());
+
+            // the field yields what was spooled, then the error, instead of reading on
+            assert_eq!(&*first.data.try_next().await.unwrap().unwrap(), b"data");
+            assert_eq!(first.data.try_next().await.unwrap_err(), "field size limit of 12 exceeded");
+            assert!(first.data.try_next().await.unwrap().is_none());
+        });
+    }
+
+    #[test]
+    fn test_progress() {
+        use std::sync::{Arc, Mutex};
+
+        const BODY: &str = "--boundary\r\n\
+                            Content-Disposition: form-data; name=\"text\"\r\n\r\n\
+                            text data\r\n\
+                            --boundary\r\n\
+                            Content-Disposition: form-data; name=\"file\"; filename=\"file.txt\"\r\n\r\n\
+                            file data, skipped\r\n\
+                            --boundary--";
+
+        let stream = crate::mock_stream!(&BODY[..50]; &BODY[50..100]; &BODY[100..]);
+        let mut multipart = Multipart::with_body(stream, BOUNDARY);
+
+        let progress = Arc::new(Mutex::new(Vec::new()));
+        let listener = progress.clone();
+        multipart.set_progress_listener(move |progress: &super::Progress| {
+            listener.lock().unwrap().push(progress.clone());
+        });
+
+        block_on(async {
+            let field = multipart.try_next().await.unwrap().unwrap();
+            assert_eq!(field.data.read_text().await.unwrap().text, "text data");
+
+            // dropped without reading
+            let _ = multipart.try_next().await.unwrap().unwrap();
+            assert!(multipart.try_next().await.unwrap().is_none());
+        });
+
+        let progress = progress.lock().unwrap();
+        let last = progress.last().unwrap();
+        assert_eq!(last.bytes_read, BODY.len() as u64);
+        assert_eq!(last.fields, 2);
+        assert_eq!(last.field, None);
+
+        // the last report for each field before it ended
+        let fields: Vec<_> = progress.windows(2)
+            .filter(|pair| pair[0].field.is_some() && pair[1].field.is_none())
+            .map(|pair| pair[0].field.clone().unwrap())
+            .map(|field| (field.name.unwrap(), field.filename, field.bytes_read))
+            .collect();
+
+        assert_eq!(fields, [("text".to_string(), None, 9),
+                            ("file".to_string(), Some("file.txt".to_string()), 18)]);
+    }
+
+    #[test]
+    fn test_one_chunk() {
+        let stream = crate::mock_stream!(
+            "--boundary\r\n\
+             Content-Disposition: form-data; name=\"foo\"\r\n\r\n\
+             field data\r\n\
+             --boundary\r\n\
+             Content-Disposition: form-data; name=\"bar\"\r\n\r\n\
+             field data 2\r\n\
+             --boundary--"
+        );
+
+        let mut multipart = Multipart::with_body(stream, BOUNDARY);
+
+        block_on(async {
+            let field = multipart.try_next().await.unwrap().unwrap();
+            assert_eq!(field.headers.name, "foo");
+            assert_eq!(field.data.read_text().await.unwrap().text, "field data");
+
+            let field = multipart.try_next().await.unwrap().unwrap();
+            assert_eq!(field.headers.name, "bar");
+            assert_eq!(field.data.read_text().await.unwrap().text, "field data 2");
+
+            assert!(multipart.try_next().await.unwrap().is_none());
+        });
+    }
+
+    #[test]
+    fn test_split_boundary() {
+        // the CRLF before a boundary split from it, and a boundary split in the middle
+        let stream = crate::mock_stream!(
+            "--boundary\r\n\
+             Content-Disposition: form-data; name=\"foo\"\r\n\r\n\
+             field data\r";
+            "\n--boundary\r\n\
+             Content-Disposition: form-data; name=\"bar\"\r\n\r\n\
+             field data 2\r\n--bou";
+            "ndary--"
+        );
+
+        let mut multipart = Multipart::with_body(stream, BOUNDARY);
+
+        block_on(async {
+            let field = multipart.try_next().await.unwrap().unwrap();
+            assert_eq!(field.headers.name, "foo");
+            assert_eq!(field.data.read_text().await.unwrap().text, "field data");
+
+            let field = multipart.try_next().await.unwrap().unwrap();
+            assert_eq!(field.headers.name, "bar");
+            assert_eq!(field.data.read_text().await.unwrap().text, "field data 2");
+
+            assert!(multipart.try_next().await.unwrap().is_none());
+        });
+    }
+
+    #[test]
+    fn test_split_header_end() {
+        // the double-CRLF ending the headers split across chunks, with another one in the data
+        let stream = crate::mock_stream!(
+            "--boundary\r\n\
+             Content-Disposition: form-data; name=\"foo\"\r\n\r";
+            "\nfield\r\n\r\ndata\r\n\
+             --boundary--"
+        );
+
+        let mut multipart = Multipart::with_body(stream, BOUNDARY);
+
+        block_on(async {
+            let field = multipart.try_next().await.unwrap().unwrap();
+            assert_eq!(field.headers.name, "foo");
+            assert_eq!(field.data.read_text().await.unwrap().text, "field\r\n\r\ndata");
+
+            assert!(multipart.try_next().await.unwrap().is_none());
+        });
+    }
+
+    #[test]
+    fn test_html5_escapes() {
+        let stream = crate::mock_stream!(
+            "--boundary\r\n\
+             Content-Disposition: form-data; name=\"a%22b%22\"; filename=\"C:\\file%0A.txt\"\r\n\r\n\
+             field data\r\n\
+             --boundary--"
+        );
+
+        let mut multipart = Multipart::with_body(stream, BOUNDARY);
+        multipart.set_html5_escapes(true);
+
+        let field = block_on(multipart.try_next()).unwrap().unwrap();
+        assert_eq!(field.headers.name, "a\"b\"");
+        assert_eq!(field.headers.filename.as_ref().unwrap(), "C:\\file\n.txt");
+    }
+
+    #[test]
+    fn test_read_parts() {
+        let stream = crate::mock_stream!(
+            "--boundary\r\n\
+             Content-Type: text/html\r\n\
+             Content-ID: <root>\r\n\r\n\
+             <img src=\"cid:image\">\r\n\
+             --boundary\r\n\
+             Content-Disposition: inline; filename=\"image.gif\"\r\n\
+             Content-Type: image/gif\r\n\r\n\
+             GIF89a\r\n\
+             --boundary--"
+        );
+
+        let mut parts = Multipart::with_body(stream, BOUNDARY).into_parts();
+
+        block_on(async {
+            let part = parts.try_next().await.unwrap().unwrap();
+            assert_eq!(part.headers.content_type, Some(mime::TEXT_HTML));
+            assert_eq!(part.headers.disposition, None);
+            assert_eq!(part.headers.name, None);
+            assert_eq!(part.headers.headers["content-id"], "<root>");
+            assert_eq!(part.headers.headers.len(), 2);
+            assert_eq!(part.data.read_text().await.unwrap().text, "<img src=\"cid:image\">");
+
+            let part = parts.try_next().await.unwrap().unwrap();
+            assert_eq!(part.headers.disposition.as_deref(), Some("inline"));
+            assert_eq!(part.headers.filename.as_deref(), Some("image.gif"));
+            drop(part);
+
+            assert!(parts.try_next().await.unwrap().is_none());
+        });
+
+        // the same body is not valid `multipart/form-data`
+        let stream = crate::mock_stream!(
+            "--boundary\r\n\
+             Content-Disposition: inline; filename=\"image.gif\"\r\n\r\n\
+             GIF89a\r\n\
+             --boundary--"
+        );
+
+        assert!(block_on(Multipart::with_body(stream, BOUNDARY).try_next()).is_err());
+
+        // no headers are required on a part
+        let stream = crate::mock_stream!(
+            "--boundary\r\n\r\n\
+             no headers\r\n\
+             --boundary--"
+        );
+
+        let mut parts = Multipart::with_body(stream, BOUNDARY).into_parts();
+
+        block_on(async {
+            let part = parts.try_next().await.unwrap().unwrap();
+            assert!(part.headers.headers.is_empty());
+            assert_eq!(part.data.read_text().await.unwrap().text, "no headers");
+
+            assert!(parts.try_next().await.unwrap().is_none());
+        });
+    }
+
+    #[test]
+    fn test_preamble_epilogue() {
+        use super::Limits;
+
+        macro_rules! body (
+            ($epilogue:expr) => (crate::mock_stream!(
+                "preamble ";
+                "text\r\n--boundary\r\n\
+                 Content-Disposition: form-data; name=\"foo\"\r\n\r\n\
+                 field data\r\n\
+                 --boundary--";
+                $epilogue
+            ))
+        );
+
+        fn concat<S: futures::Stream<Item = Result<std::borrow::Cow<'static, [u8]>, E>> + Unpin, E>(stream: S)
+            -> Result<Vec<u8>, E> {
+            block_on(stream.map_ok(|chunk| chunk.into_owned()).try_concat())
+        }
+
+        let mut multipart = Multipart::with_body(body!("\r\nepilogue text"), BOUNDARY);
+
+        assert_eq!(concat(multipart.epilogue()).unwrap(), b"");
+        assert_eq!(concat(multipart.preamble()).unwrap(), b"preamble text");
+
+        let field = block_on(multipart.try_next()).unwrap().unwrap();
+        assert_eq!(field.headers.name, "foo");
+        drop(field);
+
+        assert!(block_on(multipart.try_next()).unwrap().is_none());
+        assert_eq!(concat(multipart.epilogue()).unwrap(), b"\r\nepilogue text");
+
+        // the preamble is skipped if not read
+        let mut multipart = Multipart::with_body(body!("\r\n"), BOUNDARY);
+        multipart.set_strict_epilogue(true);
+
+        drop(block_on(multipart.try_next()).unwrap().unwrap());
+        assert_eq!(concat(multipart.preamble()).unwrap(), b"");
+        assert!(block_on(multipart.try_next()).unwrap().is_none());
+
+        let mut multipart = Multipart::with_body(body!("\r\nepilogue text"), BOUNDARY);
+        multipart.set_strict_epilogue(true);
+
+        drop(block_on(multipart.try_next()).unwrap().unwrap());
+        assert!(block_on(multipart.try_next()).is_err());
+
+        let mut multipart = Multipart::with_limits(body!("\r\nepilogue text"), BOUNDARY,
+                                                   Limits::default().epilogue_size(8));
+
+        drop(block_on(multipart.try_next()).unwrap().unwrap());
+        assert!(block_on(multipart.try_next()).unwrap().is_none());
+        assert_eq!(String::from(concat(multipart.epilogue()).unwrap_err()),
+                   "epilogue size limit of 8 exceeded");
+    }
+
+    #[test]
+    fn test_decode() {
+        let stream = crate::mock_stream!(
+            "--boundary\r\n\
+             Content-Disposition: form-data; name=\"encoded\"\r\n\
+             Content-Transfer-Encoding: base64\r\n\r\n\
+             SGVsbG8s";
+            "IHdvcmxk\r\nIQ==\r\n\
+             --boundary\r\n\
+             Content-Disposition: form-data; name=\"unknown\"\r\n\
+             Content-Transfer-Encoding: x-uuencode\r\n\r\n\
+             data\r\n\
+             --boundary--"
+        );
+
+        let mut multipart = Multipart::with_body(stream, BOUNDARY);
+
+        block_on(async {
+            let field = multipart.try_next().await.unwrap().unwrap();
+            assert_eq!(field.headers.transfer_encoding(), Some("base64"));
+
+            let data: Vec<u8> = field.data.decode().try_concat().await.unwrap();
+            assert_eq!(data, b"Hello, world!");
+
+            let field = multipart.try_next().await.unwrap().unwrap();
+            let mut data = field.data.decode();
+            assert!(data.try_next().await.is_err());
+            assert_eq!(data.try_next().await.unwrap(), None);
+        });
+    }
+
+    #[test]
+    fn test_nested_multipart() {
+        let stream = crate::mock_stream!(
+            "--boundary\r\n\
+             Content-Disposition: form-data; name=\"files\"\r\n\
+             Content-Type: multipart/mixed; boundary=inner\r\n\r\n";
+            "--inner\r\n\
+             Content-Disposition: file; filename=\"file1.txt\"\r\n\
+             Content-Type: text/plain\r\n\r\n\
+             contents of file1.txt\r\n\
+             --inner\r\n";
+            "Content-Disposition: attachment; name=\"other\"; filename=\"file2.gif\"\r\n\
+             Content-Type: image/gif\r\n\r\n\
+             contents of file2.gif\r\n\
+             --inner--\r\n\
+             --boundary\r\n\
+             Content-Disposition: form-data; name=\"after\"\r\n\r\n\
+             after\r\n\
+             --boundary--"
+        );
+
+        let mut multipart = Multipart::with_body(stream, BOUNDARY);
+
+        block_on(async {
+            let field = multipart.try_next().await.unwrap().unwrap();
+            assert_eq!(field.headers.name, "files");
+
+            let mut nested = field.data.into_multipart().unwrap();
+
+            let file = nested.try_next().await.unwrap().unwrap();
+            assert_eq!(file.headers.name, "files");
+            assert_eq!(file.headers.filename.as_deref(), Some("file1.txt"));
+            assert_eq!(file.data.read_text().await.unwrap().text, "contents of file1.txt");
+
+            let file = nested.try_next().await.unwrap().unwrap();
+            assert_eq!(file.headers.name, "other");
+            assert_eq!(file.headers.filename.as_deref(), Some("file2.gif"));
+            assert_eq!(file.data.read_text().await.unwrap().text, "contents of file2.gif");
+
+            assert!(nested.try_next().await.unwrap().is_none());
+            drop(nested);
+
+            let field = multipart.try_next().await.unwrap().unwrap();
+            assert_eq!(field.headers.name, "after");
+            assert!(field.data.into_multipart().is_err());
+        });
+    }
+
+    #[test]
+    fn test_sniff() {
+        use super::SniffPolicy;
+
+        macro_rules! elf_as_png (
+            () => (crate::mock_stream!(
+                "--boundary\r\n\
+                 Content-Disposition: form-data; name=\"image\"; filename=\"image.png\"\r\n\
+                 Content-Type: image/png\r\n\r\n\
+                 \x7FE";
+                "LF\x02\x01\x01\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\r\n\
+                 --boundary--"
+            ))
+        );
+
+        let mut multipart = Multipart::with_body(elf_as_png!(), BOUNDARY);
+        let field = block_on(multipart.try_next()).unwrap().unwrap();
+        let mut data = field.data.sniff(SniffPolicy::Flag);
+
+        let first = block_on(data.try_next()).unwrap().unwrap();
+        assert_eq!(&*first, b"\x7FE");
+
+        let sniffed = data.sniffed().unwrap();
+        assert_eq!(sniffed.content_type.as_ref().unwrap().essence_str(), "application/x-executable");
+        assert!(!sniffed.content_type_matches);
+        assert!(!sniffed.extension_matches);
+
+        // the rest of the data is still yielded in order
+        let rest = block_on(data.try_next()).unwrap().unwrap();
+        assert!(rest.starts_with(b"LF"));
+
+        let mut multipart = Multipart::with_body(elf_as_png!(), BOUNDARY);
+        let field = block_on(multipart.try_next()).unwrap().unwrap();
+        let mut data = field.data.sniff(SniffPolicy::Reject);
+
+        assert!(block_on(data.try_next()).is_err());
+        assert!(data.sniffed().unwrap().is_mismatch());
+        assert_eq!(block_on(data.try_next()).unwrap(), None);
+    }
+
+    #[test]
+    fn test_limits() {
+        use super::Limits;
+
+        macro_rules! two_fields (
+            () => (crate::mock_stream!(
+                "preamble\r\n--boundary\r\n\
+                 Content-Disposition: form-data; name=\"foo\"\r\n\r\n\
+                 field data\r\n\
+                 --boundary\r\n\
+                 Content-Disposition: form-data; name=\"bar\"; filename=\"bar.txt\"\r\n\r\n\
+                 file data\r\n\
+                 --boundary--"
+            ))
+        );
+
+        fn read_all<S>(mut multipart: Multipart<S>) -> Result<(), String>
+        where S: futures::TryStream<Ok = std::borrow::Cow<'static, [u8]>, Error = crate::mock::StringError> + Unpin {
+            block_on(async {
+                while let Some(field) = multipart.try_next().await? {
+                    field.data.try_for_each(|_| async { Ok(()) }).await?;
+                }
+
+                Ok::<_, crate::mock::StringError>(())
+            }).map_err(String::from)
+        }
+
+        let limited = |limits| read_all(Multipart::with_limits(two_fields!(), BOUNDARY, limits));
+
+        assert_eq!(limited(Limits::default()), Ok(()));
+        assert_eq!(limited(Limits::default().fields(1)),
+                   Err("field count limit of 1 exceeded".into()));
+        assert_eq!(limited(Limits::default().files(0)),
+                   Err("file count limit of 0 exceeded".into()));
+        assert_eq!(limited(Limits::default().field_size(9)),
+                   Err("field size limit of 9 exceeded".into()));
+        assert_eq!(limited(Limits::default().total_size(100)),
+                   Err("request body size limit of 100 exceeded".into()));
+        assert_eq!(limited(Limits::default().preamble_size(4)),
+                   Err("preamble size limit of 4 exceeded".into()));
+        assert_eq!(limited(Limits::default().header_block_size(32)),
+                   Err("field headers size limit of 32 exceeded".into()));
+    }
+
+    #[test]
+    fn test_parse_mode() {
+        use super::ParseMode;
+
+        macro_rules! body (
+            () => (crate::mock_stream!(
+                "--boundary \t";
+                "\nContent-Disposition: form-data; name=\"foo\"\n\n";
+                "foo data\n";
+                "--boundary\t\r";
+                "\nContent-Disposition: form-data; name=\"bar\"\r\n\r\n";
+                "bar data\n--boun";
+                "dary\n\
+                 Content-Disposition: form-data; name=\"baz\"\n\n\
+                 baz data\r\n"
+            ))
+        );
+
+        let mut multipart = Multipart::with_body(body!(), BOUNDARY);
+        multipart.set_parse_mode(ParseMode::Lenient);
+
+        block_on(async {
+            for (name, data) in &[("foo", "foo data"), ("bar", "bar data"), ("baz", "baz data")] {
+                let field = multipart.try_next().await.unwrap().unwrap();
+                assert_eq!(field.headers.name, *name);
+                assert_eq!(field.data.read_text().await.unwrap().text, *data);
+            }
+
+            assert!(multipart.try_next().await.unwrap().is_none());
+        });
+
+        // the headers of the first field don't end in strict mode
+        let mut multipart = Multipart::with_body(body!(), BOUNDARY);
+        assert!(block_on(multipart.try_next()).is_err());
+
+        // strict mode requires the closing boundary
+        let mut multipart = Multipart::with_body(crate::mock_stream!(
+            "--boundary\r\n\
+             Content-Disposition: form-data; name=\"foo\"\r\n\r\n\
+             foo data"
+        ), BOUNDARY);
+
+        block_on(async {
+            let field = multipart.try_next().await.unwrap().unwrap();
+            let err = field.data.read_text().await.unwrap_err();
+            assert!(String::from(err).starts_with("unexpected end of stream before the closing boundary"));
+        });
+
+        // even if the body is empty
+        let mut multipart = Multipart::with_body(crate::mock_stream!(), BOUNDARY);
+        let err = block_on(multipart.try_next()).unwrap_err();
+        assert!(String::from(err).starts_with("unexpected end of stream before the closing boundary"));
+    }
+
+    #[test]
+    fn test_recovery() {
+        use super::Limits;
+
+        let stream = crate::mock_stream!(
+            "--boundary\r\n\
+             Content-Disposition: form-data; name=\"foo\"\r\n\r\n\
+             foo data\r\n\
+             --boundary\r\n\
+             Content Disposition: form-data; name=\"invalid\"\r\n\r\n\
+             invalid data\r\n";
+            "--boundary\r\n\
+             Content-Type: text/plain\r\n\r\n\
+             anonymous data\r\n\
+             --boundary\r\n\
+             Content-Disposition: form-data; name=\"bar\"\r\n\
+             X-Padding: 0123456789012345678901234567890123456789\r\n\r\n";
+            "bar data\r\n\
+             --boundary\r\n\
+             Content-Disposition: form-data; name=\"baz\"\r\n\r\n\
+             baz data\r\n\
+             --boundary--"
+        );
+
+        let mut multipart = Multipart::with_limits(stream, BOUNDARY, Limits::default().header_block_size(64));
+        multipart.set_recovery(true);
+
+        block_on(async {
+            let field = multipart.try_next().await.unwrap().unwrap();
+            assert_eq!(field.headers.name, "foo");
+            assert_eq!(field.data.read_text().await.unwrap().text, "foo data");
+
+            let err = String::from(multipart.try_next().await.unwrap_err());
+            assert!(err.starts_with("invalid field headers: error parsing headers"), "{}", err);
+            assert!(err.contains("in field 1"), "{}", err);
+
+            let err = String::from(multipart.try_next().await.unwrap_err());
+            assert!(err.starts_with("missing `Content-Disposition` header"), "{}", err);
+
+            let err = String::from(multipart.try_next().await.unwrap_err());
+            assert_eq!(err, "field headers size limit of 64 exceeded");
+
+            let field = multipart.try_next().await.unwrap().unwrap();
+            assert_eq!(field.headers.name, "baz");
+            assert_eq!(field.data.read_text().await.unwrap().text, "baz data");
+
+            assert!(multipart.try_next().await.unwrap().is_none());
+        });
+    }
+
+    #[test]
+    fn test_error_context() {
+        use crate::helpers::show_bytes;
+
+        const BODY: &str = "--boundary\r\n\
+                            Content-Disposition: form-data; name=\"foo\"\r\n\r\n\
+                            field data\r\n\
+                            --boundary\r\n\
+                            Content-Type: text/plain\r\n\r\n\
+                            \r\n--boundary\r\n\
+                            Content-Type: text/plain\r\n\
+                            \r\n--boundary--";
+
+        let mut multipart = Multipart::with_body(crate::mock_stream!(BODY), BOUNDARY);
+
+        let err = block_on(async {
+            drop(multipart.try_next().await.unwrap().unwrap());
+            multipart.try_next().await.unwrap_err()
+        });
+
+        let offset = BODY.find("Content-Type").unwrap();
+        assert_eq!(String::from(err),
+                   format!("missing `Content-Disposition` header on a field of a multipart/form-data \
+                            request (at byte {} in field 1, near: {})",
+                           offset, show_bytes(&BODY.as_bytes()[offset..offset + 28])));
+
+        // the header block is cut short by the boundary
+        let mut multipart = Multipart::with_body(crate::mock_stream!(BODY), BOUNDARY);
+
+        let err = block_on(async {
+            drop(multipart.try_next().await.unwrap().unwrap());
+            multipart.try_next().await.unwrap_err();
+            multipart.try_next().await.unwrap_err()
+        });
+
+        let offset = BODY.rfind("Content-Type").unwrap();
+        assert_eq!(String::from(err),
+                   format!("unexpected end of stream while reading field headers (at byte {} in field 2, \
+                            near: {})", offset, show_bytes(&BODY.as_bytes()[offset..offset + 26])));
+
+        let mut multipart = Multipart::with_body(crate::mock_stream!(
+            "--boundary\r\n\
+             Content-Disposition: form-data; name=\"foo\"\r\n\r\n\
+             field data\r\n\
+             --boundary--"; "\r\n\tepilogue"
+        ), BOUNDARY);
+        multipart.set_strict_epilogue(true);
+
+        let err = block_on(async {
+            drop(multipart.try_next().await.unwrap().unwrap());
+            multipart.try_next().await.unwrap_err()
+        });
+
+        assert_eq!(String::from(err), "invalid multipart boundary: unexpected data after the closing \
+                                       boundary (at byte 85 outside of any field, near: epilogue)");
+    }
+
+    #[cfg(feature = "encoding_rs")]
+    #[test]
+    fn test_read_charsets() {
+        let stream = crate::mock_stream!(
+            b"--boundary\r\n\
+              Content-Disposition: form-data; name=\"latin\"\r\n\
+              Content-Type: text/plain; charset=windows-1252\r\n\r\n\
+              caf\xE9\r\n\
+              --boundary\r\n\
+              Content-Disposition: form-data; name=\"_charset_\"\r\n\r\n\
+              Shift_JIS\r\n\
+              --boundary\r\n\
+              Content-Disposition: form-data; name=\"sjis\"\r\n\r\n\x93";
+            // sequence split across chunks
+            b"\xFA\x96\x7B\r\n--boundary--"
+        );
+
+        let mut multipart = Multipart::with_body(stream, BOUNDARY);
+
+        block_on(async {
+            let field = multipart.try_next().await.unwrap().unwrap();
+            assert_eq!(field.data.read_text().await.unwrap().text, "caf\u{E9}");
+
+            let field = multipart.try_next().await.unwrap().unwrap();
+            assert_eq!(field.data.read_text().await.unwrap().text, "Shift_JIS");
+
+            let field = multipart.try_next().await.unwrap().unwrap();
+            assert_eq!(field.data.read_text().await.unwrap().text, "\u{65E5}\u{672C}");
+
+            assert!(multipart.try_next().await.unwrap().is_none());
+        });
+
+        // `_charset_` applies even if the field is dropped without being read
+        let stream = crate::mock_stream!(
+            b"--boundary\r\n\
+              Content-Disposition: form-data; name=\"_charset_\"\r\n\r\n\
+              Shift_JIS\r\n\
+              --boundary\r\n\
+              Content-Disposition: form-data; name=\"sjis\"\r\n\r\n\
+              \x93\xFA\x96\x7B\r\n\
+              --boundary--"
+        );
+
+        let mut multipart = Multipart::with_body(stream, BOUNDARY);
+
+        block_on(async {
+            drop(multipart.try_next().await.unwrap().unwrap());
+
+            let field = multipart.try_next().await.unwrap().unwrap();
+            assert_eq!(field.data.read_text().await.unwrap().text, "\u{65E5}\u{672C}");
+
+            assert!(multipart.try_next().await.unwrap().is_none());
+        });
+    }
+
+    #[cfg(feature = "use_arc")]
+    #[test]
+    fn test_send() {
+        use std::io;
+
+        use futures::stream::Iter;
+
+        use super::{Field, FieldData, ReadTextField, SaveField, SavedField, TextField};
+
+        type Body = Iter<::std::vec::IntoIter<Result<Vec<u8>, io::Error>>>;
+
+        fn assert_send<T: Send>() {}
+
+        assert_send::<Multipart<Body>>();
+        assert_send::<Field<Body>>();
+        assert_send::<FieldData<Body>>();
+        assert_send::<ReadTextField<FieldData<Body>>>();
+        assert_send::<TextField>();
+        assert_send::<SaveField<FieldData<Body>>>();
+        assert_send::<SavedField>();
+        assert_send::<super::CollectForm<Body>>();
+        assert_send::<super::Form>();
+        assert_send::<super::Part<Body>>();
+        assert_send::<super::Parts<Body>>();
+        assert_send::<super::SniffField<FieldData<Body>>>();
+        assert_send::<super::DecodeField<FieldData<Body>>>();
+        assert_send::<super::ReadField<FieldData<Body>>>();
+        #[cfg(feature = "digest")]
+        assert_send::<super::HashField<FieldData<Body>>>();
+    }
+
+    #[cfg(feature = "use_arc")]
+    #[test]
+    fn test_read_on_other_thread() {
+        use std::thread;
+
+        let stream = crate::mock_stream!(
+            "--boundary\r\n\
+             Content-Disposition: form-data; name=\"foo\"\r\n\r\n\
+             field data\r\n\
+             --boundary--"
+        );
+
+        let mut multipart = Multipart::with_body(stream, BOUNDARY);
+
+        let field = block_on(multipart.try_next()).unwrap().unwrap();
+
+        let text = thread::spawn(move || block_on(field.data.read_text()).unwrap().text)
+            .join().unwrap();
+
+        assert_eq!(text, "field data");
+        assert!(block_on(multipart.try_next()).unwrap().is_none());
+    }
 }
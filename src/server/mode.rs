@@ -0,0 +1,31 @@
+// Copyright 2017 `multipart-async` Crate Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+//! How strictly the framing of a request is checked.
+
+/// How strictly a request must follow [IETF RFC 7578](https://tools.ietf.org/html/rfc7578)
+/// and [RFC 2046](https://tools.ietf.org/html/rfc2046#section-5.1.1), set with
+/// `Multipart::set_parse_mode()`.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum ParseMode {
+    /// Require CRLF (`\r\n`) line endings around boundaries and in field headers, and a closing
+    /// boundary (`--boundary--`) at the end of the request. The default.
+    ///
+    /// If the body stream ends before the closing boundary, the field being read, or
+    /// `Multipart` itself, yields `MultipartError::UnexpectedEof`, so a truncated request is
+    /// never mistaken for a complete one.
+    #[default]
+    Strict,
+    /// Accept the variations sent by some embedded devices and hand-rolled clients:
+    ///
+    /// * bare LF (`\n`) line endings, before and after boundaries and in field headers;
+    /// * spaces or tabs after a boundary, before its line ending ("transport padding");
+    /// * a request which ends without a closing boundary, in which case the last field ends
+    ///   with the body stream, less a trailing line ending.
+    ///
+    /// Each variation is logged with `warn!()` when it is accepted.
+    Lenient,
+}
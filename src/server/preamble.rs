@@ -0,0 +1,54 @@
+// Copyright 2017 `multipart-async` Crate Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+//! Streams of the data outside the parts of a multipart body.
+use std::fmt;
+
+use crate::{BodyChunk, StreamError};
+
+use super::Multipart;
+
+use crate::helpers::*;
+
+/// A `Stream` of the chunks of the preamble of a multipart body, before the first boundary;
+/// returned by `Multipart::preamble()`.
+pub struct Preamble<'a, S: TryStream> {
+    pub(super) multipart: &'a mut Multipart<S>,
+}
+
+impl<S: TryStream + Unpin> Stream for Preamble<'_, S> where S::Ok: BodyChunk, S::Error: StreamError {
+    type Item = Result<S::Ok, S::Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        self.get_mut().multipart.poll_preamble(cx)
+    }
+}
+
+impl<S: TryStream> fmt::Debug for Preamble<'_, S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Preamble { .. }")
+    }
+}
+
+/// A `Stream` of the chunks of the epilogue of a multipart body, after the closing boundary;
+/// returned by `Multipart::epilogue()`.
+pub struct Epilogue<'a, S: TryStream> {
+    pub(super) multipart: &'a mut Multipart<S>,
+}
+
+impl<S: TryStream + Unpin> Stream for Epilogue<'_, S> where S::Ok: BodyChunk, S::Error: StreamError {
+    type Item = Result<S::Ok, S::Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        self.get_mut().multipart.poll_epilogue(cx)
+    }
+}
+
+impl<S: TryStream> fmt::Debug for Epilogue<'_, S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Epilogue { .. }")
+    }
+}
@@ -0,0 +1,111 @@
+// Copyright 2017 `multipart-async` Crate Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+//! Reporting the progress of reading a request.
+use std::fmt;
+
+use super::sync::MaybeSend;
+
+/// A snapshot of the progress of reading a request, passed to a `ProgressListener`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Progress {
+    /// The total number of bytes read from the body stream.
+    pub bytes_read: u64,
+    /// The number of fields started so far, including the current one.
+    pub fields: u64,
+    /// The field currently being read, or `None` between fields.
+    pub field: Option<FieldProgress>,
+}
+
+/// The progress of reading a single field.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct FieldProgress {
+    /// The name of the field, if it has one.
+    pub name: Option<String>,
+    /// The filename of the field, if it has one.
+    pub filename: Option<String>,
+    /// The number of bytes of the field's data read so far.
+    pub bytes_read: u64,
+}
+
+/// An observer of the progress of reading a request, set with
+/// `Multipart::set_progress_listener()`.
+///
+/// Implemented for closures taking `&Progress`. With the `use_arc` feature, listeners must be
+/// `Send`.
+pub trait ProgressListener: MaybeSend {
+    /// Called when a field starts and ends, and whenever a chunk of the request is read,
+    /// including the data of fields which are dropped without being read.
+    ///
+    /// This is called from within the parser, so it should return quickly; e.g. store the
+    /// progress in an atomic or send it to a channel.
+    fn on_progress(&mut self, progress: &Progress);
+}
+
+impl<F: FnMut(&Progress) + MaybeSend> ProgressListener for F {
+    fn on_progress(&mut self, progress: &Progress) {
+        self(progress)
+    }
+}
+
+#[cfg(feature = "use_arc")]
+type BoxListener = Box<dyn ProgressListener + Send>;
+
+#[cfg(not(feature = "use_arc"))]
+type BoxListener = Box<dyn ProgressListener>;
+
+/// The progress of a request and the listener to report it to, kept by `BoundaryFinder`.
+pub struct ProgressState {
+    listener: BoxListener,
+    progress: Progress,
+}
+
+impl ProgressState {
+    pub fn new<L: ProgressListener + 'static>(listener: L, bytes_read: u64) -> Self {
+        ProgressState {
+            listener: Box::new(listener),
+            progress: Progress { bytes_read, .. Progress::default() },
+        }
+    }
+
+    /// A chunk was yielded, after `bytes_read` bytes had been read from the body stream.
+    pub fn chunk(&mut self, bytes_read: u64, len: usize) {
+        self.progress.bytes_read = bytes_read;
+
+        if let Some(ref mut field) = self.progress.field {
+            field.bytes_read += len as u64;
+        }
+
+        self.listener.on_progress(&self.progress);
+    }
+
+    pub fn start_field(&mut self, name: Option<&str>, filename: Option<&str>) {
+        self.progress.fields += 1;
+        self.progress.field = Some(FieldProgress {
+            name: name.map(Into::into),
+            filename: filename.map(Into::into),
+            bytes_read: 0,
+        });
+
+        self.listener.on_progress(&self.progress);
+    }
+
+    pub fn end_field(&mut self) {
+        if self.progress.field.take().is_some() {
+            self.listener.on_progress(&self.progress);
+        }
+    }
+}
+
+impl fmt::Debug for ProgressState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ProgressState")
+            .field("progress", &self.progress)
+            .finish()
+    }
+}
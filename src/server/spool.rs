@@ -0,0 +1,196 @@
+// Copyright 2017 `multipart-async` Crate Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+//! Buffering the rest of a field so the next one can be read before it.
+use tempdir::TempDir;
+
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+
+use crate::BodyChunk;
+
+const TEMP_DIR_PREFIX: &str = "multipart-async-spool";
+
+// 64 KiB, enough for most text fields without holding on to much memory per field
+const DEFAULT_MEMORY_LIMIT: usize = 64 * 1024;
+
+const READ_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Options for `Multipart::set_spooling()`.
+#[derive(Clone, Debug)]
+pub struct SpoolOptions {
+    /// The number of bytes of each field to buffer in memory before the rest is written to
+    /// a temporary file; the default is 64 KiB.
+    pub memory_limit: usize,
+    /// The directory to create temporary files in.
+    ///
+    /// If `None`, the system temporary directory is used.
+    pub dir: Option<PathBuf>,
+}
+
+impl Default for SpoolOptions {
+    fn default() -> Self {
+        SpoolOptions { memory_limit: DEFAULT_MEMORY_LIMIT, dir: None }
+    }
+}
+
+impl SpoolOptions {
+    /// Set the number of bytes of each field to buffer in memory.
+    ///
+    /// Setting this to `usize::MAX` keeps spooled fields entirely in memory, subject only to
+    /// `Limits::field_size`.
+    pub fn memory_limit(self, memory_limit: usize) -> Self {
+        SpoolOptions { memory_limit, .. self }
+    }
+
+    /// Create temporary files in `dir` instead of the system temporary directory.
+    pub fn with_dir<P: Into<PathBuf>>(self, dir: P) -> Self {
+        SpoolOptions { dir: Some(dir.into()), .. self }
+    }
+}
+
+/// The spooling options of a `Multipart`, with the conversion needed to yield chunks read back
+/// from disk.
+pub struct Spooling<C> {
+    pub options: SpoolOptions,
+    pub from_vec: fn(Vec<u8>) -> C,
+}
+
+/// The spooled data of a field, yielded in order: first the chunks held in memory,
+/// then the contents of the file.
+pub struct Spool<C> {
+    memory: VecDeque<C>,
+    memory_size: usize,
+    memory_limit: usize,
+    dir: Option<PathBuf>,
+    file: Option<SpoolFile>,
+    from_vec: fn(Vec<u8>) -> C,
+    complete: bool,
+}
+
+struct SpoolFile {
+    file: File,
+    read_pos: u64,
+    write_pos: u64,
+    // deleted along with the file when the spool is dropped
+    _dir: TempDir,
+}
+
+impl<C: BodyChunk> Spool<C> {
+    pub fn new(spooling: &Spooling<C>) -> Self {
+        Spool {
+            memory: VecDeque::new(), memory_size: 0, memory_limit: spooling.options.memory_limit,
+            dir: spooling.options.dir.clone(), file: None, from_vec: spooling.from_vec,
+            complete: false,
+        }
+    }
+
+    /// Add a chunk to the end of the spool.
+    pub fn push(&mut self, chunk: C) -> io::Result<()> {
+        let fits = self.memory_size.checked_add(chunk.len()).is_some_and(|size| size <= self.memory_limit);
+
+        // once anything is on disk, everything after it must be too
+        if self.file.is_none() && fits {
+            self.memory_size += chunk.len();
+            self.memory.push_back(chunk);
+            return Ok(());
+        }
+
+        if self.file.is_none() {
+            self.file = Some(self.create_file()?);
+        }
+
+        let file = self.file.as_mut().expect("spool file was just created");
+        file.file.seek(SeekFrom::Start(file.write_pos))?;
+        file.file.write_all(chunk.as_slice())?;
+        file.write_pos += chunk.len() as u64;
+
+        Ok(())
+    }
+
+    fn create_file(&self) -> io::Result<SpoolFile> {
+        let dir = match self.dir {
+            Some(ref dir) => TempDir::new_in(dir, TEMP_DIR_PREFIX)?,
+            None => TempDir::new(TEMP_DIR_PREFIX)?,
+        };
+
+        let path = dir.path().join("field");
+        debug!("spooling field to {:?}", path);
+
+        let file = OpenOptions::new().read(true).write(true).create_new(true).open(path)?;
+
+        Ok(SpoolFile { file, read_pos: 0, write_pos: 0, _dir: dir })
+    }
+
+    /// Take the next chunk from the front of the spool, or `None` if it is empty.
+    pub fn read(&mut self) -> io::Result<Option<C>> {
+        if let Some(chunk) = self.memory.pop_front() {
+            self.memory_size -= chunk.len();
+            return Ok(Some(chunk));
+        }
+
+        let file = match self.file {
+            Some(ref mut file) if file.read_pos < file.write_pos => file,
+            _ => return Ok(None),
+        };
+
+        let len = (file.write_pos - file.read_pos).min(READ_CHUNK_SIZE as u64) as usize;
+        let mut buf = vec![0; len];
+
+        file.file.seek(SeekFrom::Start(file.read_pos))?;
+        file.file.read_exact(&mut buf)?;
+        file.read_pos += len as u64;
+
+        Ok(Some((self.from_vec)(buf)))
+    }
+
+    /// `true` if there is nothing to `read()`.
+    pub fn is_empty(&self) -> bool {
+        self.memory.is_empty() && self.file.as_ref().is_none_or(|file| file.read_pos == file.write_pos)
+    }
+
+    /// `true` if the whole field has been spooled.
+    pub fn is_complete(&self) -> bool {
+        self.complete
+    }
+
+    pub fn set_complete(&mut self) {
+        self.complete = true;
+    }
+}
+
+#[test]
+fn test_spool() {
+    fn spool(memory_limit: usize, chunks: &[&[u8]]) -> Vec<Vec<u8>> {
+        let spooling = Spooling {
+            options: SpoolOptions::default().memory_limit(memory_limit),
+            from_vec: |vec| vec,
+        };
+
+        let mut spool = Spool::new(&spooling);
+
+        for chunk in chunks {
+            spool.push(chunk.to_vec()).unwrap();
+        }
+
+        let mut out = Vec::new();
+
+        while let Some(chunk) = spool.read().unwrap() {
+            out.push(chunk);
+        }
+
+        assert!(spool.is_empty());
+        out
+    }
+
+    // all in memory, chunks preserved
+    assert_eq!(spool(16, &[b"hello", b", ", b"world"]), [&b"hello"[..], b", ", b"world"]);
+    // spilled to disk after the first chunk, read back in order
+    assert_eq!(spool(6, &[b"hello", b", ", b"world"]), [&b"hello"[..], b", world"]);
+    assert_eq!(spool(0, &[b"hello", b", ", b"world"]), [b"hello, world"]);
+}
@@ -0,0 +1,106 @@
+// Copyright 2017 `multipart-async` Crate Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+//! Shared-state primitives which switch between single-threaded and thread-safe implementations
+//! depending on the `use_arc` feature.
+#[cfg(feature = "use_arc")]
+pub use self::arc::*;
+
+#[cfg(not(feature = "use_arc"))]
+pub use self::rc::*;
+
+#[cfg(feature = "use_arc")]
+mod arc {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{Mutex, MutexGuard, PoisonError};
+
+    /// The pointer type used to share values between a `Multipart` and its fields.
+    ///
+    /// This is `Arc` as the `use_arc` feature is enabled.
+    pub type Shared<T> = std::sync::Arc<T>;
+
+    pub type Guard<'a, T> = MutexGuard<'a, T>;
+
+    pub struct Lock<T>(Mutex<T>);
+
+    impl<T> Lock<T> {
+        pub fn new(val: T) -> Self {
+            Lock(Mutex::new(val))
+        }
+
+        /// A panic while the lock was held doesn't leave the parser state any less consistent
+        /// than a returned error would, so poisoning is ignored.
+        pub fn lock(&self) -> Guard<'_, T> {
+            self.0.lock().unwrap_or_else(PoisonError::into_inner)
+        }
+    }
+
+    /// `Send`, as the `use_arc` feature is enabled; implemented for all `Send` types.
+    pub trait MaybeSend: Send {}
+
+    impl<T: Send + ?Sized> MaybeSend for T {}
+
+    pub struct Flag(AtomicBool);
+
+    impl Flag {
+        pub fn new(val: bool) -> Self {
+            Flag(AtomicBool::new(val))
+        }
+
+        pub fn get(&self) -> bool {
+            self.0.load(Ordering::SeqCst)
+        }
+
+        pub fn set(&self, val: bool) {
+            self.0.store(val, Ordering::SeqCst)
+        }
+    }
+}
+
+#[cfg(not(feature = "use_arc"))]
+mod rc {
+    use std::cell::{Cell, RefCell, RefMut};
+
+    /// The pointer type used to share values between a `Multipart` and its fields.
+    ///
+    /// This is `Rc`; enable the `use_arc` feature to use `Arc` instead.
+    pub type Shared<T> = std::rc::Rc<T>;
+
+    pub type Guard<'a, T> = RefMut<'a, T>;
+
+    pub struct Lock<T>(RefCell<T>);
+
+    impl<T> Lock<T> {
+        pub fn new(val: T) -> Self {
+            Lock(RefCell::new(val))
+        }
+
+        pub fn lock(&self) -> Guard<'_, T> {
+            self.0.borrow_mut()
+        }
+    }
+
+    /// Implemented for all types; with the `use_arc` feature enabled, this requires `Send`.
+    pub trait MaybeSend {}
+
+    impl<T: ?Sized> MaybeSend for T {}
+
+    pub struct Flag(Cell<bool>);
+
+    impl Flag {
+        pub fn new(val: bool) -> Self {
+            Flag(Cell::new(val))
+        }
+
+        pub fn get(&self) -> bool {
+            self.0.get()
+        }
+
+        pub fn set(&self, val: bool) {
+            self.0.set(val)
+        }
+    }
+}